@@ -17,7 +17,7 @@ scene = rt.node('scene')
 -- scene:rotate('x', 10)
 
 -- the floor
-floor = rt.mesh('plane', 'obj/plane.obj' )
+floor = rt.rectangle('plane')
 floor:set_material(ground_texture)
 floor:scale(30, 1, 30)
 floor:translate(10, 0, -20)
@@ -30,6 +30,7 @@ s:translate(0, 4, 0)
 arc:add_child(s)
 
 inst:add_child(arc)
+arc_prototype = rt.prototype(arc)
 
 -- #############################################
 -- Read in the cow model from a separate file.
@@ -43,6 +44,7 @@ cow_poly:set_material(hide)
 cow_poly:translate(0.0, 3.637, 0.0)
 cow_poly:scale(factor, factor, factor)
 cow_poly:translate(0.0, -1.0, 0.0)
+cow = rt.prototype(cow_poly)
 
 -- ##############################################
 -- the scene
@@ -53,7 +55,7 @@ scene:rotate('X', 23)
 
 -- the floor
 
-plane = rt.mesh('plane', 'obj/plane.obj' )
+plane = rt.rectangle('plane')
 plane:set_material(rtass)
 plane:scale(30, 30, 30)
 scene:add_child(plane)
@@ -68,7 +70,8 @@ buckyball:scale(1.5, 1.5, 1.5)
 scene:add_child(buckyball)
 
 -- Use the instanced cow model to place some actual cows in the scene.
--- For convenience, do this in a loop.
+-- Instances share the cow mesh instead of copying it. For convenience,
+-- do this in a loop.
 
 cow_number = 1
 
@@ -76,8 +79,7 @@ for _, pt in pairs({
 		      {{1,1.3,14}, 20},
 		      {{5,1.3,-11}, 180},
 		      {{-5.5,1.3,-3}, -60}}) do
-   cow_instance = rt.node('cow' .. tostring(cow_number))
-   cow_instance:add_child(cow_poly)
+   cow_instance = rt.instance('cow' .. tostring(cow_number), cow)
    cow_instance:scale(1.4, 1.4, 1.4)
    cow_instance:rotate('Y', pt[2])
    cow_instance:translate(table.unpack(pt[1]))
@@ -89,9 +91,8 @@ end
 -- Place a ring of arches.
 
 for i = 1, 6 do
-   an_arc = rt.node('arc' .. tostring(i))
+   an_arc = rt.instance('arc' .. tostring(i), arc_prototype)
    an_arc:rotate('Y', (i-1) * 60)
-   an_arc:add_child(arc)
    scene:add_child(an_arc)
 end
 ambient_lighting = {0.2, 0.2, 0.2}
@@ -18,7 +18,7 @@ sphere_node2:translate(1, 1.0, -15)
 root_node:add_child(sphere_node)
 root_node:add_child(sphere_node2)
 
-floor = rt.mesh('floor', 'obj/plane.obj')
+floor = rt.rectangle('floor')
 floor:set_material(white)
 floor:scale(100,100,100)
 
@@ -52,7 +52,7 @@ scene:add_child(s2)
 scene:add_child(s3)
 
 -- the floor
-plane = rt.mesh('plane', 'obj/plane.obj' )
+plane = rt.rectangle('plane')
 plane:set_material(gray)
 plane:scale(30, 30, 30)
 
@@ -0,0 +1,94 @@
+use crate::scene::Color;
+
+// Operator used to squeeze the unbounded radiance of a render into displayable colors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapping {
+    // Anything brighter than 1 is cut off
+    Clamp,
+    // x / (1 + x) on each channel, never quite reaches white
+    Reinhard,
+    // Narkowicz's fit of the ACES filmic curve, with a slight toe and soft highlights
+    Aces,
+}
+
+impl ToneMapping {
+    pub fn from_name(name: &str) -> Option<ToneMapping> {
+        match name {
+            "clamp" | "none" | "linear" => Some(ToneMapping::Clamp),
+            "reinhard" => Some(ToneMapping::Reinhard),
+            "aces" | "filmic" => Some(ToneMapping::Aces),
+            _ => None,
+        }
+    }
+
+    fn apply(self, x: f32) -> f32 {
+        let x = x.max(0.0);
+        match self {
+            ToneMapping::Clamp => x,
+            ToneMapping::Reinhard => x / (1.0 + x),
+            ToneMapping::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+        }
+    }
+}
+
+// How linear radiance is turned into colors for an 8-bit image
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisplayTransform {
+    // Exposure adjustment in stops, every stop doubles the brightness
+    pub exposure: f32,
+    pub tone_mapping: ToneMapping,
+    // Encode the output with the sRGB transfer curve, which is what displays expect
+    pub srgb: bool,
+}
+
+impl Default for DisplayTransform {
+    fn default() -> DisplayTransform {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::Clamp,
+            srgb: true,
+        }
+    }
+}
+
+impl DisplayTransform {
+    // Writes colors out exactly as they are, used for debug images like sample heatmaps
+    pub fn raw() -> DisplayTransform {
+        DisplayTransform {
+            exposure: 0.0,
+            tone_mapping: ToneMapping::Clamp,
+            srgb: false,
+        }
+    }
+
+    // Map a linear color to a display color in [0, 1]
+    pub fn apply(&self, color: &Color) -> Color {
+        let scale = 2.0f32.powf(self.exposure);
+        let map = |x: f32| {
+            let mapped = self.tone_mapping.apply(x * scale).clamp(0.0, 1.0);
+            if self.srgb {
+                linear_to_srgb(mapped)
+            } else {
+                mapped
+            }
+        };
+        Color::new(map(color.r), map(color.g), map(color.b))
+    }
+}
+
+// The sRGB transfer functions, for values in [0, 1]
+pub fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.040_45 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
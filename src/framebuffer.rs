@@ -0,0 +1,96 @@
+use crate::display::DisplayTransform;
+use crate::scene::Color;
+use image::hdr::HDREncoder;
+use image::{save_buffer, Rgb, RGB};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// A rendered image in linear radiance. Colors are kept unclamped so bright highlights
+// survive until the image is saved, and are only clamped when written to an 8-bit format.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    // Pixels in rows from the top left of the image
+    pub pixels: Vec<Color>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, pixels: Vec<Color>) -> Framebuffer {
+        assert_eq!(pixels.len(), (width * height) as usize);
+        Framebuffer {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    // Save the image, with the format chosen by the file extension. OpenEXR (.exr),
+    // Radiance (.hdr) and portable float maps (.pfm) keep the full range of the image,
+    // anything else is written as an 8-bit image using the display transform.
+    pub fn save(&self, file_name: &str, display: &DisplayTransform) -> Result<(), Box<dyn Error>> {
+        let extension = Path::new(file_name)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("exr") => self.save_exr(file_name),
+            Some("hdr") => self.save_hdr(file_name),
+            Some("pfm") => self.save_pfm(file_name),
+            _ => self.save_ldr(file_name, display),
+        }
+    }
+
+    fn save_ldr(&self, file_name: &str, display: &DisplayTransform) -> Result<(), Box<dyn Error>> {
+        let mut buffer: Vec<u8> = Vec::with_capacity(self.pixels.len() * 3);
+        for color in self.pixels.iter() {
+            buffer.extend_from_slice(&display.apply(color).as_rgb().data);
+        }
+        save_buffer(file_name, &buffer, self.width, self.height, RGB(8))?;
+        Ok(())
+    }
+
+    fn save_exr(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        exr::prelude::write_rgb_file(
+            file_name,
+            self.width as usize,
+            self.height as usize,
+            |x, y| {
+                let color = self.pixels[y * self.width as usize + x];
+                (color.r, color.g, color.b)
+            },
+        )?;
+        Ok(())
+    }
+
+    fn save_hdr(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        // Radiance files can't store negative values, which filters like mitchell can produce
+        let data: Vec<Rgb<f32>> = self
+            .pixels
+            .iter()
+            .map(|c| Rgb([c.r.max(0.0), c.g.max(0.0), c.b.max(0.0)]))
+            .collect();
+        let writer = BufWriter::new(File::create(file_name)?);
+        HDREncoder::new(writer).encode(&data, self.width as usize, self.height as usize)?;
+        Ok(())
+    }
+
+    // Portable float map, a header followed by raw little endian floats with the rows
+    // stored from the bottom of the image to the top
+    fn save_pfm(&self, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        // A negative scale marks the data as little endian
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for color in row {
+                for value in [color.r, color.g, color.b].iter() {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use nalgebra::{Affine3, Point3, Vector3};
+use std::f32;
+
+// An axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Point3<f32>, max: Point3<f32>) -> Aabb {
+        Aabb { min, max }
+    }
+
+    // An inverted box that any point or box can be unioned into
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point3::new(f32::MAX, f32::MAX, f32::MAX),
+            max: Point3::new(f32::MIN, f32::MIN, f32::MIN),
+        }
+    }
+
+    pub fn from_corner_size(corner: &Vector3<f32>, size: &Vector3<f32>) -> Aabb {
+        Aabb {
+            min: Point3::from(*corner),
+            max: Point3::from(corner + size),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Point3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+
+    pub fn grow(&mut self, point: &Point3<f32>) {
+        for i in 0..3 {
+            self.min[i] = self.min[i].min(point[i]);
+            self.max[i] = self.max[i].max(point[i]);
+        }
+    }
+
+    pub fn centroid(&self) -> Point3<f32> {
+        Point3::from((self.min.coords + self.max.coords) * 0.5)
+    }
+
+    pub fn extent(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let e = self.extent();
+        if e.x < 0.0 || e.y < 0.0 || e.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+
+    // Bounds of this box after it has been moved by a transform, found by transforming every corner
+    pub fn transform(&self, transform: &Affine3<f32>) -> Aabb {
+        let mut bounds = Aabb::empty();
+        for i in 0..8 {
+            let corner = Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            bounds.grow(&(transform * corner));
+        }
+        bounds
+    }
+
+    // Slab test against a ray, returning the t value where the ray enters the box
+    // if it does so before t_max
+    pub fn hit(&self, src: &Point3<f32>, inv_dir: &Vector3<f32>, t_max: f32) -> Option<f32> {
+        let mut t_enter = 0.0f32;
+        let mut t_leave = t_max;
+        for i in 0..3 {
+            let mut near = (self.min[i] - src[i]) * inv_dir[i];
+            let mut far = (self.max[i] - src[i]) * inv_dir[i];
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // Written so that NaN values (a ray lying in a slab plane) are ignored
+            if near > t_enter {
+                t_enter = near;
+            }
+            if far < t_leave {
+                t_leave = far;
+            }
+            if t_enter > t_leave {
+                return None;
+            }
+        }
+        Some(t_enter)
+    }
+}
@@ -0,0 +1,315 @@
+use crate::geometry::{Aabb, Ray};
+use nalgebra::{Point3, Vector3};
+
+// Number of buckets used when estimating the surface area heuristic
+const BIN_COUNT: usize = 12;
+// Leaves smaller than this are never split
+const MIN_SPLIT_SIZE: usize = 2;
+// Leaves larger than this are always split, even if the heuristic says otherwise
+const MAX_LEAF_SIZE: usize = 8;
+// Relative cost of visiting a node compared to testing an item
+const TRAVERSAL_COST: f32 = 1.0;
+// Deeper nodes are left as leaves so traversal can use a fixed size stack
+const MAX_DEPTH: usize = 48;
+const STACK_SIZE: usize = MAX_DEPTH + 2;
+
+#[derive(Debug, Clone, PartialEq)]
+struct BvhNode {
+    bounds: Aabb,
+    // For interior nodes this is the index of the right child (the left child always
+    // directly follows its parent), for leaves it is the first entry in `items`
+    offset: usize,
+    // Number of items in a leaf, zero for interior nodes
+    count: usize,
+    // Axis the node was split along, used to visit the closer child first
+    axis: usize,
+}
+
+// A bounding volume hierarchy built with a binned surface area heuristic. The hierarchy
+// only stores indices, so it can be used for anything that has a bounding box
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    items: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            items: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            let centroids: Vec<Point3<f32>> = bounds.iter().map(|b| b.centroid()).collect();
+            bvh.build_node(bounds, &centroids, 0, bounds.len(), 0);
+        }
+        bvh
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Bounds of everything in the hierarchy
+    pub fn bounds(&self) -> Option<Aabb> {
+        self.nodes.first().map(|node| node.bounds)
+    }
+
+    fn build_node(
+        &mut self,
+        bounds: &[Aabb],
+        centroids: &[Point3<f32>],
+        start: usize,
+        end: usize,
+        depth: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &item in &self.items[start..end] {
+            node_bounds = node_bounds.union(&bounds[item]);
+            centroid_bounds.grow(&centroids[item]);
+        }
+        self.nodes.push(BvhNode {
+            bounds: node_bounds,
+            offset: start,
+            count: end - start,
+            axis: 0,
+        });
+
+        let count = end - start;
+        if count < MIN_SPLIT_SIZE || depth >= MAX_DEPTH {
+            return index;
+        }
+
+        let extent = centroid_bounds.extent();
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        if extent[axis] <= 0.0 {
+            // Every centroid is in the same place, there is nothing to split on
+            return index;
+        }
+
+        let bin_of = |item: usize| {
+            let offset = (centroids[item][axis] - centroid_bounds.min[axis]) / extent[axis];
+            ((offset * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+        };
+
+        let mut bin_bounds = [Aabb::empty(); BIN_COUNT];
+        let mut bin_counts = [0usize; BIN_COUNT];
+        for &item in &self.items[start..end] {
+            let bin = bin_of(item);
+            bin_bounds[bin] = bin_bounds[bin].union(&bounds[item]);
+            bin_counts[bin] += 1;
+        }
+
+        // Evaluate the cost of splitting after every bin
+        let mut best_split = 0;
+        let mut best_cost = f32::MAX;
+        for split in 1..BIN_COUNT {
+            let (mut left, mut right) = (Aabb::empty(), Aabb::empty());
+            let (mut left_count, mut right_count) = (0, 0);
+            for bin in 0..split {
+                left = left.union(&bin_bounds[bin]);
+                left_count += bin_counts[bin];
+            }
+            for bin in split..BIN_COUNT {
+                right = right.union(&bin_bounds[bin]);
+                right_count += bin_counts[bin];
+            }
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost =
+                left.surface_area() * left_count as f32 + right.surface_area() * right_count as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let parent_area = node_bounds.surface_area();
+        let leaf_cost = count as f32;
+        let split_cost = TRAVERSAL_COST + best_cost / parent_area.max(f32::EPSILON);
+        if best_split == 0 || (split_cost >= leaf_cost && count <= MAX_LEAF_SIZE) {
+            return index;
+        }
+
+        // Partition the items in place around the chosen split
+        let mut mid = start;
+        for i in start..end {
+            if bin_of(self.items[i]) < best_split {
+                self.items.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        self.nodes[index].count = 0;
+        self.nodes[index].axis = axis;
+        self.build_node(bounds, centroids, start, mid, depth + 1);
+        let right = self.build_node(bounds, centroids, mid, end, depth + 1);
+        self.nodes[index].offset = right;
+        index
+    }
+
+    // Walk every leaf the ray passes through, closest first. `test` is given an item and
+    // the closest hit found so far, and returns the t value of a closer hit if there is one
+    pub fn traverse<F>(&self, ray: &Ray, t_max: f32, mut test: F)
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inv_dir = Vector3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z);
+        let mut closest = t_max;
+        let mut stack = [0usize; STACK_SIZE];
+        let mut stack_len = 1;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let index = stack[stack_len];
+            let node = &self.nodes[index];
+            if node.bounds.hit(&ray.src, &inv_dir, closest).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &item in &self.items[node.offset..node.offset + node.count] {
+                    if let Some(t) = test(item, closest) {
+                        closest = closest.min(t);
+                    }
+                }
+            } else {
+                let left = index + 1;
+                // Push the far child first so the near child is visited first
+                if ray.dir[node.axis] < 0.0 {
+                    stack[stack_len] = left;
+                    stack[stack_len + 1] = node.offset;
+                } else {
+                    stack[stack_len] = node.offset;
+                    stack[stack_len + 1] = left;
+                }
+                stack_len += 2;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::testing::{brute_force, random_point, random_ray, seeded_rng};
+    use rand::rngs::StdRng;
+    use rand::Rng;
+
+    fn random_boxes(rng: &mut StdRng, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let corner = random_point(rng, 10.0);
+                let size = Vector3::new(
+                    rng.gen_range(0.0, 2.0),
+                    rng.gen_range(0.0, 2.0),
+                    rng.gen_range(0.0, 2.0),
+                );
+                Aabb::new(corner, corner + size)
+            })
+            .collect()
+    }
+
+    fn inv_dir(ray: &Ray) -> Vector3<f32> {
+        Vector3::new(1.0 / ray.dir.x, 1.0 / ray.dir.y, 1.0 / ray.dir.z)
+    }
+
+    // Closest box a ray enters before t_max by testing every one of them
+    fn closest_box(boxes: &[Aabb], ray: &Ray, t_max: f32) -> Option<f32> {
+        brute_force(boxes.len(), |i| {
+            boxes[i].hit(&ray.src, &inv_dir(ray), t_max)
+        })
+        .map(|hit| hit.0)
+    }
+
+    // Closest box a ray enters before t_max through the hierarchy, and how many were tested
+    fn traverse(bvh: &Bvh, boxes: &[Aabb], ray: &Ray, t_max: f32) -> (Option<f32>, usize) {
+        let mut closest = None;
+        let mut tested = 0;
+        bvh.traverse(ray, t_max, |item, t_closest| {
+            tested += 1;
+            let t = boxes[item].hit(&ray.src, &inv_dir(ray), t_closest)?;
+            if t < t_closest {
+                closest = Some(t);
+                Some(t)
+            } else {
+                None
+            }
+        });
+        (closest, tested)
+    }
+
+    #[test]
+    fn traversal_finds_the_same_hits_as_testing_everything() {
+        let mut rng = seeded_rng(1);
+        let boxes = random_boxes(&mut rng, 300);
+        let bvh = Bvh::build(&boxes);
+        assert_eq!(
+            bvh.bounds(),
+            Some(boxes.iter().fold(Aabb::empty(), |a, b| a.union(b)))
+        );
+
+        let mut hits = 0;
+        let mut tested = 0;
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng, 15.0, 10.0);
+            let expected = closest_box(&boxes, &ray, f32::MAX);
+            let (closest, count) = traverse(&bvh, &boxes, &ray, f32::MAX);
+            assert_eq!(closest, expected);
+            hits += expected.is_some() as usize;
+            tested += count;
+        }
+        // Make sure the rays actually hit things, and that most boxes were skipped
+        assert!(hits > 500);
+        assert!(tested < 1000 * boxes.len() / 10);
+    }
+
+    #[test]
+    fn traversal_ignores_hits_beyond_t_max() {
+        let mut rng = seeded_rng(2);
+        let boxes = random_boxes(&mut rng, 300);
+        let bvh = Bvh::build(&boxes);
+
+        let mut pruned = 0;
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng, 15.0, 10.0);
+            let t_max = rng.gen_range(0.0, 20.0);
+            let expected = closest_box(&boxes, &ray, t_max);
+            let (closest, _) = traverse(&bvh, &boxes, &ray, t_max);
+            assert_eq!(closest, expected);
+            pruned +=
+                (expected.is_none() && closest_box(&boxes, &ray, f32::MAX).is_some()) as usize;
+        }
+        assert!(pruned > 50);
+
+        // Nothing at all is tested when the limit is before the whole hierarchy
+        let ray = Ray::new(Point3::new(0.0, 0.0, -100.0), Vector3::z());
+        assert_eq!(traverse(&bvh, &boxes, &ray, 50.0), (None, 0));
+    }
+
+    #[test]
+    fn empty_hierarchies_test_nothing() {
+        let bvh = Bvh::build(&[]);
+        assert!(bvh.is_empty());
+        assert_eq!(bvh.bounds(), None);
+        let ray = Ray::new(Point3::origin(), Vector3::x());
+        assert_eq!(traverse(&bvh, &[], &ray, f32::MAX), (None, 0));
+    }
+}
@@ -0,0 +1,95 @@
+use image::{GrayImage, ImageResult};
+use nalgebra::{Point3, Vector3};
+
+// A terrain of heights sampled on a regular grid, from (0, 0, 0) to size. Each cell
+// between four samples is split into two triangles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heightfield {
+    pub size: Vector3<f32>,
+    // Number of samples along x and z
+    pub width: usize,
+    pub depth: usize,
+    // Heights from 0 to 1, in rows along x
+    pub heights: Vec<f32>,
+    // Normal at every sample, which are blended across the triangles
+    pub normals: Vec<Vector3<f32>>,
+    pub max_height: f32,
+}
+
+impl Heightfield {
+    // Load a grayscale image, where black is the bottom of the heightfield and white the top.
+    // Rows of the image go along z.
+    pub fn load(file_name: &str, size: Vector3<f32>) -> ImageResult<Heightfield> {
+        let image = image::open(file_name)?.to_luma();
+        Ok(Heightfield::from_image(&image, size))
+    }
+
+    pub fn from_image(image: &GrayImage, size: Vector3<f32>) -> Heightfield {
+        let width = image.width().max(2) as usize;
+        let depth = image.height().max(2) as usize;
+        let mut heights = Vec::with_capacity(width * depth);
+        for z in 0..depth {
+            for x in 0..width {
+                // Single pixel wide images are stretched to fill a cell
+                let x = (x as u32).min(image.width() - 1);
+                let z = (z as u32).min(image.height() - 1);
+                heights.push(f32::from(image.get_pixel(x, z)[0]) / 255.0);
+            }
+        }
+
+        let mut heightfield = Heightfield {
+            size,
+            width,
+            depth,
+            max_height: heights.iter().cloned().fold(0.0, f32::max),
+            heights,
+            normals: vec![],
+        };
+        heightfield.normals = (0..depth)
+            .flat_map(|z| (0..width).map(move |x| (x, z)))
+            .map(|(x, z)| heightfield.sample_normal(x, z))
+            .collect();
+        heightfield
+    }
+
+    pub fn cell_size(&self) -> (f32, f32) {
+        (
+            self.size.x / (self.width - 1) as f32,
+            self.size.z / (self.depth - 1) as f32,
+        )
+    }
+
+    pub fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[x + (z * self.width)]
+    }
+
+    pub fn normal(&self, x: usize, z: usize) -> Vector3<f32> {
+        self.normals[x + (z * self.width)]
+    }
+
+    pub fn vertex(&self, x: usize, z: usize) -> Vector3<f32> {
+        let (cell_x, cell_z) = self.cell_size();
+        Vector3::new(
+            x as f32 * cell_x,
+            self.height(x, z) * self.size.y,
+            z as f32 * cell_z,
+        )
+    }
+
+    // Texture coordinates stretch once over the whole heightfield
+    pub fn uv(&self, point: &Point3<f32>) -> [f32; 2] {
+        [point.x / self.size.x, point.z / self.size.z]
+    }
+
+    // Normal from the slope between the neighbouring samples
+    fn sample_normal(&self, x: usize, z: usize) -> Vector3<f32> {
+        let (cell_x, cell_z) = self.cell_size();
+        let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.width - 1));
+        let (z0, z1) = (z.saturating_sub(1), (z + 1).min(self.depth - 1));
+        let slope_x =
+            (self.height(x1, z) - self.height(x0, z)) * self.size.y / ((x1 - x0) as f32 * cell_x);
+        let slope_z =
+            (self.height(x, z1) - self.height(x, z0)) * self.size.y / ((z1 - z0) as f32 * cell_z);
+        Vector3::new(-slope_x, 1.0, -slope_z).normalize()
+    }
+}
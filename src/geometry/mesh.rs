@@ -1,20 +1,109 @@
-use nalgebra::Vector3;
+use crate::geometry::{obj, ply, stl, subdivide};
+use crate::geometry::{Aabb, Bvh};
+use nalgebra::{Point3, Vector3};
+use std::collections::HashMap;
 use std::error::Error;
 use std::f32;
+use std::fmt;
 use std::fs::File;
-use std::io::prelude::*;
+use std::io;
 use std::io::BufReader;
-use std::iter::Iterator;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
+// Faces meeting at a sharper angle than this (in degrees) keep a hard edge when normals
+// are generated for a mesh
+pub const DEFAULT_CREASE_ANGLE: f32 = 60.0;
+
+// Errors from loading a mesh file
+#[derive(Debug)]
+pub enum MeshError {
+    Io(io::Error),
+    // A line of the file that couldn't be understood, line numbers start at 1
+    Parse { line: usize, message: String },
+    // A problem with a file that isn't tied to a line, like in a binary file
+    Format(String),
+}
+
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::Io(e) => write!(f, "{}", e),
+            MeshError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            MeshError::Format(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for MeshError {}
+
+impl From<io::Error> for MeshError {
+    fn from(e: io::Error) -> MeshError {
+        MeshError::Io(e)
+    }
+}
+
+// How texture coordinates are made up for faces that don't have any
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum UvProjection {
+    // The x and z coordinates of the point, looking down on the mesh from above
+    Planar,
+    // Longitude and latitude around the center of the mesh, each from 0 to 1
+    Spherical,
+    // Angle around the y axis through the center of the mesh and height up the mesh, each
+    // from 0 to 1
+    Cylindrical,
+    // Planar along whichever axis the face points down the most
+    Box,
+}
+
+impl UvProjection {
+    pub fn from_name(name: &str) -> Option<UvProjection> {
+        match name {
+            "planar" => Some(UvProjection::Planar),
+            "spherical" | "sphere" => Some(UvProjection::Spherical),
+            "cylindrical" | "cylinder" => Some(UvProjection::Cylindrical),
+            "box" | "cube" => Some(UvProjection::Box),
+            _ => None,
+        }
+    }
+}
+
+// A named range of faces, from an `o`, `g` or `usemtl` line in an obj file
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct MeshGroup {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Mesh {
     pub vertices: Vec<Vector3<f32>>,
     // Each face represents three indexes in the vertices vec
     pub faces: Vec<[usize; 3]>,
 
+    // Texture coordinates and normals are indexed separately from the vertices, so each
+    // corner of a face can have its own. These are parallel to faces, and None for faces
+    // which didn't specify them.
+    pub uvs: Vec<[f32; 2]>,
+    pub face_uvs: Vec<Option<[usize; 3]>>,
+    pub projection: UvProjection,
+    pub normals: Vec<Vector3<f32>>,
+    pub face_normals: Vec<Option<[usize; 3]>>,
+    // Linear color of every vertex, or empty if the file didn't have any
+    pub colors: Vec<Vector3<f32>>,
+    pub groups: Vec<MeshGroup>,
+    // Faces using each material named by `usemtl`, and the `mtllib` files defining them
+    pub material_groups: Vec<MeshGroup>,
+    pub material_libraries: Vec<String>,
+
     // Track a bounding box to improve performance
     pub aabb_corner: Vector3<f32>,
     pub aabb_size: Vector3<f32>,
+
+    // Hierarchy over the faces, so rays only test the triangles they pass near
+    pub bvh: Bvh,
 }
 
 // Generate a bounding box for a set of vertices
@@ -47,57 +136,231 @@ fn generate_bounding_box(vertices: &[Vector3<f32>]) -> (Vector3<f32>, Vector3<f3
 }
 
 impl Mesh {
-    // Load a mesh from a file
-    pub fn from_file(file_name: &str) -> Result<Mesh, Box<Error>> {
-        let mut vertices = vec![];
-        let mut faces = vec![];
+    // A mesh with only positions, the bounding box and hierarchy are built straight away
+    pub fn new(vertices: Vec<Vector3<f32>>, faces: Vec<[usize; 3]>) -> Mesh {
+        let mut mesh = Mesh::without_bvh(vertices, faces);
+        mesh.build_bvh();
+        mesh
+    }
+
+    // A mesh with only positions and no hierarchy yet, for when the faces are going to
+    // change before it is used
+    pub(crate) fn without_bvh(vertices: Vec<Vector3<f32>>, faces: Vec<[usize; 3]>) -> Mesh {
+        let (aabb_corner, aabb_size) = generate_bounding_box(&vertices);
+        Mesh {
+            face_uvs: vec![None; faces.len()],
+            face_normals: vec![None; faces.len()],
+            vertices,
+            faces,
+            uvs: vec![],
+            projection: UvProjection::Planar,
+            normals: vec![],
+            colors: vec![],
+            groups: vec![],
+            material_groups: vec![],
+            material_libraries: vec![],
+            aabb_corner,
+            aabb_size,
+            bvh: Bvh::default(),
+        }
+    }
+
+    // Load a mesh from a file, with the format chosen by the extension: stanford (.ply),
+    // stereolithography (.stl) or wavefront obj for anything else. Material libraries of
+    // obj files are found relative to the directory of the obj file.
+    pub fn from_file(file_name: &str) -> Result<Mesh, MeshError> {
+        Mesh::from_file_subdivided(file_name, 0, None)
+    }
+
+    // Load a mesh from a file and smooth it with `levels` steps of subdivision, before any
+    // normals are generated so only the hard edges given in the file stay sharp. Edges bent
+    // further than the crease angle (in degrees) are kept sharp too.
+    pub fn from_file_subdivided(
+        file_name: &str,
+        levels: usize,
+        crease_angle: Option<f32>,
+    ) -> Result<Mesh, MeshError> {
         let file_reader = BufReader::new(File::open(file_name)?);
+        let path = Path::new(file_name);
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        let mut mesh = match extension.as_deref() {
+            Some("ply") => ply::parse(file_reader)?,
+            Some("stl") => stl::parse(file_reader)?,
+            _ => {
+                let mut mesh = obj::parse(file_reader)?;
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                mesh.material_libraries = mesh
+                    .material_libraries
+                    .iter()
+                    .map(|library| directory.join(library).to_string_lossy().into_owned())
+                    .collect();
+                mesh
+            }
+        };
+        mesh.subdivide(levels, crease_angle);
+        mesh.generate_normals(DEFAULT_CREASE_ANGLE);
+        Ok(mesh)
+    }
 
-        for l in file_reader.lines() {
-            let line = l?;
-            let mut parts = line.split_whitespace();
-            let first = parts.next();
-            if first.is_some() {
-                match first.unwrap() {
-                    "v" => {
-                        let x: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let y: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        let z: f32 = parts.next().ok_or("Incorrect file format")?.parse()?;
-                        vertices.push(Vector3::new(x, y, z));
-                    }
-                    "f" => {
-                        let av: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let bv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let cv: Vec<&str> = parts
-                            .next()
-                            .ok_or("Incorrect file format")?
-                            .split("/")
-                            .collect();
-                        let a: usize = av[0].parse()?;
-                        let b: usize = bv[0].parse()?;
-                        let c: usize = cv[0].parse()?;
-                        faces.push([a - 1, b - 1, c - 1]);
+    // Split every face into four `levels` times with loop subdivision, which turns a low
+    // poly mesh into a smooth surface through it. Normals are dropped, so generate_normals
+    // has to be called again afterwards.
+    pub fn subdivide(&mut self, levels: usize, crease_angle: Option<f32>) {
+        if levels == 0 {
+            return;
+        }
+        for _ in 0..levels {
+            *self = subdivide::subdivide(self, crease_angle);
+        }
+        self.build_bvh();
+    }
+
+    // Vertex color at a point on a face, blended from the corners of the face
+    pub fn color_at(&self, face: usize, barycentric: &[f32; 3]) -> Option<Vector3<f32>> {
+        if self.colors.is_empty() {
+            return None;
+        }
+        let face = self.faces[face];
+        Some(
+            (self.colors[face[0]] * barycentric[0])
+                + (self.colors[face[1]] * barycentric[1])
+                + (self.colors[face[2]] * barycentric[2]),
+        )
+    }
+
+    // Give smooth normals to faces that don't have any. The normal at each corner averages
+    // the faces around the vertex, weighted by their angle at the vertex, but skips faces
+    // bent further than the crease angle (in degrees) from the face being shaded.
+    pub fn generate_normals(&mut self, crease_angle: f32) {
+        if self.face_normals.iter().all(|normals| normals.is_some()) {
+            return;
+        }
+
+        let cos_crease = crease_angle.to_radians().cos();
+        let face_normals: Vec<Vector3<f32>> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let edge1 = self.vertices[face[1]] - self.vertices[face[0]];
+                let edge2 = self.vertices[face[2]] - self.vertices[face[0]];
+                edge1
+                    .cross(&edge2)
+                    .try_normalize(f32::EPSILON)
+                    .unwrap_or_else(Vector3::zeros)
+            })
+            .collect();
+
+        // Every face touching each vertex, along with the angle of the face at that vertex
+        let mut vertex_faces: Vec<Vec<(usize, f32)>> = vec![vec![]; self.vertices.len()];
+        for (f, face) in self.faces.iter().enumerate() {
+            for corner in 0..3 {
+                let vertex = self.vertices[face[corner]];
+                let a = self.vertices[face[(corner + 1) % 3]] - vertex;
+                let b = self.vertices[face[(corner + 2) % 3]] - vertex;
+                let angle = if a.norm() > 0.0 && b.norm() > 0.0 {
+                    a.angle(&b)
+                } else {
+                    0.0
+                };
+                vertex_faces[face[corner]].push((f, angle));
+            }
+        }
+
+        // Corners around a smooth vertex all get the same normal, so share them
+        let mut shared: HashMap<(usize, [u32; 3]), usize> = HashMap::new();
+        for f in 0..self.faces.len() {
+            if self.face_normals[f].is_some() {
+                continue;
+            }
+            let mut indexes = [0; 3];
+            for (corner, index) in indexes.iter_mut().enumerate() {
+                let vertex = self.faces[f][corner];
+                let mut sum = Vector3::zeros();
+                for &(other, angle) in vertex_faces[vertex].iter() {
+                    if face_normals[other].dot(&face_normals[f]) >= cos_crease {
+                        sum += face_normals[other] * angle;
                     }
-                    // Ignore all other lines
-                    _ => {}
                 }
+                let normal = sum.try_normalize(f32::EPSILON).unwrap_or(face_normals[f]);
+                let key = (
+                    vertex,
+                    [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits()],
+                );
+                let normals = &mut self.normals;
+                *index = *shared.entry(key).or_insert_with(|| {
+                    normals.push(normal);
+                    normals.len() - 1
+                });
             }
+            self.face_normals[f] = Some(indexes);
         }
+    }
 
-        let (aabb_corner, aabb_size) = generate_bounding_box(&vertices);
-        Ok(Mesh {
-            vertices,
-            faces,
-            aabb_corner,
-            aabb_size,
-        })
+    // Texture coordinates at a point on a face, blended from the corners of the face with
+    // the barycentric coordinates of the point. Faces without texture coordinates use the
+    // mesh's projection instead.
+    pub fn uv_at(
+        &self,
+        face: usize,
+        barycentric: &[f32; 3],
+        point: &Vector3<f32>,
+        normal: &Vector3<f32>,
+    ) -> [f32; 2] {
+        if let Some(indexes) = self.face_uvs[face] {
+            let mut uv = [0.0f32; 2];
+            for (corner, &index) in indexes.iter().enumerate() {
+                uv[0] += self.uvs[index][0] * barycentric[corner];
+                uv[1] += self.uvs[index][1] * barycentric[corner];
+            }
+            return uv;
+        }
+
+        let center = self.aabb_corner + (self.aabb_size / 2.0);
+        let offset = point - center;
+        let around = offset.x.atan2(offset.z) / (2.0 * f32::consts::PI) + 0.5;
+        match self.projection {
+            UvProjection::Planar => [point.x, point.z],
+            UvProjection::Spherical => {
+                let radius = offset.norm().max(f32::EPSILON);
+                let down = (offset.y / radius).clamp(-1.0, 1.0).acos() / f32::consts::PI;
+                [around, down]
+            }
+            UvProjection::Cylindrical => {
+                let height = (point.y - self.aabb_corner.y) / self.aabb_size.y.max(f32::EPSILON);
+                [around, height]
+            }
+            UvProjection::Box => {
+                let (x, y, z) = (normal.x.abs(), normal.y.abs(), normal.z.abs());
+                if x >= y && x >= z {
+                    [point.z, point.y]
+                } else if y >= z {
+                    [point.x, point.z]
+                } else {
+                    [point.x, point.y]
+                }
+            }
+        }
+    }
+
+    // Rebuild the face hierarchy, this needs to be called whenever the faces change.
+    // Returns how long the build took, the caller decides whether that is worth reporting.
+    pub fn build_bvh(&mut self) -> Duration {
+        let start = Instant::now();
+        let bounds: Vec<Aabb> = self
+            .faces
+            .iter()
+            .map(|face| {
+                let mut bounds = Aabb::empty();
+                for &vertex in face.iter() {
+                    bounds.grow(&Point3::from(self.vertices[vertex]));
+                }
+                bounds
+            })
+            .collect();
+        self.bvh = Bvh::build(&bounds);
+        start.elapsed()
     }
 }
@@ -1,11 +1,25 @@
+mod bounds;
+mod bvh;
+mod heightfield;
 mod mesh;
+mod obj;
+mod ply;
 mod primitive;
 mod ray;
+mod sdf;
+mod stl;
+mod subdivide;
+#[cfg(test)]
+mod testing;
 pub mod volume;
 
-pub use self::mesh::Mesh;
-pub use self::primitive::Primitive;
+pub use self::bounds::Aabb;
+pub use self::bvh::Bvh;
+pub use self::heightfield::Heightfield;
+pub use self::mesh::{Mesh, MeshError, MeshGroup, UvProjection, DEFAULT_CREASE_ANGLE};
+pub use self::primitive::{Crossing, Primitive};
 pub use self::ray::Ray;
+pub use self::sdf::{Sdf, SdfGrid};
 
 use nalgebra::Vector3;
 use roots::Roots;
@@ -35,9 +49,6 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
     }
 
     if (tmin > tymax) || (tymin > tmax) {
-        if size.x == 1000.0f32 {
-            println!("1");
-        }
         return Roots::No([]);
     }
     if tymin > tmin {
@@ -56,9 +67,6 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
     }
 
     if (tmin > tzmax) || (tzmin > tmax) {
-        if size.x == 1000.0f32 {
-            println!("4");
-        }
         return Roots::No([]);
     }
     if tzmin > tmin {
@@ -70,19 +78,10 @@ pub fn aabb_collision(ray: &Ray, pos: &Vector3<f32>, size: &Vector3<f32>) -> Roo
 
     if tmin <= CUBE_EPS {
         if tmax <= CUBE_EPS {
-            if size.x == 1000.0f32 {
-                println!("2");
-            }
             return Roots::No([]);
         }
-        if size.x == 1000.0f32 {
-            println!("3");
-        }
         Roots::One([tmax])
     } else {
-        if size.x == 1000.0f32 {
-            println!("5");
-        }
         Roots::Two([tmin, tmax])
     }
 }
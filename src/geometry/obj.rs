@@ -0,0 +1,291 @@
+use crate::geometry::{Mesh, MeshError, MeshGroup};
+use nalgebra::Vector3;
+use std::io::BufRead;
+
+// One corner of a face, as indexes into the positions, texture coordinates and normals
+#[derive(Debug, Clone, Copy)]
+struct Corner {
+    vertex: usize,
+    uv: Option<usize>,
+    normal: Option<usize>,
+}
+
+fn parse_error(line: usize, message: String) -> MeshError {
+    MeshError::Parse { line, message }
+}
+
+// Parse the numbers following a v, vt or vn keyword, which needs at least `min` of them
+fn parse_floats<'a, I>(parts: I, line: usize, min: usize, kind: &str) -> Result<Vec<f32>, MeshError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let values = parts
+        .map(|part| {
+            part.parse::<f32>()
+                .map_err(|_| parse_error(line, format!("invalid {} value \'{}\'", kind, part)))
+        })
+        .collect::<Result<Vec<f32>, MeshError>>()?;
+    if values.len() < min {
+        return Err(parse_error(
+            line,
+            format!("{} needs {} values but has {}", kind, min, values.len()),
+        ));
+    }
+    Ok(values)
+}
+
+// Indexes start at 1, and negative indexes count back from the last element defined so far
+fn resolve_index(token: &str, count: usize, line: usize, kind: &str) -> Result<usize, MeshError> {
+    let index: i64 = token
+        .parse()
+        .map_err(|_| parse_error(line, format!("invalid {} index \'{}\'", kind, token)))?;
+    let resolved = if index > 0 {
+        index - 1
+    } else if index < 0 {
+        count as i64 + index
+    } else {
+        return Err(parse_error(line, format!("{} index can't be 0", kind)));
+    };
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(parse_error(
+            line,
+            format!(
+                "{} index {} is out of range, {} defined",
+                kind, index, count
+            ),
+        ));
+    }
+    Ok(resolved as usize)
+}
+
+// A face corner is one of v, v/vt, v//vn or v/vt/vn
+fn parse_corner(
+    token: &str,
+    counts: (usize, usize, usize),
+    line: usize,
+) -> Result<Corner, MeshError> {
+    let mut fields = token.split('/');
+    let vertex = resolve_index(fields.next().unwrap_or(""), counts.0, line, "vertex")?;
+    let uv = match fields.next() {
+        Some(field) if !field.is_empty() => {
+            Some(resolve_index(field, counts.1, line, "texture coordinate")?)
+        }
+        _ => None,
+    };
+    let normal = match fields.next() {
+        Some(field) if !field.is_empty() => Some(resolve_index(field, counts.2, line, "normal")?),
+        _ => None,
+    };
+    Ok(Corner { vertex, uv, normal })
+}
+
+// Indexes for a triangle, if every corner has one
+fn triangle_indexes(corners: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    match corners {
+        [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+        _ => None,
+    }
+}
+
+// Close off the faces added since a group started, empty groups are dropped
+fn end_group(groups: &mut Vec<MeshGroup>, group: Option<(String, usize)>, end: usize) {
+    if let Some((name, start)) = group {
+        if start < end {
+            groups.push(MeshGroup { name, start, end });
+        }
+    }
+}
+
+// Parse a wavefront obj file. Polygons are split into triangles, texture coordinates and
+// normals are kept, and `o`/`g` lines split the faces into named groups. Materials from
+// `usemtl` lines are kept as a second set of groups, along with the `mtllib` file names.
+pub fn parse<R: BufRead>(reader: R) -> Result<Mesh, MeshError> {
+    let mut vertices = vec![];
+    let mut uvs = vec![];
+    let mut normals = vec![];
+    let mut faces = vec![];
+    let mut face_uvs = vec![];
+    let mut face_normals = vec![];
+    let mut groups = vec![];
+    let mut current_group: Option<(String, usize)> = None;
+    let mut material_groups = vec![];
+    let mut current_material: Option<(String, usize)> = None;
+    let mut material_libraries = vec![];
+
+    for (i, l) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = l?;
+        // Anything after a # is a comment
+        let content = line.split('#').next().unwrap_or("");
+        let mut parts = content.split_whitespace();
+        let keyword = match parts.next() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+
+        match keyword {
+            "v" => {
+                // Some exporters add a w coordinate or vertex colors, which are ignored
+                let v = parse_floats(parts, line_number, 3, "vertex")?;
+                vertices.push(Vector3::new(v[0], v[1], v[2]));
+            }
+            "vt" => {
+                // Obj files put v = 0 at the bottom of the image, while textures are read
+                // from the top row down
+                let t = parse_floats(parts, line_number, 1, "texture coordinate")?;
+                uvs.push([t[0], 1.0 - t.get(1).cloned().unwrap_or(0.0)]);
+            }
+            "vn" => {
+                let n = parse_floats(parts, line_number, 3, "normal")?;
+                normals.push(Vector3::new(n[0], n[1], n[2]));
+            }
+            "f" => {
+                let counts = (vertices.len(), uvs.len(), normals.len());
+                let corners = parts
+                    .map(|token| parse_corner(token, counts, line_number))
+                    .collect::<Result<Vec<Corner>, MeshError>>()?;
+                if corners.len() < 3 {
+                    return Err(parse_error(
+                        line_number,
+                        format!("face needs at least 3 vertices but has {}", corners.len()),
+                    ));
+                }
+
+                // Fan triangulation, which is correct for the convex polygons obj files use
+                for k in 1..corners.len() - 1 {
+                    let triangle = [corners[0], corners[k], corners[k + 1]];
+                    faces.push([triangle[0].vertex, triangle[1].vertex, triangle[2].vertex]);
+                    face_uvs.push(triangle_indexes([
+                        triangle[0].uv,
+                        triangle[1].uv,
+                        triangle[2].uv,
+                    ]));
+                    face_normals.push(triangle_indexes([
+                        triangle[0].normal,
+                        triangle[1].normal,
+                        triangle[2].normal,
+                    ]));
+                }
+            }
+            "o" | "g" => {
+                end_group(&mut groups, current_group.take(), faces.len());
+                let name = parts.collect::<Vec<&str>>().join(" ");
+                current_group = Some((name, faces.len()));
+            }
+            "usemtl" => {
+                end_group(&mut material_groups, current_material.take(), faces.len());
+                let name = parts.collect::<Vec<&str>>().join(" ");
+                current_material = Some((name, faces.len()));
+            }
+            "mtllib" => {
+                // File names can contain spaces, so the rest of the line is the name
+                let name = parts.collect::<Vec<&str>>().join(" ");
+                if name.is_empty() {
+                    return Err(parse_error(line_number, "mtllib needs a file name".into()));
+                }
+                material_libraries.push(name);
+            }
+            // Ignore all other lines
+            _ => {}
+        }
+    }
+
+    end_group(&mut groups, current_group, faces.len());
+    end_group(&mut material_groups, current_material, faces.len());
+
+    let mut mesh = Mesh::new(vertices, faces);
+    mesh.uvs = uvs;
+    mesh.face_uvs = face_uvs;
+    mesh.normals = normals;
+    mesh.face_normals = face_normals;
+    mesh.groups = groups;
+    mesh.material_groups = material_groups;
+    mesh.material_libraries = material_libraries;
+    Ok(mesh)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(source: &str) -> Result<Mesh, MeshError> {
+        parse(source.as_bytes())
+    }
+
+    #[test]
+    fn texture_coordinates_start_at_the_bottom_of_the_image() {
+        let mesh = parse_str(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+             f 1/1 2/2 3/3 4/4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            mesh.uvs,
+            vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]
+        );
+
+        // The bottom left corner of the quad reads from the bottom row of the texture and
+        // the top right corner from the top row
+        let normal = Vector3::z();
+        let bottom_left = mesh.uv_at(0, &[1.0, 0.0, 0.0], &Vector3::zeros(), &normal);
+        let top_right = mesh.uv_at(0, &[0.0, 0.0, 1.0], &Vector3::new(1.0, 1.0, 0.0), &normal);
+        assert_eq!(bottom_left, [0.0, 1.0]);
+        assert_eq!(top_right, [1.0, 0.0]);
+    }
+
+    #[test]
+    fn negative_indexes_count_back_from_the_last_element_so_far() {
+        let mesh = parse_str(
+            "v 0 0 0\nv 1 0 0\nv 1 1 0\nvn 0 0 1\n\
+             f -3//-1 -2//-1 -1//-1\n\
+             v 0 0 1\nv 1 0 1\nv 1 1 1\nvn 0 0 -1\n\
+             f -3//-1 -2//-1 -1//-1\n\
+             f 1//1 -2//-2 6//2\n",
+        )
+        .unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [3, 4, 5], [0, 4, 5]]);
+        assert_eq!(
+            mesh.face_normals,
+            vec![Some([0, 0, 0]), Some([1, 1, 1]), Some([0, 0, 1])]
+        );
+    }
+
+    #[test]
+    fn polygons_are_split_into_a_fan_of_triangles() {
+        let mesh = parse_str(
+            "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\n\
+             vt 0 0\nvt 1 0\nvt 1 1\nvt 0.5 1\nvt 0 1\n\
+             f 1/1 2/2 3/3 4/4 5/5\n",
+        )
+        .unwrap();
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert_eq!(
+            mesh.face_uvs,
+            vec![Some([0, 1, 2]), Some([0, 2, 3]), Some([0, 3, 4])]
+        );
+    }
+
+    #[test]
+    fn faces_missing_some_texture_coordinates_have_none() {
+        let mesh = parse_str("v 0 0 0\nv 1 0 0\nv 1 1 0\nvt 0 0\nf 1/1 2 3\n").unwrap();
+        assert_eq!(mesh.face_uvs, vec![None]);
+    }
+
+    fn error_line(source: &str) -> usize {
+        match parse_str(source) {
+            Err(MeshError::Parse { line, .. }) => line,
+            other => panic!("expected a parse error, got {:?}", other.map(|m| m.faces)),
+        }
+    }
+
+    #[test]
+    fn errors_report_the_line_they_are_on() {
+        assert_eq!(error_line("v 0 0 0\n# comment\n\nv 1 x 0\n"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 1 2 4\n"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nv 1 1 0\nf 0 1 2\n"), 4);
+        assert_eq!(error_line("v 0 0 0\nv 1 0 0\nf 1 2\n"), 3);
+        assert_eq!(error_line("v 0 0\n"), 1);
+        assert_eq!(error_line("mtllib\n"), 1);
+    }
+}
@@ -0,0 +1,438 @@
+use crate::display::srgb_to_linear;
+use crate::geometry::{Mesh, MeshError};
+use nalgebra::Vector3;
+use std::io::BufRead;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    LittleEndian,
+    BigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scalar {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl Scalar {
+    fn from_name(name: &str) -> Option<Scalar> {
+        match name {
+            "char" | "int8" => Some(Scalar::Int8),
+            "uchar" | "uint8" => Some(Scalar::Uint8),
+            "short" | "int16" => Some(Scalar::Int16),
+            "ushort" | "uint16" => Some(Scalar::Uint16),
+            "int" | "int32" => Some(Scalar::Int32),
+            "uint" | "uint32" => Some(Scalar::Uint32),
+            "float" | "float32" => Some(Scalar::Float32),
+            "double" | "float64" => Some(Scalar::Float64),
+            _ => None,
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            Scalar::Int8 | Scalar::Uint8 => 1,
+            Scalar::Int16 | Scalar::Uint16 => 2,
+            Scalar::Int32 | Scalar::Uint32 | Scalar::Float32 => 4,
+            Scalar::Float64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Property {
+    Scalar(String, Scalar),
+    // A list of values preceded by their count, like the corners of a face
+    List(String, Scalar, Scalar),
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+fn parse_error(line: usize, message: String) -> MeshError {
+    MeshError::Parse { line, message }
+}
+
+// Reads the values in the body of the file, either as text or as raw numbers
+struct ValueReader<R> {
+    reader: R,
+    format: Format,
+    // Unread values on the current line of an ascii file
+    tokens: Vec<String>,
+    line: usize,
+}
+
+impl<R: BufRead> ValueReader<R> {
+    fn read(&mut self, scalar: Scalar) -> Result<f64, MeshError> {
+        if self.format == Format::Ascii {
+            while self.tokens.is_empty() {
+                let mut line = String::new();
+                if self.reader.read_line(&mut line)? == 0 {
+                    return Err(parse_error(self.line, "unexpected end of file".into()));
+                }
+                self.line += 1;
+                self.tokens = line.split_whitespace().rev().map(String::from).collect();
+            }
+            let token = self.tokens.pop().unwrap();
+            return token
+                .parse()
+                .map_err(|_| parse_error(self.line, format!("invalid value \'{}\'", token)));
+        }
+
+        let mut buffer = [0u8; 8];
+        let bytes = &mut buffer[..scalar.size()];
+        self.reader.read_exact(bytes)?;
+        // Values are decoded as little endian, so big endian values are flipped first
+        if self.format == Format::BigEndian {
+            bytes.reverse();
+        }
+        let b = buffer;
+        Ok(match scalar {
+            Scalar::Int8 => f64::from(b[0] as i8),
+            Scalar::Uint8 => f64::from(b[0]),
+            Scalar::Int16 => f64::from(i16::from_le_bytes([b[0], b[1]])),
+            Scalar::Uint16 => f64::from(u16::from_le_bytes([b[0], b[1]])),
+            Scalar::Int32 => f64::from(i32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::Uint32 => f64::from(u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::Float32 => f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            Scalar::Float64 => f64::from_le_bytes(b),
+        })
+    }
+}
+
+// Parse the header, up to and including the end_header line
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<(Format, Vec<Element>, usize), MeshError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = vec![];
+    let mut line_number = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(parse_error(line_number, "header has no end_header".into()));
+        }
+        line_number += 1;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if line_number == 1 {
+            if parts.first() != Some(&"ply") {
+                return Err(parse_error(1, "not a ply file".into()));
+            }
+            continue;
+        }
+
+        match parts.as_slice() {
+            ["format", name, ..] => {
+                format = Some(match *name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::LittleEndian,
+                    "binary_big_endian" => Format::BigEndian,
+                    _ => {
+                        return Err(parse_error(
+                            line_number,
+                            format!("unknown format \'{}\'", name),
+                        ))
+                    }
+                });
+            }
+            ["element", name, count] => {
+                let count = count.parse().map_err(|_| {
+                    parse_error(line_number, format!("invalid element count \'{}\'", count))
+                })?;
+                elements.push(Element {
+                    name: name.to_string(),
+                    count,
+                    properties: vec![],
+                });
+            }
+            ["property", rest @ ..] => {
+                let property = match rest {
+                    ["list", count_type, item_type, name] => Property::List(
+                        name.to_string(),
+                        scalar_type(count_type, line_number)?,
+                        scalar_type(item_type, line_number)?,
+                    ),
+                    [scalar, name] => {
+                        Property::Scalar(name.to_string(), scalar_type(scalar, line_number)?)
+                    }
+                    _ => return Err(parse_error(line_number, "invalid property".into())),
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(property),
+                    None => {
+                        return Err(parse_error(
+                            line_number,
+                            "property before any element".into(),
+                        ))
+                    }
+                }
+            }
+            ["end_header"] => break,
+            // Comments, obj_info and blank lines
+            _ => {}
+        }
+    }
+
+    match format {
+        Some(format) => Ok((format, elements, line_number)),
+        None => Err(parse_error(line_number, "header has no format".into())),
+    }
+}
+
+fn scalar_type(name: &str, line: usize) -> Result<Scalar, MeshError> {
+    Scalar::from_name(name).ok_or_else(|| parse_error(line, format!("unknown type \'{}\'", name)))
+}
+
+// Parse a stanford ply file, in ascii or binary. Vertex positions, normals, texture
+// coordinates and colors are read from the vertex element and faces from the face
+// element, anything else is skipped.
+pub fn parse<R: BufRead>(mut reader: R) -> Result<Mesh, MeshError> {
+    let (format, elements, header_lines) = parse_header(&mut reader)?;
+    let mut values = ValueReader {
+        reader,
+        format,
+        tokens: vec![],
+        line: header_lines,
+    };
+
+    let mut vertices = vec![];
+    let mut normals = vec![];
+    let mut uvs = vec![];
+    let mut colors = vec![];
+    let mut faces = vec![];
+    for element in elements.iter() {
+        for _ in 0..element.count {
+            let mut scalars: Vec<(&str, Scalar, f64)> = vec![];
+            let mut corners: Vec<f64> = vec![];
+            for property in element.properties.iter() {
+                match property {
+                    Property::Scalar(name, scalar) => {
+                        scalars.push((name, *scalar, values.read(*scalar)?));
+                    }
+                    Property::List(name, count_type, item_type) => {
+                        let count = values.read(*count_type)? as usize;
+                        let list = (0..count)
+                            .map(|_| values.read(*item_type))
+                            .collect::<Result<Vec<f64>, MeshError>>()?;
+                        if name == "vertex_indices" || name == "vertex_index" {
+                            corners = list;
+                        }
+                    }
+                }
+            }
+
+            let get = |names: &[&str]| {
+                scalars
+                    .iter()
+                    .find(|(name, _, _)| names.contains(name))
+                    .map(|&(_, scalar, value)| (scalar, value as f32))
+            };
+            match element.name.as_str() {
+                "vertex" => {
+                    let position = [get(&["x"]), get(&["y"]), get(&["z"])];
+                    match position {
+                        [Some(x), Some(y), Some(z)] => vertices.push(Vector3::new(x.1, y.1, z.1)),
+                        _ => return Err(MeshError::Format("vertex has no position".into())),
+                    }
+                    if let [Some(x), Some(y), Some(z)] = [get(&["nx"]), get(&["ny"]), get(&["nz"])]
+                    {
+                        normals.push(Vector3::new(x.1, y.1, z.1));
+                    }
+                    let u = get(&["u", "s", "texture_u", "texture_s"]);
+                    let v = get(&["v", "t", "texture_v", "texture_t"]);
+                    // Like obj files, v = 0 is the bottom of the image
+                    if let (Some(u), Some(v)) = (u, v) {
+                        uvs.push([u.1, 1.0 - v.1]);
+                    }
+                    let color = [
+                        get(&["red", "r", "diffuse_red"]),
+                        get(&["green", "g", "diffuse_green"]),
+                        get(&["blue", "b", "diffuse_blue"]),
+                    ];
+                    if let [Some(r), Some(g), Some(b)] = color {
+                        colors.push(Vector3::new(color_value(r), color_value(g), color_value(b)));
+                    }
+                }
+                "face" => {
+                    if corners.len() < 3 {
+                        return Err(MeshError::Format(format!(
+                            "face needs at least 3 vertices but has {}",
+                            corners.len()
+                        )));
+                    }
+                    let corners = corners
+                        .iter()
+                        .map(|&corner| vertex_index(corner))
+                        .collect::<Result<Vec<usize>, MeshError>>()?;
+                    // Fan triangulation, like obj polygons
+                    for k in 1..corners.len() - 1 {
+                        faces.push([corners[0], corners[k], corners[k + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(face) = faces
+        .iter()
+        .find(|face| face.iter().any(|&i| i >= vertices.len()))
+    {
+        return Err(MeshError::Format(format!(
+            "face {:?} uses a vertex that doesn't exist, {} defined",
+            face,
+            vertices.len()
+        )));
+    }
+
+    let mut mesh = Mesh::new(vertices, faces);
+    // Attributes are only kept if every vertex has them, since they share the vertex indexes
+    let count = mesh.vertices.len();
+    if normals.len() == count {
+        mesh.face_normals = mesh.faces.iter().map(|face| Some(*face)).collect();
+        mesh.normals = normals;
+    }
+    if uvs.len() == count {
+        mesh.face_uvs = mesh.faces.iter().map(|face| Some(*face)).collect();
+        mesh.uvs = uvs;
+    }
+    if colors.len() == count {
+        mesh.colors = colors;
+    }
+    Ok(mesh)
+}
+
+// Indexes are read like any other value, so floating point lists could give fractions
+// or negative numbers which would otherwise be silently cast to some other vertex
+fn vertex_index(value: f64) -> Result<usize, MeshError> {
+    if value >= 0.0 && value.fract() == 0.0 && value <= usize::MAX as f64 {
+        Ok(value as usize)
+    } else {
+        Err(MeshError::Format(format!("invalid vertex index {}", value)))
+    }
+}
+
+// Integer colors are stored with the sRGB curve applied, floating point ones are linear
+fn color_value((scalar, value): (Scalar, f32)) -> f32 {
+    match scalar {
+        Scalar::Float32 | Scalar::Float64 => value,
+        Scalar::Uint16 => srgb_to_linear(value / 65535.0),
+        _ => srgb_to_linear(value / 255.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "element vertex 4\n\
+                          property float x\n\
+                          property float y\n\
+                          property float z\n\
+                          property float s\n\
+                          property float t\n\
+                          property uchar red\n\
+                          property uchar green\n\
+                          property uchar blue\n\
+                          element face 1\n\
+                          property list uchar int vertex_indices\n\
+                          element edge 1\n\
+                          property short vertex1\n\
+                          property short vertex2\n\
+                          end_header\n";
+
+    // A unit quad with texture coordinates and colors, split into two triangles
+    const VERTICES: [[f32; 5]; 4] = [
+        [0.0, 0.0, 0.0, 0.0, 0.0],
+        [1.0, 0.0, 0.0, 1.0, 0.0],
+        [1.0, 1.0, 0.0, 1.0, 1.0],
+        [0.0, 1.0, 0.0, 0.0, 1.0],
+    ];
+    const COLORS: [[u8; 3]; 4] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+
+    fn ascii() -> Vec<u8> {
+        let mut file = format!("ply\nformat ascii 1.0\ncomment a quad\n{}", HEADER);
+        for (v, c) in VERTICES.iter().zip(COLORS.iter()) {
+            file += &format!(
+                "{} {} {} {} {} {} {} {}\n",
+                v[0], v[1], v[2], v[3], v[4], c[0], c[1], c[2]
+            );
+        }
+        file += "4 0 1 2 3\n0 2\n";
+        file.into_bytes()
+    }
+
+    fn binary(format: &str, big_endian: bool) -> Vec<u8> {
+        let mut file = format!("ply\nformat {} 1.0\n{}", format, HEADER).into_bytes();
+        let order = |mut bytes: Vec<u8>| {
+            if big_endian {
+                bytes.reverse();
+            }
+            bytes
+        };
+        for (v, c) in VERTICES.iter().zip(COLORS.iter()) {
+            for value in v.iter() {
+                file.extend(order(value.to_le_bytes().to_vec()));
+            }
+            file.extend_from_slice(c);
+        }
+        file.push(4);
+        for index in 0..4i32 {
+            file.extend(order(index.to_le_bytes().to_vec()));
+        }
+        for index in [0i16, 2].iter() {
+            file.extend(order(index.to_le_bytes().to_vec()));
+        }
+        file
+    }
+
+    #[test]
+    fn ascii_and_binary_files_give_the_same_mesh() {
+        let ascii = parse(&ascii()[..]).unwrap();
+        assert_eq!(ascii.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(ascii.vertices[2], Vector3::new(1.0, 1.0, 0.0));
+        // Texture coordinates are flipped to run down the image, like obj files
+        assert_eq!(ascii.uvs[0], [0.0, 1.0]);
+        assert_eq!(ascii.face_uvs, vec![Some([0, 1, 2]), Some([0, 2, 3])]);
+        assert_eq!(ascii.colors[0], Vector3::new(1.0, 0.0, 0.0));
+
+        let little = parse(&binary("binary_little_endian", false)[..]).unwrap();
+        let big = parse(&binary("binary_big_endian", true)[..]).unwrap();
+        assert_eq!(little, ascii);
+        assert_eq!(big, ascii);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let mut file = binary("binary_little_endian", false);
+        file.truncate(file.len() - 6);
+        assert!(parse(&file[..]).is_err());
+        assert!(parse(&b"ply\nformat ascii 1.0\nelement vertex 1\n"[..]).is_err());
+        assert!(parse(&b"obj\n"[..]).is_err());
+    }
+
+    #[test]
+    fn faces_must_use_existing_vertices() {
+        let with_face = |face: &str| {
+            let file = String::from_utf8(ascii()).unwrap();
+            file.replace("4 0 1 2 3\n", face).into_bytes()
+        };
+        assert!(parse(&with_face("3 0 1 3\n")[..]).is_ok());
+        for face in ["3 0 1 4\n", "3 0 -1 2\n", "3 0 1.5 2\n", "3 0 nan 2\n"].iter() {
+            match parse(&with_face(face)[..]) {
+                Err(MeshError::Format(_)) => {}
+                result => panic!("{:?} gave {:?}", face, result),
+            }
+        }
+    }
+}
@@ -1,7 +1,6 @@
-use crate::geometry::{aabb_collision, Mesh, Ray};
-use nalgebra::{dot, Unit, Vector3};
-use roots::find_roots_quadratic;
-use roots::Roots;
+use crate::geometry::{aabb_collision, Aabb, Heightfield, Mesh, Ray, Sdf};
+use nalgebra::{Point3, Unit, Vector3};
+use roots::{find_roots_quadratic, find_roots_quartic, Roots};
 use std::f32;
 
 const SPHERE_EPS: f32 = 0.0001;
@@ -9,17 +8,59 @@ const CYLINDER_EPS: f32 = 0.0001;
 const CONE_EPS: f32 = 0.001;
 const CLOSE_EPS: f32 = 0.001;
 const TRIANGLE_EPS: f32 = 0.0000001;
+const PLANE_EPS: f32 = 0.0001;
+const TORUS_EPS: f64 = 0.0001;
+const CAPSULE_EPS: f32 = 0.0001;
+// Flat primitives get a little thickness so their bounds can be hit edge on
+const FLAT_BOUNDS: f32 = 0.0001;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+// Primitives are shared behind an Arc, so the size of the mesh variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Primitive {
     Sphere,
     Cube,
     Cylinder,
     Cone,
     Mesh(Mesh),
+    // The xz plane, facing up the y axis
+    Plane,
+    // A disc of radius 1 in the xz plane
+    Disc,
+    // A square from (-1, 0, -1) to (1, 0, 1) in the xz plane
+    Rectangle,
+    // A ring of radius 1 around the y axis, with a tube of the given radius
+    Torus { radius: f32 },
+    // A cylinder from y = 0 up to the given height, capped by hemispheres
+    Capsule { radius: f32, height: f32 },
+    // A signed distance field, found by sphere tracing
+    Sdf(Sdf),
+    Heightfield(Heightfield),
     None,
 }
 
+// A point where a ray passes through the surface of a primitive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Crossing {
+    pub t_value: f32,
+    pub normal: Vector3<f32>,
+    pub uv: [f32; 2],
+    pub face: Option<usize>,
+    pub color: Option<Vector3<f32>>,
+}
+
+impl Crossing {
+    fn new(t_value: f32, normal: Vector3<f32>, uv: [f32; 2]) -> Crossing {
+        Crossing {
+            t_value,
+            normal,
+            uv,
+            face: None,
+            color: None,
+        }
+    }
+}
+
 impl Primitive {
     pub fn collides(
         &self,
@@ -27,16 +68,110 @@ impl Primitive {
         t_value: &mut f32,
         normal: &mut Vector3<f32>,
         uv: &mut [f32; 2],
+        face: &mut Option<usize>,
+        color: &mut Option<Vector3<f32>>,
     ) -> bool {
         match self {
-            Primitive::Sphere => sphere_collides(ray, t_value, normal),
-            Primitive::Cylinder => cylinder_collides(ray, t_value, normal, uv),
-            Primitive::Cone => cone_collides(ray, t_value, normal),
-            Primitive::Cube => cube_collides(ray, t_value, normal),
-            Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv),
+            Primitive::Sphere => closest_surface(ray, t_value, normal, uv, sphere_surfaces),
+            Primitive::Cylinder => closest_surface(ray, t_value, normal, uv, cylinder_surfaces),
+            Primitive::Cone => closest_surface(ray, t_value, normal, uv, cone_surfaces),
+            Primitive::Cube => closest_surface(ray, t_value, normal, uv, cube_surfaces),
+            Primitive::Plane => closest_surface(ray, t_value, normal, uv, plane_surfaces),
+            Primitive::Disc => closest_surface(ray, t_value, normal, uv, disc_surfaces),
+            Primitive::Rectangle => closest_surface(ray, t_value, normal, uv, rectangle_surfaces),
+            Primitive::Torus { radius } => closest_surface(ray, t_value, normal, uv, |ray, hit| {
+                torus_surfaces(ray, *radius, hit)
+            }),
+            Primitive::Capsule { radius, height } => {
+                closest_surface(ray, t_value, normal, uv, |ray, hit| {
+                    capsule_surfaces(ray, *radius, *height, hit)
+                })
+            }
+            Primitive::Sdf(sdf) => closest_surface(ray, t_value, normal, uv, |ray, hit| {
+                sdf.trace(ray, false, hit)
+            }),
+            Primitive::Heightfield(heightfield) => {
+                closest_surface(ray, t_value, normal, uv, |ray, hit| {
+                    heightfield_surfaces(ray, heightfield, hit)
+                })
+            }
+            Primitive::Mesh(mesh) => mesh_collides(ray, mesh, t_value, normal, uv, face, color),
             _ => false,
         }
     }
+
+    // Every point where the ray crosses the surface ahead of its source, closest first.
+    // Solid primitives are closed, so an odd number of crossings means the ray starts inside.
+    pub fn crossings(&self, ray: &Ray) -> Vec<Crossing> {
+        let mut crossings = vec![];
+        let mut add = |t_value, normal, uv| crossings.push(Crossing::new(t_value, normal, uv));
+        match self {
+            Primitive::Sphere => sphere_surfaces(ray, &mut add),
+            Primitive::Cylinder => cylinder_surfaces(ray, &mut add),
+            Primitive::Cone => cone_surfaces(ray, &mut add),
+            Primitive::Cube => cube_surfaces(ray, &mut add),
+            Primitive::Torus { radius } => torus_surfaces(ray, *radius, &mut add),
+            Primitive::Capsule { radius, height } => {
+                capsule_surfaces(ray, *radius, *height, &mut add)
+            }
+            Primitive::Sdf(sdf) => sdf.trace(ray, true, &mut add),
+            Primitive::Mesh(mesh) => {
+                mesh_crossings(ray, mesh, &mut crossings);
+                return crossings;
+            }
+            // Flat primitives and heightfields have no inside to cross into
+            Primitive::Plane
+            | Primitive::Disc
+            | Primitive::Rectangle
+            | Primitive::Heightfield(_)
+            | Primitive::None => return crossings,
+        }
+        crossings.sort_by(|a, b| a.t_value.partial_cmp(&b.t_value).unwrap());
+        crossings
+    }
+
+    // Object space bounds of the primitive, or None if it has no geometry or is infinite
+    pub fn bounds(&self) -> Option<Aabb> {
+        match self {
+            Primitive::Sphere => Some(Aabb::new(
+                Point3::new(-1.0, -1.0, -1.0),
+                Point3::new(1.0, 1.0, 1.0),
+            )),
+            Primitive::Cube => Some(Aabb::new(
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(1.0, 1.0, 1.0),
+            )),
+            Primitive::Cylinder | Primitive::Cone => Some(Aabb::new(
+                Point3::new(-1.0, 0.0, -1.0),
+                Point3::new(1.0, 1.0, 1.0),
+            )),
+            Primitive::Mesh(mesh) => {
+                Some(Aabb::from_corner_size(&mesh.aabb_corner, &mesh.aabb_size))
+            }
+            Primitive::Disc | Primitive::Rectangle => Some(Aabb::new(
+                Point3::new(-1.0, -FLAT_BOUNDS, -1.0),
+                Point3::new(1.0, FLAT_BOUNDS, 1.0),
+            )),
+            Primitive::Torus { radius } => Some(Aabb::new(
+                Point3::new(-1.0 - radius, -radius, -1.0 - radius),
+                Point3::new(1.0 + radius, *radius, 1.0 + radius),
+            )),
+            Primitive::Capsule { radius, height } => Some(Aabb::new(
+                Point3::new(-radius, -radius, -radius),
+                Point3::new(*radius, height + radius, *radius),
+            )),
+            Primitive::Sdf(sdf) => sdf.bounds(),
+            Primitive::Heightfield(heightfield) => Some(Aabb::new(
+                Point3::new(0.0, 0.0, 0.0),
+                Point3::new(
+                    heightfield.size.x,
+                    (heightfield.max_height * heightfield.size.y).max(FLAT_BOUNDS),
+                    heightfield.size.z,
+                ),
+            )),
+            Primitive::Plane | Primitive::None => None,
+        }
+    }
 }
 
 fn close(a: f32, b: f32) -> bool {
@@ -44,67 +179,237 @@ fn close(a: f32, b: f32) -> bool {
     diff < CLOSE_EPS
 }
 
-fn cube_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+// Keep the closest of the hits that `surfaces` reports for the ray
+fn closest_surface<F>(
+    ray: &Ray,
+    t_value: &mut f32,
+    normal: &mut Vector3<f32>,
+    uv: &mut [f32; 2],
+    surfaces: F,
+) -> bool
+where
+    F: FnOnce(&Ray, &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])),
+{
+    let mut closest_root = f32::MAX;
+    surfaces(ray, &mut |root, hit_normal, hit_uv| {
+        if root < closest_root {
+            closest_root = root;
+            *normal = hit_normal;
+            *uv = hit_uv;
+        }
+    });
+
+    *t_value = closest_root;
+    closest_root < f32::MAX
+}
+
+fn cube_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
     let roots = aabb_collision(
         ray,
         &Vector3::new(0.0, 0.0, 0.0),
         &Vector3::new(1.0, 1.0, 1.0),
     );
 
-    *t_value = match roots {
-        Roots::Two([t1, _]) => t1,
-        Roots::One([t1]) => t1,
-        _ => return false,
-    };
-
-    let collision_point = ray.src + (*t_value * ray.dir);
-    // decide which side the point is on
-    if close(collision_point.x, 0.0) {
-        *normal = Vector3::new(-1.0, 0.0, 0.0);
-    } else if close(collision_point.x, 1.0) {
-        *normal = Vector3::new(1.0, 0.0, 0.0);
-    } else if close(collision_point.y, 0.0) {
-        *normal = Vector3::new(0.0, -1.0, 0.0);
-    } else if close(collision_point.y, 1.0) {
-        *normal = Vector3::new(0.0, 1.0, 0.0);
-    } else if close(collision_point.z, 0.0) {
-        *normal = Vector3::new(0.0, 0.0, -1.0);
-    } else {
-        *normal = Vector3::new(0.0, 0.0, 1.0);
+    for &root in roots.as_ref() {
+        let collision_point = ray.src + (root * ray.dir);
+        // decide which side the point is on
+        let normal = if close(collision_point.x, 0.0) {
+            Vector3::new(-1.0, 0.0, 0.0)
+        } else if close(collision_point.x, 1.0) {
+            Vector3::new(1.0, 0.0, 0.0)
+        } else if close(collision_point.y, 0.0) {
+            Vector3::new(0.0, -1.0, 0.0)
+        } else if close(collision_point.y, 1.0) {
+            Vector3::new(0.0, 1.0, 0.0)
+        } else if close(collision_point.z, 0.0) {
+            Vector3::new(0.0, 0.0, -1.0)
+        } else {
+            Vector3::new(0.0, 0.0, 1.0)
+        };
+        hit(root, normal, [0.0, 0.0]);
     }
-
-    true
 }
 
-fn sphere_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+fn sphere_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
     // Check if circle collides with unit sphere
     let l = &ray.src.coords;
     let udir: Unit<Vector3<f32>> = ray.unit_dir();
     let dir = udir.as_ref();
-    let a = dot(dir, dir);
-    let b = 2.0f32 * dot(l, dir);
-    let c = dot(l, l) - 1.0f32;
-
-    let closest_root = match find_roots_quadratic(a, b, c) {
-        Roots::One([r1]) => r1,
-        Roots::Two([r1, _]) => r1,
-        _ => return false,
+    let a = dir.dot(dir);
+    let b = 2.0f32 * l.dot(dir);
+    let c = l.dot(l) - 1.0f32;
+
+    // Rays starting inside the sphere (refracted rays) only cross the far root
+    for &root in find_roots_quadratic(a, b, c).as_ref() {
+        if root > SPHERE_EPS {
+            hit(root, (ray.src + (root * ray.dir)).coords, [0.0, 0.0]);
+        }
+    }
+}
+
+// Where the ray crosses the xz plane, along with the point it crosses at
+fn xz_plane_hit(ray: &Ray) -> Option<(f32, Point3<f32>)> {
+    if ray.dir.y == 0.0 {
+        return None;
+    }
+    let root = -ray.src.y / ray.dir.y;
+    if root <= PLANE_EPS {
+        return None;
+    }
+    Some((root, ray.src + (root * ray.dir)))
+}
+
+// Textures repeat across the plane, covering the same area as they do on a rectangle
+fn plane_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
+    if let Some((root, point)) = xz_plane_hit(ray) {
+        hit(root, Vector3::new(0.0, 1.0, 0.0), disc_uv(&point));
+    }
+}
+
+fn disc_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
+    if let Some((root, point)) = xz_plane_hit(ray) {
+        if (point.x * point.x) + (point.z * point.z) <= 1.0 {
+            hit(root, Vector3::new(0.0, 1.0, 0.0), disc_uv(&point));
+        }
+    }
+}
+
+fn rectangle_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
+    if let Some((root, point)) = xz_plane_hit(ray) {
+        if point.x.abs() <= 1.0 && point.z.abs() <= 1.0 {
+            hit(root, Vector3::new(0.0, 1.0, 0.0), disc_uv(&point));
+        }
+    }
+}
+
+// A torus around the y axis with a ring of radius 1. Points on its surface satisfy
+// (|p|^2 + 1 - r^2)^2 = 4 (x^2 + z^2), which makes a quartic in t along the ray.
+fn torus_surfaces(ray: &Ray, radius: f32, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
+    let udir = ray.unit_dir();
+    let dir = udir.as_ref().map(f64::from);
+    let mut src = ray.src.coords.map(f64::from);
+    let r2 = f64::from(radius) * f64::from(radius);
+
+    // The quartic loses precision far from the torus, so start the ray next to it
+    let bound = 1.0 + f64::from(radius);
+    let shift = (-src.dot(&dir) - bound).max(0.0);
+    src += dir * shift;
+
+    let m = src.dot(&src);
+    let n = src.dot(&dir);
+    let k = m + 1.0 - r2;
+    let a2 = (dir.x * dir.x) + (dir.z * dir.z);
+    let a1 = (src.x * dir.x) + (src.z * dir.z);
+    let a0 = (src.x * src.x) + (src.z * src.z);
+    let coefficients = [
+        1.0,
+        4.0 * n,
+        (4.0 * n * n) + (2.0 * k) - (4.0 * a2),
+        (4.0 * n * k) - (8.0 * a1),
+        (k * k) - (4.0 * a0),
+    ];
+    let f = |t: f64| {
+        coefficients
+            .iter()
+            .fold(0.0, |total, coefficient| (total * t) + coefficient)
+    };
+    let df = |t: f64| {
+        (4.0 * t * t * t)
+            + (3.0 * coefficients[1] * t * t)
+            + (2.0 * coefficients[2] * t)
+            + coefficients[3]
+    };
+
+    let roots = find_roots_quartic(
+        coefficients[0],
+        coefficients[1],
+        coefficients[2],
+        coefficients[3],
+        coefficients[4],
+    );
+    for &root in roots.as_ref() {
+        // Polish the root, the closed form solution is rough near grazing hits
+        let mut t = root;
+        for _ in 0..2 {
+            let slope = df(t);
+            if slope != 0.0 {
+                t -= f(t) / slope;
+            }
+        }
+        let t_value = t + shift;
+        if t_value <= TORUS_EPS {
+            continue;
+        }
+
+        let point = ray.src + ((t_value as f32) * udir.as_ref());
+        let ring = Vector3::new(point.x, 0.0, point.z);
+        let ring_distance = ring.norm();
+        // Gradient of the surface, without the constant factor
+        let sum = point.coords.dot(&point.coords) + 1.0 - (radius * radius);
+        let normal = (point.coords * sum) - (ring * 2.0);
+        // u goes around the ring and v goes around the tube
+        let u = point.x.atan2(point.z) / (2.0 * f32::consts::PI) + 0.5;
+        let v = point.y.atan2(ring_distance - 1.0) / (2.0 * f32::consts::PI) + 0.5;
+        hit(t_value as f32, normal, [u, v]);
+    }
+}
+
+// A cylinder of the given radius from y = 0 to y = height, with a hemisphere on each end.
+// v goes from the bottom of the capsule to the top.
+fn capsule_surfaces(
+    ray: &Ray,
+    radius: f32,
+    height: f32,
+    hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2]),
+) {
+    let src = &ray.src;
+    let dir = ray.unit_dir();
+    let dir = dir.as_ref();
+    let r2 = radius * radius;
+    let uv = |point: &Point3<f32>| {
+        let u = point.x.atan2(point.z) / (2.0 * f32::consts::PI) + 0.5;
+        [u, (point.y + radius) / (height + (2.0 * radius))]
     };
 
-    if closest_root > SPHERE_EPS {
-        *t_value = closest_root;
-        *normal = (ray.src + (closest_root * ray.dir)).coords;
-        true
-    } else {
-        false
+    // Side of the capsule, rays parallel to the axis never hit it
+    let a = (dir.x * dir.x) + (dir.z * dir.z);
+    if a > 0.0 {
+        let b = 2.0f32 * ((src.x * dir.x) + (src.z * dir.z));
+        let c = (src.x * src.x) + (src.z * src.z) - r2;
+        for &root in find_roots_quadratic(a, b, c).as_ref() {
+            let point = src + (root * dir);
+            if root > CAPSULE_EPS && point.y >= 0.0 && point.y <= height {
+                hit(root, Vector3::new(point.x, 0.0, point.z), uv(&point));
+            }
+        }
+    }
+
+    // The bottom and top hemispheres
+    for &(center_y, side) in [(0.0f32, -1.0f32), (height, 1.0)].iter() {
+        let l = src.coords - Vector3::new(0.0, center_y, 0.0);
+        let b = 2.0f32 * l.dot(dir);
+        let c = l.dot(&l) - r2;
+        for &root in find_roots_quadratic(1.0, b, c).as_ref() {
+            let point = src + (root * dir);
+            if root > CAPSULE_EPS && (point.y - center_y) * side > 0.0 {
+                hit(
+                    root,
+                    point.coords - Vector3::new(0.0, center_y, 0.0),
+                    uv(&point),
+                );
+            }
+        }
     }
 }
 
+// Ray/triangle test, which also gives the barycentric coordinates of the hit: the weight
+// of each corner of the triangle at that point
 fn triangle_collides(
     ray: &Ray,
     triangle: &[Vector3<f32>; 3],
     t_value: &mut f32,
     normal: &mut Vector3<f32>,
+    barycentric: &mut [f32; 3],
 ) -> bool {
     let edge1 = triangle[1] - triangle[0];
     let edge2 = triangle[2] - triangle[0];
@@ -114,7 +419,8 @@ fn triangle_collides(
     let q = ray.dir.cross(&edge2);
     let a = edge1.dot(&q);
 
-    if (a.abs() <= TRIANGLE_EPS) || face_normal.dot(&ray.dir) >= 0.0 {
+    // Triangles are two sided, so rays can leave closed meshes
+    if a.abs() <= TRIANGLE_EPS {
         return false;
     }
 
@@ -136,10 +442,24 @@ fn triangle_collides(
     }
 
     *normal = face_normal;
+    *barycentric = [z, x, y];
     true
 }
 
-fn cone_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> bool {
+// Texture coordinates around the y axis, u goes once around and v goes up the side
+fn cylindrical_uv(point: &Point3<f32>) -> [f32; 2] {
+    let u = point.x.atan2(point.z) / (2.0 * f32::consts::PI) + 0.5;
+    [u, point.y]
+}
+
+// Texture coordinates for a flat disc of radius 1 in the xz plane, mapping the disc
+// into the unit square
+fn disc_uv(point: &Point3<f32>) -> [f32; 2] {
+    [(point.x + 1.0) / 2.0, (point.z + 1.0) / 2.0]
+}
+
+// A cone with its tip at the origin, opening up the y axis to a base of radius 1 at y = 1
+fn cone_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
     let src = &ray.src;
     let dir = &ray.dir;
 
@@ -147,42 +467,34 @@ fn cone_collides(ray: &Ray, t_value: &mut f32, normal: &mut Vector3<f32>) -> boo
     let b = 2.0f32 * ((src.x * dir.x) + (src.z * dir.z) - (src.y * dir.y));
     let c = (src.x * src.x) + (src.z * src.z) - (src.y * src.y);
 
-    let closest_root = match find_roots_quadratic(a, b, c) {
-        Roots::One([r1]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
-            }
+    // The equation describes a double cone, so only keep hits on the upper half
+    for &root in find_roots_quadratic(a, b, c).as_ref() {
+        if root <= CONE_EPS {
+            continue;
         }
-        Roots::Two([r1, _r2]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
-            }
+        let point = ray.src + (root * ray.dir);
+        if point.y >= 0.0 && point.y <= 1.0 {
+            // Gradient of x^2 + z^2 - y^2
+            hit(
+                root,
+                Vector3::new(point.x, -point.y, point.z),
+                cylindrical_uv(&point),
+            );
         }
-        _ => return false,
-    };
+    }
 
-    if closest_root > CONE_EPS {
-        let _intersection_point = ray.src + (closest_root * ray.dir);
-        *t_value = closest_root;
-        *normal = Vector3::new(0.0f32, 0.0f32, 1.0f32);
-        true
-    } else {
-        false
+    // Base of the cone
+    if dir.y != 0.0 {
+        let root = (1.0 - src.y) / dir.y;
+        let point = ray.src + (root * ray.dir);
+        if root > CONE_EPS && (point.x * point.x) + (point.z * point.z) <= 1.0 {
+            hit(root, Vector3::new(0.0, 1.0, 0.0), disc_uv(&point));
+        }
     }
 }
 
-fn cylinder_collides(
-    ray: &Ray,
-    t_value: &mut f32,
-    normal: &mut Vector3<f32>,
-    uv: &mut [f32; 2],
-) -> bool {
+// A cylinder of radius 1 around the y axis, closed by caps at y = 0 and y = 1
+fn cylinder_surfaces(ray: &Ray, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
     let src = &ray.src;
     let dir = &ray.dir;
 
@@ -190,109 +502,800 @@ fn cylinder_collides(
     let b = 2.0f32 * ((src.x * dir.x) + (src.z * dir.z));
     let c = (src.x * src.x) + (src.z * src.z) - 1.0f32;
 
-    let mut intercept_cap = false;
-    // closest cap
-    let mut cap_normal = Vector3::new(0.0, 1.0, 0.0);
-    let closest_root = match find_roots_quadratic(a, b, c) {
-        Roots::One([r1]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 1.0 {
-                r1
-            } else {
-                return false;
+    // Side of the cylinder, rays parallel to the axis never hit it
+    if a > 0.0 {
+        for &root in find_roots_quadratic(a, b, c).as_ref() {
+            if root <= CYLINDER_EPS {
+                continue;
+            }
+            let point = ray.src + (root * ray.dir);
+            if point.y >= 0.0 && point.y <= 1.0 {
+                hit(
+                    root,
+                    Vector3::new(point.x, 0.0f32, point.z),
+                    cylindrical_uv(&point),
+                );
             }
         }
-        Roots::Two([r1, r2]) => {
-            let i_1 = ray.src + (r1 * ray.dir);
-            let i_2 = ray.src + (r2 * ray.dir);
-            // Although r1 <= r2, no guarantees about y1 and y2
-            let y1 = i_1.y;
-            let y2 = i_2.y;
-
-            if (y1 < 0.0 && y2 < 0.0) || (y1 > 1.0 && y2 > 1.0) {
-                // Pass over or under the cylinder
-                return false;
-            } else if y1 >= 0.0 && y1 <= 1.0 {
-                // First intercept hits the cylinder
-                r1
-            } else if y1 < 0.0 {
-                // Hit the bottom cap
-                cap_normal = Vector3::new(0.0, -1.0, 0.0);
-                intercept_cap = true;
-                -src.y / dir.y
-            } else if y1 > 1.0 {
-                // Hit the top cap
-                intercept_cap = true;
-                (1.0 - src.y) / dir.y
-            } else {
-                // Hit both caps
-                // TODO
-                return false;
+    }
+
+    // Bottom and top caps
+    if dir.y != 0.0 {
+        for &(cap_y, cap_normal) in [(0.0f32, -1.0f32), (1.0, 1.0)].iter() {
+            let root = (cap_y - src.y) / dir.y;
+            if root <= CYLINDER_EPS {
+                continue;
+            }
+            let point = ray.src + (root * ray.dir);
+            if (point.x * point.x) + (point.z * point.z) <= 1.0 {
+                hit(root, Vector3::new(0.0, cap_normal, 0.0), disc_uv(&point));
             }
         }
-        _ => return false,
+    }
+}
+
+// Walk the cells of the heightfield under the ray in order, only testing the triangles of
+// cells the ray passes low enough over. The first cell with a hit has the closest one.
+fn heightfield_surfaces(
+    ray: &Ray,
+    heightfield: &Heightfield,
+    hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2]),
+) {
+    let top = (heightfield.max_height * heightfield.size.y).max(FLAT_BOUNDS);
+    let (t_enter, t_exit) = match aabb_collision(
+        ray,
+        &Vector3::new(0.0, 0.0, 0.0),
+        &Vector3::new(heightfield.size.x, top, heightfield.size.z),
+    ) {
+        Roots::Two([near, far]) => (near, far),
+        Roots::One([far]) => (0.0, far),
+        _ => return,
     };
 
-    if closest_root > CYLINDER_EPS {
-        let intersection_point = ray.src + (closest_root * ray.dir);
-        *t_value = closest_root;
-        if intercept_cap {
-            uv[0] = 0.0f32;
-            uv[1] = 0.0f32;
-            *normal = cap_normal;
-        } else {
-            *normal = Vector3::new(intersection_point.x, 0.0f32, intersection_point.z);
-            let u = normal.x.atan2(normal.z) / f32::consts::PI + 2.0; //atan2(n.x, n.z) / (2*pi) + 0.5;
-            let v = intersection_point.y; //atan2(n.x, n.z) / (2*pi) + 0.5;
-                                          // println!("u: {}, v: {}", u, v);
-            (*uv)[0] = u;
-            (*uv)[1] = v;
+    let (cell_x, cell_z) = heightfield.cell_size();
+    let cells = [heightfield.width - 1, heightfield.depth - 1];
+    let cell_size = [cell_x, cell_z];
+    let src = [ray.src.x, ray.src.z];
+    let dir = [ray.dir.x, ray.dir.z];
+    let entry = ray.src + (t_enter * ray.dir);
+    let entry = [entry.x, entry.z];
+
+    // Cell the ray starts in, and the t values where it crosses into the next cell
+    let mut cell = [0usize; 2];
+    let mut step = [0isize; 2];
+    let mut t_next = [f32::MAX; 2];
+    let mut t_delta = [f32::MAX; 2];
+    for i in 0..2 {
+        cell[i] = ((entry[i] / cell_size[i]).floor().max(0.0) as usize).min(cells[i] - 1);
+        if dir[i] > 0.0 {
+            step[i] = 1;
+            t_next[i] = (((cell[i] + 1) as f32 * cell_size[i]) - src[i]) / dir[i];
+            t_delta[i] = cell_size[i] / dir[i];
+        } else if dir[i] < 0.0 {
+            step[i] = -1;
+            t_next[i] = ((cell[i] as f32 * cell_size[i]) - src[i]) / dir[i];
+            t_delta[i] = -cell_size[i] / dir[i];
         }
-        true
-    } else {
-        false
+    }
+
+    let mut t = t_enter;
+    loop {
+        let t_leave = t_next[0].min(t_next[1]).min(t_exit);
+        let (x, z) = (cell[0], cell[1]);
+        let corners = [(x, z), (x + 1, z), (x + 1, z + 1), (x, z + 1)];
+
+        // Skip cells the ray passes over entirely
+        let lowest_ray = (ray.src.y + (t * ray.dir.y)).min(ray.src.y + (t_leave * ray.dir.y));
+        let highest_ground = corners
+            .iter()
+            .map(|&(x, z)| heightfield.height(x, z))
+            .fold(0.0, f32::max)
+            * heightfield.size.y;
+        if lowest_ray <= highest_ground {
+            let mut closest_t = f32::MAX;
+            let mut closest_normal = Vector3::zeros();
+            for triangle in [
+                [corners[0], corners[2], corners[1]],
+                [corners[0], corners[3], corners[2]],
+            ]
+            .iter()
+            {
+                let vertices = [
+                    heightfield.vertex(triangle[0].0, triangle[0].1),
+                    heightfield.vertex(triangle[1].0, triangle[1].1),
+                    heightfield.vertex(triangle[2].0, triangle[2].1),
+                ];
+                let mut face_t = 0.0f32;
+                let mut face_normal = Vector3::zeros();
+                let mut barycentric = [0.0f32; 3];
+                if triangle_collides(
+                    ray,
+                    &vertices,
+                    &mut face_t,
+                    &mut face_normal,
+                    &mut barycentric,
+                ) && face_t < closest_t
+                {
+                    closest_t = face_t;
+                    // Smooth shading, blend the normals at the corners of the triangle
+                    closest_normal = (0..3).fold(Vector3::zeros(), |total, i| {
+                        total + (heightfield.normal(triangle[i].0, triangle[i].1) * barycentric[i])
+                    });
+                }
+            }
+
+            if closest_t < f32::MAX {
+                let point = ray.src + (closest_t * ray.dir);
+                hit(closest_t, closest_normal, heightfield.uv(&point));
+                return;
+            }
+        }
+
+        if t_leave >= t_exit {
+            return;
+        }
+        let axis = if t_next[0] < t_next[1] { 0 } else { 1 };
+        let next = cell[axis] as isize + step[axis];
+        if next < 0 || next >= cells[axis] as isize {
+            return;
+        }
+        cell[axis] = next as usize;
+        t = t_next[axis];
+        t_next[axis] += t_delta[axis];
+    }
+}
+
+// Fill in the texture coordinates, vertex color and smooth normal of a triangle hit
+fn mesh_crossing(
+    ray: &Ray,
+    mesh: &Mesh,
+    face: usize,
+    t_value: f32,
+    face_normal: Vector3<f32>,
+    barycentric: &[f32; 3],
+) -> Crossing {
+    let intersect = ray.src + (t_value * ray.dir);
+    let mut normal = face_normal;
+
+    // Smooth shading, blend the normals at the corners of the face
+    if let Some(indexes) = mesh.face_normals[face] {
+        let blended = (mesh.normals[indexes[0]] * barycentric[0])
+            + (mesh.normals[indexes[1]] * barycentric[1])
+            + (mesh.normals[indexes[2]] * barycentric[2]);
+        if let Some(blended) = blended.try_normalize(f32::EPSILON) {
+            normal = blended;
+        }
+    }
+
+    Crossing {
+        t_value,
+        normal,
+        uv: mesh.uv_at(face, barycentric, &intersect.coords, &face_normal),
+        face: Some(face),
+        color: mesh.color_at(face, barycentric),
     }
 }
 
+fn mesh_triangle(mesh: &Mesh, face_index: usize) -> [Vector3<f32>; 3] {
+    let face = &mesh.faces[face_index];
+    [
+        mesh.vertices[face[0]],
+        mesh.vertices[face[1]],
+        mesh.vertices[face[2]],
+    ]
+}
+
 fn mesh_collides(
     ray: &Ray,
     mesh: &Mesh,
     t_value: &mut f32,
     normal: &mut Vector3<f32>,
     uv: &mut [f32; 2],
+    face: &mut Option<usize>,
+    color: &mut Option<Vector3<f32>>,
 ) -> bool {
-    if aabb_collision(ray, &mesh.aabb_corner, &mesh.aabb_size) == Roots::No([]) {
-        return false;
-    }
-
     let mut smallest_t = f32::MAX;
     let mut smallest_normal = Vector3::new(0.0f32, 0.0f32, 0.0f32);
-    let mut triangle = [smallest_normal, smallest_normal, smallest_normal];
+    let mut smallest_face = None;
+    let mut smallest_barycentric = [0.0f32; 3];
 
-    for face in mesh.faces.iter() {
-        triangle[0] = mesh.vertices[face[0]];
-        triangle[1] = mesh.vertices[face[1]];
-        triangle[2] = mesh.vertices[face[2]];
+    mesh.bvh.traverse(ray, f32::MAX, |face_index, closest_t| {
+        let mut face_t = 0.0f32;
+        let mut face_normal = smallest_normal;
+        let mut barycentric = [0.0f32; 3];
+        if triangle_collides(
+            ray,
+            &mesh_triangle(mesh, face_index),
+            &mut face_t,
+            &mut face_normal,
+            &mut barycentric,
+        ) && face_t < closest_t
+        {
+            smallest_t = face_t;
+            smallest_normal = face_normal;
+            smallest_face = Some(face_index);
+            smallest_barycentric = barycentric;
+            Some(face_t)
+        } else {
+            None
+        }
+    });
 
-        if triangle_collides(ray, &triangle, t_value, normal) {
-            if *t_value < smallest_t {
-                smallest_t = *t_value;
-                smallest_normal = *normal;
-            }
+    match smallest_face {
+        Some(face_index) => {
+            let crossing = mesh_crossing(
+                ray,
+                mesh,
+                face_index,
+                smallest_t,
+                smallest_normal,
+                &smallest_barycentric,
+            );
+            *t_value = crossing.t_value;
+            *normal = crossing.normal;
+            *uv = crossing.uv;
+            *face = crossing.face;
+            *color = crossing.color;
+            true
+        }
+        None => false,
+    }
+}
+
+// Every triangle along the ray. Only closed meshes have a well defined inside, open
+// ones will give odd results in csg.
+fn mesh_crossings(ray: &Ray, mesh: &Mesh, crossings: &mut Vec<Crossing>) {
+    mesh.bvh.traverse(ray, f32::MAX, |face_index, _| {
+        let mut face_t = 0.0f32;
+        let mut face_normal = Vector3::zeros();
+        let mut barycentric = [0.0f32; 3];
+        if triangle_collides(
+            ray,
+            &mesh_triangle(mesh, face_index),
+            &mut face_t,
+            &mut face_normal,
+            &mut barycentric,
+        ) {
+            crossings.push(mesh_crossing(
+                ray,
+                mesh,
+                face_index,
+                face_t,
+                face_normal,
+                &barycentric,
+            ));
+        }
+        // Never narrow the search, every hit is needed
+        None
+    });
+    crossings.sort_by(|a, b| a.t_value.partial_cmp(&b.t_value).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::testing::{brute_force, random_ray, seeded_rng, triangle_soup};
+    use image::{GrayImage, Luma};
+    use rand::rngs::StdRng;
+    use rand::Rng;
+
+    fn crossing_ts(primitive: &Primitive, src: Point3<f32>) -> Vec<f32> {
+        let ray = Ray::new(src, Vector3::x());
+        primitive
+            .crossings(&ray)
+            .iter()
+            .map(|crossing| crossing.t_value)
+            .collect()
+    }
+
+    fn assert_close(actual: Vec<f32>, expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-3, "{:?} != {:?}", actual, expected);
         }
     }
 
-    if smallest_t < f32::MAX {
-        let intersect = ray.src + (smallest_t * ray.dir);
-        if intersect.x < 0.0 {
-            uv[0] = 1.0 - intersect.x;
+    // The closest hit of a ray from src along dir, with its t value, unit normal and uv
+    fn surface_hit(
+        primitive: &Primitive,
+        src: Point3<f32>,
+        dir: Vector3<f32>,
+    ) -> Option<(f32, Vector3<f32>, [f32; 2])> {
+        let (mut t, mut normal, mut uv) = (0.0, Vector3::zeros(), [0.0; 2]);
+        let (mut face, mut color) = (None, None);
+        let ray = Ray::new(src, dir);
+        if primitive.collides(&ray, &mut t, &mut normal, &mut uv, &mut face, &mut color) {
+            Some((t, normal.normalize(), uv))
         } else {
-            uv[0] = intersect.x;
+            None
+        }
+    }
+
+    fn assert_near(actual: Vector3<f32>, expected: Vector3<f32>) {
+        assert!(
+            (actual - expected).norm() < 1e-3,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_uv(actual: [f32; 2], expected: [f32; 2]) {
+        assert_close(actual.to_vec(), &expected);
+    }
+
+    #[test]
+    fn closed_primitives_are_crossed_twice_from_outside() {
+        let outside = Point3::new(-3.0, 0.5, 0.5);
+        assert_close(crossing_ts(&Primitive::Cube, outside), &[3.0, 4.0]);
+        let outside = Point3::new(-3.0, 0.0, 0.0);
+        assert_close(crossing_ts(&Primitive::Sphere, outside), &[2.0, 4.0]);
+    }
+
+    #[test]
+    fn closed_primitives_are_crossed_once_from_inside() {
+        let inside = Point3::new(0.25, 0.5, 0.5);
+        assert_close(crossing_ts(&Primitive::Cube, inside), &[0.75]);
+        assert_close(crossing_ts(&Primitive::Sphere, Point3::origin()), &[1.0]);
+    }
+
+    #[test]
+    fn flat_primitives_have_no_crossings() {
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), -Vector3::y());
+        assert!(Primitive::Plane.crossings(&ray).is_empty());
+        assert!(Primitive::Rectangle.crossings(&ray).is_empty());
+    }
+
+    #[test]
+    fn meshes_find_the_closest_triangle_along_a_ray() {
+        let mut rng = seeded_rng(3);
+        let mesh = triangle_soup(&mut rng, 300, 5.0, 1.0);
+
+        let mut hits = 0;
+        for _ in 0..1000 {
+            let ray = random_ray(&mut rng, 10.0, 5.0);
+            let expected = brute_force(mesh.faces.len(), |i| {
+                let (mut t, mut normal, mut barycentric) = (0.0, Vector3::zeros(), [0.0; 3]);
+                let triangle = mesh_triangle(&mesh, i);
+                if triangle_collides(&ray, &triangle, &mut t, &mut normal, &mut barycentric) {
+                    Some(t)
+                } else {
+                    None
+                }
+            });
+
+            let (mut t, mut normal, mut uv) = (0.0, Vector3::zeros(), [0.0; 2]);
+            let (mut face, mut color) = (None, None);
+            let hit = mesh_collides(
+                &ray,
+                &mesh,
+                &mut t,
+                &mut normal,
+                &mut uv,
+                &mut face,
+                &mut color,
+            );
+            assert_eq!(hit, expected.is_some());
+            if let Some((expected_t, expected_face)) = expected {
+                assert_eq!(face, Some(expected_face));
+                assert!((t - expected_t).abs() < 1e-4, "{} != {}", t, expected_t);
+                hits += 1;
+            }
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn discs_and_rectangles_are_hit_from_either_side_inside_their_edges() {
+        let down = -Vector3::y();
+        for primitive in [Primitive::Disc, Primitive::Rectangle].iter() {
+            let (t, normal, uv) =
+                surface_hit(primitive, Point3::new(0.5, 2.0, -0.5), down).unwrap();
+            assert_close(vec![t], &[2.0]);
+            assert_near(normal, Vector3::y());
+            assert_uv(uv, [0.75, 0.25]);
+
+            let (t, _, uv) =
+                surface_hit(primitive, Point3::new(0.5, -1.0, -0.5), Vector3::y()).unwrap();
+            assert_close(vec![t], &[1.0]);
+            assert_uv(uv, [0.75, 0.25]);
+
+            // Rays along the plane and rays pointing away from it never reach it
+            assert!(surface_hit(primitive, Point3::new(-2.0, 0.0, 0.0), Vector3::x()).is_none());
+            assert!(surface_hit(primitive, Point3::new(0.0, 1.0, 0.0), Vector3::y()).is_none());
+        }
+
+        // Past the edge of the disc but inside the corner of the rectangle
+        let corner = Point3::new(0.8, 1.0, 0.8);
+        assert!(surface_hit(&Primitive::Disc, corner, down).is_none());
+        assert!(surface_hit(&Primitive::Rectangle, corner, down).is_some());
+        // Past the edge of both
+        let outside = Point3::new(1.01, 1.0, 0.0);
+        assert!(surface_hit(&Primitive::Disc, outside, down).is_none());
+        assert!(surface_hit(&Primitive::Rectangle, outside, down).is_none());
+    }
+
+    #[test]
+    fn rectangle_uvs_cover_the_unit_square() {
+        let down = -Vector3::y();
+        let uv_at =
+            |x: f32, z: f32| surface_hit(&Primitive::Rectangle, Point3::new(x, 1.0, z), down);
+        assert_uv(uv_at(-1.0, -1.0).unwrap().2, [0.0, 0.0]);
+        assert_uv(uv_at(1.0, -1.0).unwrap().2, [1.0, 0.0]);
+        assert_uv(uv_at(1.0, 1.0).unwrap().2, [1.0, 1.0]);
+        assert_uv(uv_at(0.0, 0.0).unwrap().2, [0.5, 0.5]);
+
+        let mut rng = seeded_rng(4);
+        for _ in 0..200 {
+            let ray = random_ray(&mut rng, 3.0, 1.0);
+            if let Some((_, _, uv)) = surface_hit(&Primitive::Rectangle, ray.src, ray.dir) {
+                assert!(uv.iter().all(|c| (0.0..=1.0).contains(c)), "{:?}", uv);
+            }
         }
-        uv[1] = intersect.z;
     }
 
-    *normal = smallest_normal;
-    *t_value = smallest_t;
-    smallest_t < f32::MAX
+    #[test]
+    fn planes_are_hit_anywhere_but_parallel() {
+        let far = Point3::new(1000.0, 5.0, -300.0);
+        let (t, normal, _) = surface_hit(&Primitive::Plane, far, -Vector3::y()).unwrap();
+        assert_close(vec![t], &[5.0]);
+        assert_near(normal, Vector3::y());
+        assert!(surface_hit(&Primitive::Plane, far, Vector3::x()).is_none());
+    }
+
+    #[test]
+    fn tori_are_hit_through_both_sides_of_the_ring() {
+        let torus = Primitive::Torus { radius: 0.25 };
+        // Across the ring, through the tube on both sides of the hole
+        assert_close(
+            crossing_ts(&torus, Point3::new(-5.0, 0.0, 0.0)),
+            &[3.75, 4.25, 5.75, 6.25],
+        );
+        let (_, normal, uv) =
+            surface_hit(&torus, Point3::new(-5.0, 0.0, 0.0), Vector3::x()).unwrap();
+        assert_near(normal, -Vector3::x());
+        // A quarter of the way around the ring, on the outside of the tube
+        assert_uv(uv, [0.25, 0.5]);
+
+        // Down through the top of the tube
+        let (t, normal, uv) =
+            surface_hit(&torus, Point3::new(0.0, 5.0, 1.0), -Vector3::y()).unwrap();
+        assert_close(vec![t], &[4.75]);
+        assert_near(normal, Vector3::y());
+        assert_uv(uv, [0.5, 0.75]);
+
+        // Down the axis through the hole, and just over the top of the tube
+        assert!(surface_hit(&torus, Point3::new(0.0, 5.0, 0.0), -Vector3::y()).is_none());
+        assert!(surface_hit(&torus, Point3::new(-5.0, 0.26, 0.0), Vector3::x()).is_none());
+    }
+
+    #[test]
+    fn tori_are_hit_precisely_from_far_away() {
+        let torus = Primitive::Torus { radius: 0.25 };
+        let (t, _, _) = surface_hit(&torus, Point3::new(0.0, 0.0, -1000.0), Vector3::z()).unwrap();
+        assert!((t - 998.75).abs() < 1e-3, "{}", t);
+        // Grazing the top of the tube from far away, which only clips it within 0.12 of x = 0
+        let (t, normal, _) =
+            surface_hit(&torus, Point3::new(-1000.0, 0.2499, -1.0), Vector3::x()).unwrap();
+        assert!((t - 999.881).abs() < 2e-3, "{}", t);
+        assert!(normal.y > 0.99, "{}", normal);
+
+        // A steep ray clipping the top of the tube, where the closed form roots alone are off
+        // the surface by almost a thousandth
+        let ray = Ray::new(
+            Point3::new(0.91667, 1.93639, 0.58971),
+            Vector3::new(0.026812, -0.998211, -0.053443),
+        );
+        let crossings = torus.crossings(&ray);
+        assert_eq!(crossings.len(), 2);
+        for crossing in crossings.iter() {
+            let point = ray.src + (crossing.t_value * ray.dir);
+            let ring = Vector3::new(point.x, 0.0, point.z).normalize();
+            let error = ((point.coords - ring).norm() - 0.25).abs();
+            assert!(error < 1e-5, "{} is {} off the torus", point, error);
+        }
+    }
+
+    #[test]
+    fn torus_hits_are_on_the_tube_with_normals_out_of_it() {
+        let radius = 0.4;
+        let torus = Primitive::Torus { radius };
+        let mut rng = seeded_rng(5);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let ray = random_ray(&mut rng, 4.0, 1.4);
+            let (t, normal, uv) = match surface_hit(&torus, ray.src, ray.dir) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            let point = ray.src + (t * ray.dir);
+            let ring = Vector3::new(point.x, 0.0, point.z).normalize();
+            let from_ring = point.coords - ring;
+            assert!(
+                (from_ring.norm() - radius).abs() < 1e-3,
+                "{} is off the torus",
+                point
+            );
+            assert_near(normal, from_ring.normalize());
+            assert!(uv.iter().all(|c| (0.0..=1.0).contains(c)), "{:?}", uv);
+            hits += 1;
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn capsules_are_hit_on_the_body_and_both_caps() {
+        let capsule = Primitive::Capsule {
+            radius: 0.5,
+            height: 2.0,
+        };
+        // The cylinder in the middle, half way up
+        let (t, normal, uv) =
+            surface_hit(&capsule, Point3::new(-5.0, 1.0, 0.0), Vector3::x()).unwrap();
+        assert_close(vec![t], &[4.5]);
+        assert_near(normal, -Vector3::x());
+        assert_uv(uv, [0.25, 0.5]);
+        assert_close(
+            crossing_ts(&capsule, Point3::new(-5.0, 1.0, 0.0)),
+            &[4.5, 5.5],
+        );
+
+        // The tips of the top and bottom caps are at the ends of the texture
+        let (t, normal, uv) =
+            surface_hit(&capsule, Point3::new(0.0, 5.0, 0.0), -Vector3::y()).unwrap();
+        assert_close(vec![t], &[2.5]);
+        assert_near(normal, Vector3::y());
+        assert_close(vec![uv[1]], &[1.0]);
+        let (t, normal, uv) =
+            surface_hit(&capsule, Point3::new(0.0, -5.0, 0.0), Vector3::y()).unwrap();
+        assert_close(vec![t], &[4.5]);
+        assert_near(normal, -Vector3::y());
+        assert_close(vec![uv[1]], &[0.0]);
+
+        // Off center on the top cap the normal leans out from the cap's center
+        let (t, normal, _) =
+            surface_hit(&capsule, Point3::new(0.3, 5.0, 0.0), -Vector3::y()).unwrap();
+        assert_close(vec![t], &[2.6]);
+        assert_near(normal, Vector3::new(0.6, 0.8, 0.0));
+        // Beyond the side of the capsule
+        assert!(surface_hit(&capsule, Point3::new(0.6, 5.0, 0.0), -Vector3::y()).is_none());
+    }
+
+    #[test]
+    fn rays_down_a_cylinder_cross_both_caps() {
+        let src = Point3::new(0.5, 5.0, -0.5);
+        let ray = Ray::new(src, -Vector3::y());
+        let ts: Vec<f32> = Primitive::Cylinder
+            .crossings(&ray)
+            .iter()
+            .map(|crossing| crossing.t_value)
+            .collect();
+        assert_close(ts, &[4.0, 5.0]);
+
+        let (t, normal, uv) = surface_hit(&Primitive::Cylinder, src, -Vector3::y()).unwrap();
+        assert_close(vec![t], &[4.0]);
+        assert_near(normal, Vector3::y());
+        assert_uv(uv, [0.75, 0.25]);
+        let below = Point3::new(0.5, -1.0, -0.5);
+        let (t, normal, uv) = surface_hit(&Primitive::Cylinder, below, Vector3::y()).unwrap();
+        assert_close(vec![t], &[1.0]);
+        assert_near(normal, -Vector3::y());
+        assert_uv(uv, [0.75, 0.25]);
+    }
+
+    #[test]
+    fn cylinder_sides_are_hit_past_a_root_outside_the_height() {
+        // The ray meets the side above the top first, so it enters through the cap and
+        // leaves through the far side
+        let src = Point3::new(-2.0, 1.8, 0.0);
+        let dir = Vector3::new(2.0, -1.0, 0.0);
+        let ray = Ray::new(src, dir);
+        let crossings = Primitive::Cylinder.crossings(&ray);
+        let ts: Vec<f32> = crossings.iter().map(|c| c.t_value).collect();
+        let along = 5.0f32.sqrt() / 2.0;
+        assert_close(ts, &[1.6 * along, 3.0 * along]);
+        assert_near(crossings[0].normal, Vector3::y());
+        assert_near(crossings[1].normal, Vector3::x());
+
+        // From inside only the far root is ahead of the ray
+        let inside = Point3::new(0.0, 0.5, 0.0);
+        let (t, normal, _) = surface_hit(&Primitive::Cylinder, inside, Vector3::x()).unwrap();
+        assert_close(vec![t], &[1.0]);
+        assert_near(normal, Vector3::x());
+    }
+
+    #[test]
+    fn cylinder_sides_wrap_the_texture_once() {
+        let (_, normal, uv) = surface_hit(
+            &Primitive::Cylinder,
+            Point3::new(-3.0, 0.25, 0.0),
+            Vector3::x(),
+        )
+        .unwrap();
+        assert_near(normal, -Vector3::x());
+        assert_uv(uv, [0.25, 0.25]);
+        let (_, _, uv) = surface_hit(
+            &Primitive::Cylinder,
+            Point3::new(3.0, 0.75, 0.0),
+            -Vector3::x(),
+        )
+        .unwrap();
+        assert_uv(uv, [0.75, 0.75]);
+
+        let mut rng = seeded_rng(6);
+        for _ in 0..200 {
+            let ray = random_ray(&mut rng, 3.0, 1.0);
+            if let Some((_, _, uv)) = surface_hit(&Primitive::Cylinder, ray.src, ray.dir) {
+                assert!(uv.iter().all(|c| (0.0..=1.0).contains(c)), "{:?}", uv);
+            }
+        }
+    }
+
+    #[test]
+    fn cone_sides_have_analytic_normals_and_cylindrical_uvs() {
+        let src = Point3::new(-3.0, 0.5, 0.0);
+        let (t, normal, uv) = surface_hit(&Primitive::Cone, src, Vector3::x()).unwrap();
+        assert_close(vec![t], &[2.5]);
+        // The side leans out at 45 degrees, so its normal points down as much as out
+        assert_near(normal, Vector3::new(-1.0, -1.0, 0.0).normalize());
+        assert_uv(uv, [0.25, 0.5]);
+
+        let mut rng = seeded_rng(7);
+        for _ in 0..200 {
+            let ray = random_ray(&mut rng, 3.0, 1.0);
+            if let Some((_, _, uv)) = surface_hit(&Primitive::Cone, ray.src, ray.dir) {
+                assert!(uv.iter().all(|c| (0.0..=1.0).contains(c)), "{:?}", uv);
+            }
+        }
+    }
+
+    #[test]
+    fn cones_skip_roots_on_the_mirrored_half() {
+        // Going up, the ray first meets the lower half of the double cone at y = -0.5
+        let src = Point3::new(0.5, -3.0, 0.0);
+        let (t, normal, _) = surface_hit(&Primitive::Cone, src, Vector3::y()).unwrap();
+        assert_close(vec![t], &[3.5]);
+        assert_near(normal, Vector3::new(1.0, -1.0, 0.0).normalize());
+        // Above the base both roots are out of the height range
+        assert!(surface_hit(&Primitive::Cone, Point3::new(-3.0, 1.5, 0.0), Vector3::x()).is_none());
+    }
+
+    #[test]
+    fn cone_bases_are_hit_from_below() {
+        let ts = |src: Point3<f32>| {
+            Primitive::Cone
+                .crossings(&Ray::new(src, Vector3::y()))
+                .iter()
+                .map(|crossing| crossing.t_value)
+                .collect::<Vec<f32>>()
+        };
+        // In through the side and out through the base
+        assert_close(ts(Point3::new(0.5, -3.0, 0.0)), &[3.5, 4.0]);
+
+        let inside = Point3::new(0.2, 0.5, -0.2);
+        assert_close(ts(inside), &[0.5]);
+        let (_, normal, uv) = surface_hit(&Primitive::Cone, inside, Vector3::y()).unwrap();
+        assert_near(normal, Vector3::y());
+        assert_uv(uv, [0.6, 0.4]);
+    }
+
+    // A jagged heightfield of random samples, with cells of different sizes along x and z
+    fn random_heightfield(rng: &mut StdRng) -> Heightfield {
+        let image = GrayImage::from_fn(7, 5, |_, _| Luma([rng.gen::<u8>()]));
+        Heightfield::from_image(&image, Vector3::new(6.0, 2.0, 3.0))
+    }
+
+    // The closest hit on any triangle of the heightfield, with the smooth normal blended
+    // at that point
+    fn heightfield_brute_force(
+        heightfield: &Heightfield,
+        ray: &Ray,
+    ) -> Option<(f32, Vector3<f32>)> {
+        let cells_x = heightfield.width - 1;
+        let triangle = |i: usize| {
+            let (x, z) = ((i / 2) % cells_x, (i / 2) / cells_x);
+            let corners = [(x, z), (x + 1, z), (x + 1, z + 1), (x, z + 1)];
+            [
+                [corners[0], corners[2], corners[1]],
+                [corners[0], corners[3], corners[2]],
+            ][i % 2]
+        };
+        let collide = |i: usize| {
+            let corners = triangle(i);
+            let vertices = [
+                heightfield.vertex(corners[0].0, corners[0].1),
+                heightfield.vertex(corners[1].0, corners[1].1),
+                heightfield.vertex(corners[2].0, corners[2].1),
+            ];
+            let (mut t, mut normal, mut barycentric) = (0.0, Vector3::zeros(), [0.0; 3]);
+            if triangle_collides(ray, &vertices, &mut t, &mut normal, &mut barycentric) {
+                Some((t, barycentric))
+            } else {
+                None
+            }
+        };
+
+        let count = 2 * cells_x * (heightfield.depth - 1);
+        let (t, i) = brute_force(count, |i| collide(i).map(|(t, _)| t))?;
+        let (_, barycentric) = collide(i).unwrap();
+        let corners = triangle(i);
+        let normal = (0..3).fold(Vector3::zeros(), |total, j| {
+            total + (heightfield.normal(corners[j].0, corners[j].1) * barycentric[j])
+        });
+        Some((t, normal.normalize()))
+    }
+
+    // Compare walking the cells with testing every triangle for rays from the points given
+    // by `src` towards points within the bounds. Gives the number of rays that hit.
+    fn compare_heightfield_rays<F>(heightfield: &Heightfield, rng: &mut StdRng, mut src: F) -> usize
+    where
+        F: FnMut(&mut StdRng) -> Point3<f32>,
+    {
+        let size = heightfield.size;
+        let primitive = Primitive::Heightfield(heightfield.clone());
+        let mut hits = 0;
+        for _ in 0..500 {
+            let src = src(rng);
+            let towards = Point3::new(
+                rng.gen_range(0.0, size.x),
+                rng.gen_range(0.0, size.y),
+                rng.gen_range(0.0, size.z),
+            );
+            let dir = (towards - src).normalize();
+            let expected = heightfield_brute_force(heightfield, &Ray::new(src, dir));
+            let actual = surface_hit(&primitive, src, dir);
+            assert_eq!(actual.is_some(), expected.is_some(), "{} {}", src, dir);
+            if let (Some((t, normal, uv)), Some((expected_t, expected_normal))) = (actual, expected)
+            {
+                assert!((t - expected_t).abs() < 1e-4, "{} != {}", t, expected_t);
+                assert_near(normal, expected_normal);
+                let point = src + (t * dir);
+                assert_uv(uv, [point.x / size.x, point.z / size.z]);
+                assert!(uv.iter().all(|uv| (-1e-4..=1.0 + 1e-4).contains(uv)));
+                hits += 1;
+            }
+        }
+        hits
+    }
+
+    #[test]
+    fn heightfields_find_the_closest_triangle_along_a_ray() {
+        let mut rng = seeded_rng(11);
+        let heightfield = random_heightfield(&mut rng);
+        let size = heightfield.size;
+
+        // From anywhere around, including inside the bounds
+        let hits = compare_heightfield_rays(&heightfield, &mut rng, |rng| {
+            Point3::new(
+                rng.gen_range(-4.0, size.x + 4.0),
+                rng.gen_range(-1.0, size.y + 4.0),
+                rng.gen_range(-4.0, size.z + 4.0),
+            )
+        });
+        assert!(hits > 200, "{}", hits);
+
+        // Down through the top
+        let hits = compare_heightfield_rays(&heightfield, &mut rng, |rng| {
+            Point3::new(
+                rng.gen_range(0.0, size.x),
+                size.y + rng.gen_range(0.5, 3.0),
+                rng.gen_range(0.0, size.z),
+            )
+        });
+        assert!(hits > 400, "{}", hits);
+
+        // Low across the sides, through the -x, +x, -z and +z faces of the bounds
+        let mut side = 0;
+        let hits = compare_heightfield_rays(&heightfield, &mut rng, |rng| {
+            side += 1;
+            let (y, along) = (rng.gen_range(0.0, size.y), rng.gen_range(0.0, 1.0));
+            match side % 4 {
+                0 => Point3::new(-2.0, y, along * size.z),
+                1 => Point3::new(size.x + 2.0, y, along * size.z),
+                2 => Point3::new(along * size.x, y, -2.0),
+                _ => Point3::new(along * size.x, y, size.z + 2.0),
+            }
+        });
+        assert!(hits > 200, "{}", hits);
+    }
 }
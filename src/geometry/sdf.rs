@@ -0,0 +1,515 @@
+use crate::geometry::{aabb_collision, Aabb, Ray};
+use nalgebra::{Point3, Vector3};
+use roots::Roots;
+use std::f32;
+
+// Distance from the surface that counts as a hit
+const SDF_EPS: f32 = 0.0001;
+// Offset used for the central differences of the gradient
+const GRADIENT_EPS: f32 = 0.0005;
+const MAX_STEPS: usize = 512;
+// Steps used to find the surface when a march overshoots it
+const BISECT_STEPS: usize = 24;
+// How far rays march through distance fields without finite bounds
+const MAX_DISTANCE: f32 = 1000.0;
+// Space left around the bounds of a shape when tracing it
+const BOUNDS_MARGIN: f32 = 0.001;
+
+// A solid described by the distance from any point to its surface, negative inside
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    // A box centered on the origin, from -half_size to half_size
+    Box {
+        half_size: Vector3<f32>,
+    },
+    // A ring around the y axis
+    Torus {
+        ring_radius: f32,
+        tube_radius: f32,
+    },
+    // Blends two shapes together, joining them with a fillet of about the given size
+    SmoothUnion {
+        a: Box<Sdf>,
+        b: Box<Sdf>,
+        smoothness: f32,
+    },
+    // Copies of the shape every period along each axis, a period of 0 doesn't repeat
+    Repeat {
+        shape: Box<Sdf>,
+        period: Vector3<f32>,
+    },
+    Translate {
+        shape: Box<Sdf>,
+        offset: Vector3<f32>,
+    },
+    // Distances sampled over a box, like from a function defined in lua
+    Grid(SdfGrid),
+}
+
+// Distances sampled at the corners of a regular grid of cells, which are interpolated
+// between the samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct SdfGrid {
+    pub bounds: Aabb,
+    // Number of samples along each axis
+    pub resolution: usize,
+    // Samples in x, then y, then z order
+    pub values: Vec<f32>,
+}
+
+impl SdfGrid {
+    // Sample `distance` at resolution^3 points spread over bounds
+    pub fn sample<F>(bounds: Aabb, resolution: usize, mut distance: F) -> SdfGrid
+    where
+        F: FnMut(Point3<f32>) -> f32,
+    {
+        let resolution = resolution.max(2);
+        let mut values = Vec::with_capacity(resolution * resolution * resolution);
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    values.push(distance(SdfGrid::sample_point(
+                        &bounds, resolution, x, y, z,
+                    )));
+                }
+            }
+        }
+        SdfGrid {
+            bounds,
+            resolution,
+            values,
+        }
+    }
+
+    fn sample_point(bounds: &Aabb, resolution: usize, x: usize, y: usize, z: usize) -> Point3<f32> {
+        let step = bounds.extent() / (resolution - 1) as f32;
+        bounds.min + Vector3::new(x as f32 * step.x, y as f32 * step.y, z as f32 * step.z)
+    }
+
+    fn value(&self, x: usize, y: usize, z: usize) -> f32 {
+        self.values[x + (self.resolution * (y + (self.resolution * z)))]
+    }
+
+    fn distance(&self, point: &Point3<f32>) -> f32 {
+        let last = (self.resolution - 1) as f32;
+        let clamped = Point3::new(
+            point.x.clamp(self.bounds.min.x, self.bounds.max.x),
+            point.y.clamp(self.bounds.min.y, self.bounds.max.y),
+            point.z.clamp(self.bounds.min.z, self.bounds.max.z),
+        );
+        let extent = self.bounds.extent();
+        let cell = (clamped - self.bounds.min).component_div(&extent) * last;
+
+        // Trilinear interpolation between the 8 samples around the point
+        let mut corner = [0usize; 3];
+        let mut weight = [0.0f32; 3];
+        for i in 0..3 {
+            let base = cell[i].floor().clamp(0.0, last - 1.0);
+            corner[i] = base as usize;
+            weight[i] = cell[i] - base;
+        }
+        let mut inside = 0.0;
+        for k in 0..8 {
+            let offset = [k & 1, (k >> 1) & 1, (k >> 2) & 1];
+            let mut w = 1.0;
+            for i in 0..3 {
+                w *= if offset[i] == 1 {
+                    weight[i]
+                } else {
+                    1.0 - weight[i]
+                };
+            }
+            inside += w * self.value(
+                corner[0] + offset[0],
+                corner[1] + offset[1],
+                corner[2] + offset[2],
+            );
+        }
+
+        // Outside of the grid, add the distance to the grid so rays still approach it
+        inside + (point - clamped).norm()
+    }
+}
+
+impl Sdf {
+    pub fn distance(&self, point: &Point3<f32>) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => point.coords.norm() - radius,
+            Sdf::Box { half_size } => {
+                let q = point.coords.abs() - half_size;
+                q.map(|v| v.max(0.0)).norm() + q.x.max(q.y).max(q.z).min(0.0)
+            }
+            Sdf::Torus {
+                ring_radius,
+                tube_radius,
+            } => {
+                let ring = ((point.x * point.x) + (point.z * point.z)).sqrt() - ring_radius;
+                ((ring * ring) + (point.y * point.y)).sqrt() - tube_radius
+            }
+            Sdf::SmoothUnion { a, b, smoothness } => {
+                let a = a.distance(point);
+                let b = b.distance(point);
+                if *smoothness <= 0.0 {
+                    return a.min(b);
+                }
+                // Polynomial smooth minimum
+                let h = (0.5 + (0.5 * (b - a) / smoothness)).clamp(0.0, 1.0);
+                (b * (1.0 - h)) + (a * h) - (smoothness * h * (1.0 - h))
+            }
+            Sdf::Repeat { shape, period } => {
+                let mut local = *point;
+                for i in 0..3 {
+                    if period[i] > 0.0 {
+                        local[i] -= period[i] * (local[i] / period[i]).round();
+                    }
+                }
+                shape.distance(&local)
+            }
+            Sdf::Translate { shape, offset } => shape.distance(&(point - offset)),
+            Sdf::Grid(grid) => grid.distance(point),
+        }
+    }
+
+    // Bounds of the surface, or None if it repeats forever
+    pub fn bounds(&self) -> Option<Aabb> {
+        let centered =
+            |half: Vector3<f32>| Some(Aabb::new(Point3::from(-half), Point3::from(half)));
+        match self {
+            Sdf::Sphere { radius } => centered(Vector3::new(*radius, *radius, *radius)),
+            Sdf::Box { half_size } => centered(*half_size),
+            Sdf::Torus {
+                ring_radius,
+                tube_radius,
+            } => {
+                let outer = ring_radius + tube_radius;
+                centered(Vector3::new(outer, *tube_radius, outer))
+            }
+            Sdf::SmoothUnion { a, b, smoothness } => {
+                let bounds = a.bounds()?.union(&b.bounds()?);
+                let grow = Vector3::new(*smoothness, *smoothness, *smoothness);
+                Some(Aabb::new(bounds.min - grow, bounds.max + grow))
+            }
+            Sdf::Repeat { .. } => None,
+            Sdf::Translate { shape, offset } => {
+                let bounds = shape.bounds()?;
+                Some(Aabb::new(bounds.min + offset, bounds.max + offset))
+            }
+            Sdf::Grid(grid) => Some(grid.bounds),
+        }
+    }
+
+    // Offset for the central differences of the gradient. Sampled grids are only smooth
+    // at the scale of their cells, so they use a wider offset.
+    fn gradient_step(&self) -> f32 {
+        match self {
+            Sdf::SmoothUnion { a, b, .. } => a.gradient_step().max(b.gradient_step()),
+            Sdf::Repeat { shape, .. } | Sdf::Translate { shape, .. } => shape.gradient_step(),
+            Sdf::Grid(grid) => {
+                let cell = grid.bounds.extent() / (grid.resolution - 1) as f32;
+                (cell.x.min(cell.y).min(cell.z) * 0.5).max(GRADIENT_EPS)
+            }
+            _ => GRADIENT_EPS,
+        }
+    }
+
+    // The surface normal, from the gradient of the distance
+    pub fn normal(&self, point: &Point3<f32>) -> Vector3<f32> {
+        let step = self.gradient_step();
+        let mut gradient = Vector3::zeros();
+        for i in 0..3 {
+            let mut offset = Vector3::zeros();
+            offset[i] = step;
+            gradient[i] = self.distance(&(point + offset)) - self.distance(&(point - offset));
+        }
+        gradient
+    }
+
+    // Find where the distance changes sign between t values on either side of the surface
+    fn bisect<F>(&self, at: F, side: f32, mut outside: f32, mut inside: f32) -> f32
+    where
+        F: Fn(f32) -> Point3<f32>,
+    {
+        for _ in 0..BISECT_STEPS {
+            let middle = (outside + inside) * 0.5;
+            if self.distance(&at(middle)) * side > 0.0 {
+                outside = middle;
+            } else {
+                inside = middle;
+            }
+        }
+        (outside + inside) * 0.5
+    }
+
+    // Sphere trace along the ray, reporting where it crosses the surface. Only the first
+    // crossing is found unless `all` is set.
+    pub fn trace(&self, ray: &Ray, all: bool, hit: &mut dyn FnMut(f32, Vector3<f32>, [f32; 2])) {
+        // The bounds are grown a little, otherwise shapes whose surface lies on their bounds
+        // (like a box) are entered right on the surface and taken for one being left
+        let margin = Vector3::repeat(BOUNDS_MARGIN);
+        let (mut t, t_far) = match self.bounds() {
+            Some(bounds) => match aabb_collision(
+                ray,
+                &(bounds.min.coords - margin),
+                &(bounds.extent() + (margin * 2.0)),
+            ) {
+                Roots::Two([near, far]) => (near, far),
+                Roots::One([far]) => (0.0, far),
+                _ => return,
+            },
+            None => (0.0, MAX_DISTANCE),
+        };
+
+        let at = |t: f32| ray.src + (t * ray.dir);
+        let mut distance = self.distance(&at(t));
+        let mut steps = 0;
+        // Rays leaving a surface start right on it, so step away before looking for hits
+        while distance.abs() < SDF_EPS * 2.0 && steps < MAX_STEPS && t < t_far {
+            t += SDF_EPS * 2.0;
+            distance = self.distance(&at(t));
+            steps += 1;
+        }
+
+        // Rays that start inside march towards where the distance becomes positive
+        let mut side = distance.signum();
+        let mut last_t = t;
+        while steps < MAX_STEPS && t < t_far {
+            let step = distance * side;
+            if step < SDF_EPS {
+                // Distances that aren't exact can step right past the surface, so search
+                // back for where it was crossed
+                if step < -SDF_EPS {
+                    t = self.bisect(at, side, last_t, t);
+                }
+                hit(t, self.normal(&at(t)), [0.0, 0.0]);
+                if !all {
+                    return;
+                }
+                // Carry on from the other side of the surface
+                side = -side;
+                t += SDF_EPS * 2.0;
+                while (self.distance(&at(t)) * side) < SDF_EPS && steps < MAX_STEPS {
+                    t += SDF_EPS * 2.0;
+                    steps += 1;
+                }
+                last_t = t;
+            } else {
+                last_t = t;
+                t += step;
+            }
+            distance = self.distance(&at(t));
+            steps += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::testing::{random_ray, seeded_rng};
+
+    // Every crossing of a ray from src along dir, with the unit normal at each
+    fn crossings(sdf: &Sdf, src: Point3<f32>, dir: Vector3<f32>) -> Vec<(f32, Vector3<f32>)> {
+        let mut crossings = vec![];
+        sdf.trace(&Ray::new(src, dir), true, &mut |t, normal, _| {
+            crossings.push((t, normal.normalize()))
+        });
+        crossings
+    }
+
+    fn first_hit(sdf: &Sdf, ray: &Ray) -> Option<f32> {
+        let mut first = None;
+        sdf.trace(ray, false, &mut |t, _, _| first = Some(t));
+        first
+    }
+
+    fn assert_ts(actual: Vec<(f32, Vector3<f32>)>, expected: &[f32], tolerance: f32) {
+        let ts: Vec<f32> = actual.iter().map(|c| c.0).collect();
+        assert_eq!(ts.len(), expected.len(), "{:?}", ts);
+        for (t, e) in ts.iter().zip(expected.iter()) {
+            assert!((t - e).abs() < tolerance, "{:?} != {:?}", ts, expected);
+        }
+    }
+
+    fn assert_on_surface(sdf: &Sdf, ray: &Ray, t: f32, tolerance: f32) {
+        let point = ray.src + (t * ray.dir);
+        let distance = sdf.distance(&point);
+        assert!(
+            distance.abs() < tolerance,
+            "{} is {} off the surface",
+            point,
+            distance
+        );
+    }
+
+    // Rays that only just touch a sphere around the origin can be hit or missed
+    fn assert_grazes(ray: &Ray, radius: f32, tolerance: f32) {
+        let closest = ray.src.coords - (ray.dir * ray.src.coords.dot(&ray.dir));
+        let gap = closest.norm() - radius;
+        assert!(
+            gap.abs() < tolerance,
+            "{:?} passes {} from the sphere",
+            ray,
+            gap
+        );
+    }
+
+    // Where a ray first meets a sphere around the origin, solved exactly
+    fn sphere_hit(ray: &Ray, radius: f32) -> Option<f32> {
+        let b = ray.src.coords.dot(&ray.dir);
+        let c = ray.src.coords.norm_squared() - (radius * radius);
+        let discriminant = (b * b) - c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        [-b - root, -b + root].iter().cloned().find(|&t| t > 0.0)
+    }
+
+    #[test]
+    fn tracing_finds_the_analytic_surfaces() {
+        let sphere = Sdf::Sphere { radius: 1.5 };
+        let along = (2.25f32 - 0.09).sqrt();
+        let hits = crossings(&sphere, Point3::new(-5.0, 0.3, 0.0), Vector3::x());
+        assert_ts(hits, &[5.0 - along, 5.0 + along], 1e-3);
+
+        let cube = Sdf::Box {
+            half_size: Vector3::new(1.0, 0.5, 2.0),
+        };
+        let hits = crossings(&cube, Point3::new(-5.0, 0.2, 0.3), Vector3::x());
+        assert_ts(hits.clone(), &[4.0, 6.0], 1e-3);
+        assert!((hits[0].1 + Vector3::x()).norm() < 1e-3, "{}", hits[0].1);
+        let hits = crossings(&cube, Point3::new(0.0, 5.0, 0.0), -Vector3::y());
+        assert_ts(hits, &[4.5, 5.5], 1e-3);
+
+        let torus = Sdf::Torus {
+            ring_radius: 1.0,
+            tube_radius: 0.25,
+        };
+        let hits = crossings(&torus, Point3::new(-5.0, 0.0, 0.0), Vector3::x());
+        assert_ts(hits, &[3.75, 4.25, 5.75, 6.25], 1e-3);
+        // Down through the hole of the ring
+        assert!(crossings(&torus, Point3::new(0.0, 5.0, 0.0), -Vector3::y()).is_empty());
+
+        let mut rng = seeded_rng(10);
+        let mut hits = 0;
+        for _ in 0..300 {
+            let ray = random_ray(&mut rng, 4.0, 1.5);
+            match (first_hit(&sphere, &ray), sphere_hit(&ray, 1.5)) {
+                // Marching stops just short of the surface, which is further along the ray
+                // the closer it comes to grazing
+                (Some(t), Some(expected)) => {
+                    assert_on_surface(&sphere, &ray, t, 1e-3);
+                    assert!((t - expected).abs() < 0.05, "{} != {}", t, expected);
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => assert_grazes(&ray, 1.5, 1e-3),
+            }
+        }
+        assert!(hits > 100);
+    }
+
+    #[test]
+    fn normals_follow_the_gradient_of_the_distance() {
+        let sphere = Sdf::Sphere { radius: 1.5 };
+        let mut rng = seeded_rng(11);
+        for _ in 0..100 {
+            let direction = random_ray(&mut rng, 1.0, 1.0).dir;
+            let normal = sphere.normal(&Point3::from(direction * 1.5)).normalize();
+            assert!(
+                (normal - direction).norm() < 1e-3,
+                "{} != {}",
+                normal,
+                direction
+            );
+        }
+    }
+
+    #[test]
+    fn overshooting_steps_are_bisected_back_to_the_surface() {
+        // Twice the distance to a unit sphere, so every step goes twice as far as is safe
+        let bounds = Aabb::new(Point3::new(-2.0, -2.0, -2.0), Point3::new(2.0, 2.0, 2.0));
+        let overshooting = Sdf::Grid(SdfGrid::sample(bounds, 64, |p| {
+            2.0 * (p.coords.norm() - 1.0)
+        }));
+        let mut rng = seeded_rng(12);
+        let mut hits = 0;
+        for _ in 0..200 {
+            let ray = random_ray(&mut rng, 1.9, 0.8);
+            if ray.src.coords.norm() < 1.1 {
+                continue;
+            }
+            let t = match first_hit(&overshooting, &ray) {
+                Some(t) => t,
+                None => continue,
+            };
+            let point = ray.src + (t * ray.dir);
+            assert!(
+                (point.coords.norm() - 1.0).abs() < 2e-3,
+                "{} is off the sphere",
+                point
+            );
+            hits += 1;
+        }
+        assert!(hits > 100);
+
+        // Bisection on its own narrows down to the surface from either side
+        let ray = Ray::new(Point3::new(-3.0, 0.0, 0.0), Vector3::x());
+        let at = |t: f32| ray.src + (t * ray.dir);
+        let sphere = Sdf::Sphere { radius: 1.0 };
+        assert!((sphere.bisect(at, 1.0, 0.5, 2.5) - 2.0).abs() < 1e-5);
+        assert!((sphere.bisect(at, -1.0, 3.5, 4.5) - 4.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn repeated_shapes_are_hit_on_every_copy() {
+        let repeated = Sdf::Repeat {
+            shape: Box::new(Sdf::Sphere { radius: 0.5 }),
+            period: Vector3::new(3.0, 0.0, 0.0),
+        };
+        assert!(repeated.bounds().is_none());
+        for &x in [-6.0f32, 0.0, 3.0, 30.0].iter() {
+            let hits = crossings(&repeated, Point3::new(x, 5.0, 0.0), -Vector3::y());
+            assert_ts(hits, &[4.5, 5.5], 1e-3);
+        }
+        // Along the axis the ray goes through one copy after another
+        let hits = crossings(&repeated, Point3::new(1.5, 0.0, 0.0), Vector3::x());
+        assert_ts(hits[..4].to_vec(), &[1.0, 2.0, 4.0, 5.0], 1e-3);
+        // Between the copies there is nothing, and y doesn't repeat
+        assert!(crossings(&repeated, Point3::new(1.5, 5.0, 0.0), -Vector3::y()).is_empty());
+        assert!(crossings(&repeated, Point3::new(-5.0, 3.0, 0.0), Vector3::x()).is_empty());
+    }
+
+    #[test]
+    fn sampled_functions_match_the_shape_they_sample() {
+        // The same sphere as a built in shape and as a function, like one defined in lua
+        let sphere = Sdf::Sphere { radius: 1.0 };
+        let resolution = 32;
+        let bounds = Aabb::new(Point3::new(-1.5, -1.5, -1.5), Point3::new(1.5, 1.5, 1.5));
+        let cell = 3.0 / (resolution - 1) as f32;
+        let sampled = Sdf::Grid(SdfGrid::sample(bounds, resolution, |p| {
+            (p.x * p.x + p.y * p.y + p.z * p.z).sqrt() - 1.0
+        }));
+
+        let mut rng = seeded_rng(13);
+        let mut hits = 0;
+        for _ in 0..300 {
+            let ray = random_ray(&mut rng, 4.0, 1.2);
+            let point = random_ray(&mut rng, 1.5, 1.5).src;
+            assert!((sampled.distance(&point) - sphere.distance(&point)).abs() < cell);
+            match (first_hit(&sampled, &ray), first_hit(&sphere, &ray)) {
+                (Some(t), Some(expected)) => {
+                    assert_on_surface(&sphere, &ray, t, cell);
+                    assert!((t - expected).abs() < cell, "{} != {}", t, expected);
+                    hits += 1;
+                }
+                (None, None) => {}
+                _ => assert_grazes(&ray, 1.0, cell),
+            }
+        }
+        assert!(hits > 100);
+    }
+}
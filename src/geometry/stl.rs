@@ -0,0 +1,179 @@
+use crate::geometry::{Mesh, MeshError};
+use nalgebra::Vector3;
+use std::collections::HashMap;
+use std::io::Read;
+
+// Size of the header and triangle count at the start of a binary file
+const BINARY_HEADER: usize = 84;
+// Normal, three corners and an attribute count for every triangle in a binary file
+const BINARY_TRIANGLE: usize = 50;
+
+fn parse_error(line: usize, message: String) -> MeshError {
+    MeshError::Parse { line, message }
+}
+
+// Triangles in stl files don't share their corners, so identical corners are merged back
+// into a single vertex. This lets smooth normals be generated across the triangles.
+#[derive(Debug, Default)]
+struct VertexWelder {
+    vertices: Vec<Vector3<f32>>,
+    indexes: HashMap<[u32; 3], usize>,
+}
+
+impl VertexWelder {
+    fn add(&mut self, vertex: Vector3<f32>) -> usize {
+        let vertices = &mut self.vertices;
+        *self
+            .indexes
+            .entry([vertex.x.to_bits(), vertex.y.to_bits(), vertex.z.to_bits()])
+            .or_insert_with(|| {
+                vertices.push(vertex);
+                vertices.len() - 1
+            })
+    }
+}
+
+fn read_vector(bytes: &[u8]) -> Vector3<f32> {
+    let value = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    Vector3::new(value(0), value(4), value(8))
+}
+
+fn parse_binary(data: &[u8]) -> Mesh {
+    let mut welder = VertexWelder::default();
+    let mut faces = vec![];
+    // The normal stored with each triangle is ignored, plenty of exporters leave it empty
+    for triangle in data[BINARY_HEADER..].chunks_exact(BINARY_TRIANGLE) {
+        faces.push([
+            welder.add(read_vector(&triangle[12..24])),
+            welder.add(read_vector(&triangle[24..36])),
+            welder.add(read_vector(&triangle[36..48])),
+        ]);
+    }
+    Mesh::new(welder.vertices, faces)
+}
+
+fn parse_ascii(text: &str) -> Result<Mesh, MeshError> {
+    let mut welder = VertexWelder::default();
+    let mut faces = vec![];
+    let mut corners = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.first() {
+            Some(&"vertex") => {
+                let values = parts[1..]
+                    .iter()
+                    .map(|part| {
+                        part.parse::<f32>().map_err(|_| {
+                            parse_error(line_number, format!("invalid vertex value \'{}\'", part))
+                        })
+                    })
+                    .collect::<Result<Vec<f32>, MeshError>>()?;
+                if values.len() < 3 {
+                    return Err(parse_error(
+                        line_number,
+                        format!("vertex needs 3 values but has {}", values.len()),
+                    ));
+                }
+                corners.push(welder.add(Vector3::new(values[0], values[1], values[2])));
+            }
+            Some(&"endloop") => {
+                if corners.len() < 3 {
+                    return Err(parse_error(
+                        line_number,
+                        format!("facet needs at least 3 vertices but has {}", corners.len()),
+                    ));
+                }
+                for k in 1..corners.len() - 1 {
+                    faces.push([corners[0], corners[k], corners[k + 1]]);
+                }
+                corners.clear();
+            }
+            // solid, facet normal, outer loop, endfacet and endsolid carry nothing we need
+            _ => {}
+        }
+    }
+    Ok(Mesh::new(welder.vertices, faces))
+}
+
+// Parse an stl file, in either ascii or binary. Binary files can start with "solid" as
+// well, so a file is only treated as binary when its size matches the triangle count.
+pub fn parse<R: Read>(mut reader: R) -> Result<Mesh, MeshError> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+
+    if data.len() >= BINARY_HEADER {
+        let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        if data.len() == BINARY_HEADER + (count * BINARY_TRIANGLE) {
+            return Ok(parse_binary(&data));
+        }
+    }
+    if data.starts_with(b"solid") {
+        let text = String::from_utf8_lossy(&data);
+        return parse_ascii(&text);
+    }
+    Err(MeshError::Format(
+        "not an stl file, the size doesn't match the triangle count".into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two triangles of a unit square, sharing the edge from (1, 0, 0) to (0, 1, 0)
+    const TRIANGLES: [[[f32; 3]; 3]; 2] = [
+        [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+        [[1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]],
+    ];
+
+    fn binary(header: &[u8]) -> Vec<u8> {
+        let mut file = header.to_vec();
+        file.resize(80, b' ');
+        file.extend_from_slice(&(TRIANGLES.len() as u32).to_le_bytes());
+        for triangle in TRIANGLES.iter() {
+            file.extend_from_slice(&[0; 12]);
+            for value in triangle.iter().flatten() {
+                file.extend_from_slice(&value.to_le_bytes());
+            }
+            file.extend_from_slice(&[0; 2]);
+        }
+        file
+    }
+
+    fn ascii() -> String {
+        let mut file = String::from("solid square\n");
+        for triangle in TRIANGLES.iter() {
+            file += "facet normal 0 0 1\nouter loop\n";
+            for v in triangle.iter() {
+                file += &format!("vertex {} {} {}\n", v[0], v[1], v[2]);
+            }
+            file += "endloop\nendfacet\n";
+        }
+        file + "endsolid square\n"
+    }
+
+    fn assert_square(mesh: &Mesh) {
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.faces, vec![[0, 1, 2], [1, 3, 2]]);
+        assert_eq!(mesh.vertices[3], Vector3::new(1.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn shared_corners_are_welded() {
+        assert_square(&parse(&binary(b"square")[..]).unwrap());
+        assert_square(&parse(ascii().as_bytes()).unwrap());
+    }
+
+    #[test]
+    fn binary_files_starting_with_solid_are_still_binary() {
+        assert_square(&parse(&binary(b"solid square")[..]).unwrap());
+    }
+
+    #[test]
+    fn files_of_the_wrong_size_are_not_binary() {
+        let mut file = binary(b"square");
+        file.pop();
+        assert!(parse(&file[..]).is_err());
+    }
+}
@@ -0,0 +1,349 @@
+use crate::geometry::{Mesh, MeshGroup};
+use nalgebra::Vector3;
+use std::collections::HashMap;
+
+// A point of the subdivided mesh as a weighted sum of points of the original mesh, so
+// positions and colors are smoothed the same way
+type Stencil = Vec<(usize, f32)>;
+
+struct Edge {
+    faces: Vec<usize>,
+    crease: bool,
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+fn corner(face: &[usize; 3], vertex: usize) -> usize {
+    face.iter().position(|&v| v == vertex).unwrap()
+}
+
+fn blend(values: &[Vector3<f32>], stencil: &[(usize, f32)]) -> Vector3<f32> {
+    stencil
+        .iter()
+        .fold(Vector3::zeros(), |sum, &(i, w)| sum + (values[i] * w))
+}
+
+// The group of every face, so edges between faces of different materials stay sharp
+fn face_groups(groups: &[MeshGroup], face_count: usize) -> Vec<Option<usize>> {
+    let mut face_groups = vec![None; face_count];
+    for (g, group) in groups.iter().enumerate() {
+        for face_group in face_groups[group.start..group.end].iter_mut() {
+            *face_group = Some(g);
+        }
+    }
+    face_groups
+}
+
+// Whether two faces sharing an edge should keep a sharp edge between them. Edges are sharp
+// where the file gave the faces different normals along the edge, where the faces use
+// different materials, or where they bend further than the crease angle (in degrees).
+fn is_crease(
+    mesh: &Mesh,
+    (a, b): (usize, usize),
+    f0: usize,
+    f1: usize,
+    materials: &[Option<usize>],
+    cos_crease: Option<f32>,
+) -> bool {
+    if materials[f0] != materials[f1] {
+        return true;
+    }
+    match (mesh.face_normals[f0], mesh.face_normals[f1]) {
+        (Some(n0), Some(n1)) => {
+            let (face0, face1) = (&mesh.faces[f0], &mesh.faces[f1]);
+            if n0[corner(face0, a)] != n1[corner(face1, a)]
+                || n0[corner(face0, b)] != n1[corner(face1, b)]
+            {
+                return true;
+            }
+        }
+        (None, None) => {}
+        _ => return true,
+    }
+    cos_crease.is_some_and(|cos_crease| {
+        let normal = |f: usize| {
+            let face = mesh.faces[f];
+            (mesh.vertices[face[1]] - mesh.vertices[face[0]])
+                .cross(&(mesh.vertices[face[2]] - mesh.vertices[face[0]]))
+                .try_normalize(f32::EPSILON)
+                .unwrap_or_else(Vector3::zeros)
+        };
+        normal(f0).dot(&normal(f1)) < cos_crease
+    })
+}
+
+// One level of Loop subdivision, splitting every triangle into four and smoothing the
+// vertices. Sharp edges are subdivided as curves of their own, and vertices where more
+// than two of them meet stay where they are. Faces keep their order, so face `f` becomes
+// faces 4f to 4f + 3 and groups of faces still line up. Texture coordinates are split
+// linearly, which keeps seams where they were. Normals and the face hierarchy are dropped,
+// the caller should build them again.
+pub fn subdivide(mesh: &Mesh, crease_angle: Option<f32>) -> Mesh {
+    let cos_crease = crease_angle.map(|angle| angle.to_radians().cos());
+    let materials = face_groups(&mesh.material_groups, mesh.faces.len());
+
+    let mut edges: HashMap<(usize, usize), Edge> = HashMap::new();
+    for (f, face) in mesh.faces.iter().enumerate() {
+        for i in 0..3 {
+            edges
+                .entry(edge_key(face[i], face[(i + 1) % 3]))
+                .or_insert_with(|| Edge {
+                    faces: vec![],
+                    crease: false,
+                })
+                .faces
+                .push(f);
+        }
+    }
+    for (&key, edge) in edges.iter_mut() {
+        // Boundaries, edges shared by more than two faces and edges of degenerate faces
+        // are always sharp
+        edge.crease = match edge.faces[..] {
+            [f0, f1] if key.0 != key.1 => is_crease(mesh, key, f0, f1, &materials, cos_crease),
+            _ => true,
+        };
+    }
+
+    let mut neighbours: Vec<Vec<usize>> = vec![vec![]; mesh.vertices.len()];
+    let mut crease_neighbours: Vec<Vec<usize>> = vec![vec![]; mesh.vertices.len()];
+    for (&(a, b), edge) in edges.iter() {
+        neighbours[a].push(b);
+        neighbours[b].push(a);
+        if edge.crease {
+            crease_neighbours[a].push(b);
+            crease_neighbours[b].push(a);
+        }
+    }
+
+    // The original vertices move towards their neighbours
+    let mut stencils: Vec<Stencil> = (0..mesh.vertices.len())
+        .map(|v| match crease_neighbours[v][..] {
+            [a, b] => vec![(v, 0.75), (a, 0.125), (b, 0.125)],
+            [] | [_] => {
+                let n = neighbours[v].len();
+                if n == 0 {
+                    return vec![(v, 1.0)];
+                }
+                let beta = if n == 3 {
+                    3.0 / 16.0
+                } else {
+                    3.0 / (8.0 * n as f32)
+                };
+                let mut stencil = vec![(v, 1.0 - (n as f32 * beta))];
+                stencil.extend(neighbours[v].iter().map(|&u| (u, beta)));
+                stencil
+            }
+            _ => vec![(v, 1.0)],
+        })
+        .collect();
+
+    // Then a new vertex is added on every edge
+    let mut edge_vertices: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut sorted_edges: Vec<_> = edges.iter().collect();
+    sorted_edges.sort_by_key(|&(&key, _)| key);
+    for (&(a, b), edge) in sorted_edges {
+        let stencil = if edge.crease {
+            vec![(a, 0.5), (b, 0.5)]
+        } else {
+            let opposite = |f: usize| {
+                let face = mesh.faces[f];
+                face[3 - corner(&face, a) - corner(&face, b)]
+            };
+            vec![
+                (a, 0.375),
+                (b, 0.375),
+                (opposite(edge.faces[0]), 0.125),
+                (opposite(edge.faces[1]), 0.125),
+            ]
+        };
+        edge_vertices.insert((a, b), stencils.len());
+        stencils.push(stencil);
+    }
+
+    let mut uvs = mesh.uvs.clone();
+    let mut edge_uvs: HashMap<(usize, usize), usize> = HashMap::new();
+    let mut faces = Vec::with_capacity(mesh.faces.len() * 4);
+    let mut face_uvs = Vec::with_capacity(mesh.faces.len() * 4);
+    for (f, face) in mesh.faces.iter().enumerate() {
+        let [v0, v1, v2] = *face;
+        let e01 = edge_vertices[&edge_key(v0, v1)];
+        let e12 = edge_vertices[&edge_key(v1, v2)];
+        let e20 = edge_vertices[&edge_key(v2, v0)];
+        faces.extend_from_slice(&[
+            [v0, e01, e20],
+            [v1, e12, e01],
+            [v2, e20, e12],
+            [e01, e12, e20],
+        ]);
+
+        match mesh.face_uvs[f] {
+            Some([t0, t1, t2]) => {
+                let mut midpoint = |a: usize, b: usize| {
+                    *edge_uvs.entry(edge_key(a, b)).or_insert_with(|| {
+                        uvs.push([
+                            (mesh.uvs[a][0] + mesh.uvs[b][0]) * 0.5,
+                            (mesh.uvs[a][1] + mesh.uvs[b][1]) * 0.5,
+                        ]);
+                        uvs.len() - 1
+                    })
+                };
+                let (t01, t12, t20) = (midpoint(t0, t1), midpoint(t1, t2), midpoint(t2, t0));
+                face_uvs.extend_from_slice(&[
+                    Some([t0, t01, t20]),
+                    Some([t1, t12, t01]),
+                    Some([t2, t20, t12]),
+                    Some([t01, t12, t20]),
+                ]);
+            }
+            None => face_uvs.extend_from_slice(&[None; 4]),
+        }
+    }
+
+    let split = |groups: &[MeshGroup]| {
+        groups
+            .iter()
+            .map(|group| MeshGroup {
+                name: group.name.clone(),
+                start: group.start * 4,
+                end: group.end * 4,
+            })
+            .collect()
+    };
+
+    let mut subdivided = Mesh::without_bvh(
+        stencils.iter().map(|s| blend(&mesh.vertices, s)).collect(),
+        faces,
+    );
+    subdivided.uvs = uvs;
+    subdivided.face_uvs = face_uvs;
+    subdivided.projection = mesh.projection;
+    if !mesh.colors.is_empty() {
+        subdivided.colors = stencils.iter().map(|s| blend(&mesh.colors, s)).collect();
+    }
+    subdivided.groups = split(&mesh.groups);
+    subdivided.material_groups = split(&mesh.material_groups);
+    subdivided.material_libraries = mesh.material_libraries.clone();
+    subdivided
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tetrahedron() -> Mesh {
+        let vertices = vec![
+            Vector3::new(1.0, 1.0, 1.0),
+            Vector3::new(1.0, -1.0, -1.0),
+            Vector3::new(-1.0, 1.0, -1.0),
+            Vector3::new(-1.0, -1.0, 1.0),
+        ];
+        Mesh::without_bvh(vertices, vec![[0, 1, 2], [0, 3, 1], [0, 2, 3], [1, 3, 2]])
+    }
+
+    // A cube from -1 to 1, each side split into two triangles along a diagonal
+    fn cube() -> Mesh {
+        let vertices = (0..8)
+            .map(|i| {
+                let side = |bit: usize| if i & bit != 0 { 1.0 } else { -1.0 };
+                Vector3::new(side(4), side(2), side(1))
+            })
+            .collect();
+        let sides = [
+            [0, 1, 3, 2],
+            [4, 6, 7, 5],
+            [0, 4, 5, 1],
+            [2, 3, 7, 6],
+            [0, 2, 6, 4],
+            [1, 5, 7, 3],
+        ];
+        let faces = sides
+            .iter()
+            .flat_map(|&[a, b, c, d]| vec![[a, b, c], [a, c, d]])
+            .collect();
+        Mesh::without_bvh(vertices, faces)
+    }
+
+    #[test]
+    fn faces_are_split_into_four_with_a_vertex_on_every_edge() {
+        let mesh = tetrahedron();
+        let once = subdivide(&mesh, None);
+        assert_eq!(once.faces.len(), 16);
+        // Four vertices and six edges
+        assert_eq!(once.vertices.len(), 10);
+
+        // The new mesh has 6 * 2 + 4 * 3 edges
+        let twice = subdivide(&once, None);
+        assert_eq!(twice.faces.len(), 64);
+        assert_eq!(twice.vertices.len(), 10 + 24);
+
+        // A closed mesh is smoothed towards its center, it never grows
+        for vertex in twice.vertices.iter() {
+            assert!(vertex.norm() < 3.0f32.sqrt());
+        }
+    }
+
+    #[test]
+    fn crease_edges_stay_on_their_original_line() {
+        let mut mesh = cube();
+        mesh.subdivide(2, Some(30.0));
+        // Corners where three creases meet don't move, and everything else stays on the
+        // sides of the cube since only the diagonals are smoothed
+        assert_eq!(mesh.vertices[..8], cube().vertices[..]);
+        for vertex in mesh.vertices.iter() {
+            assert_eq!(vertex.amax(), 1.0, "{} is off the cube", vertex);
+        }
+        // Points along the edges of the cube are on two of its sides at once
+        let on_edges = mesh
+            .vertices
+            .iter()
+            .filter(|v| v.iter().filter(|c| c.abs() == 1.0).count() >= 2)
+            .count();
+        // 8 corners and 3 more points on each of the 12 edges
+        assert_eq!(on_edges, 8 + 12 * 3);
+
+        // Without creases the corners are rounded off
+        let mut smooth = cube();
+        smooth.subdivide(1, None);
+        assert!(smooth.vertices[0].amax() < 1.0);
+    }
+
+    #[test]
+    fn texture_coordinates_are_split_linearly() {
+        let mut mesh = Mesh::without_bvh(
+            vec![
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(1.0, 1.0, 0.0),
+                Vector3::new(0.0, 1.0, 0.0),
+            ],
+            vec![[0, 1, 2], [0, 2, 3], [0, 1, 3]],
+        );
+        mesh.uvs = vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+        mesh.face_uvs = vec![Some([0, 1, 2]), Some([0, 2, 3]), None];
+
+        let subdivided = subdivide(&mesh, None);
+        // The four corners and one uv in the middle of each of the five edges with them
+        assert_eq!(subdivided.uvs.len(), 4 + 5);
+        assert_eq!(subdivided.face_uvs[8..], [None; 4]);
+        for (f, face_uvs) in mesh.face_uvs.iter().enumerate() {
+            let face_uvs = match face_uvs {
+                Some(face_uvs) => face_uvs,
+                None => continue,
+            };
+            let uv = |f: usize, k: usize| subdivided.uvs[subdivided.face_uvs[f].unwrap()[k]];
+            let midpoint = |a: usize, b: usize| {
+                let (a, b) = (mesh.uvs[face_uvs[a]], mesh.uvs[face_uvs[b]]);
+                [(a[0] + b[0]) * 0.5, (a[1] + b[1]) * 0.5]
+            };
+            for (k, &corner_uv) in face_uvs.iter().enumerate() {
+                // Each corner face keeps the corner's uv
+                assert_eq!(uv(4 * f + k, 0), mesh.uvs[corner_uv]);
+            }
+            assert_eq!(uv(4 * f + 3, 0), midpoint(0, 1));
+            assert_eq!(uv(4 * f + 3, 1), midpoint(1, 2));
+            assert_eq!(uv(4 * f + 3, 2), midpoint(2, 0));
+        }
+    }
+}
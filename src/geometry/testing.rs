@@ -0,0 +1,52 @@
+use crate::geometry::{Mesh, Ray};
+use nalgebra::Point3;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+// Random geometry for tests comparing the fast paths with testing every item one by one.
+// Everything is drawn from a seeded generator so a failure can be reproduced.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::seed_from_u64(seed)
+}
+
+// A point in the cube from -size to size on every axis
+pub fn random_point(rng: &mut StdRng, size: f32) -> Point3<f32> {
+    Point3::new(
+        rng.gen_range(-size, size),
+        rng.gen_range(-size, size),
+        rng.gen_range(-size, size),
+    )
+}
+
+// A ray from a point within `from` of the origin towards a point within `towards` of it
+pub fn random_ray(rng: &mut StdRng, from: f32, towards: f32) -> Ray {
+    let src = random_point(rng, from);
+    Ray::new_from_points(src, random_point(rng, towards))
+}
+
+// Triangles with corners up to `triangle_size` apart scattered through a cube of `size`
+pub fn triangle_soup(rng: &mut StdRng, count: usize, size: f32, triangle_size: f32) -> Mesh {
+    let mut vertices = vec![];
+    for _ in 0..count {
+        let corner = random_point(rng, size).coords;
+        vertices.push(corner);
+        vertices.push(corner + random_point(rng, triangle_size).coords);
+        vertices.push(corner + random_point(rng, triangle_size).coords);
+    }
+    let faces = (0..count).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+    Mesh::new(vertices, faces)
+}
+
+// The closest of `count` items, found by testing every one of them. Gives the t value of
+// the hit and the item it was on, the first item wins a tie.
+pub fn brute_force<F>(count: usize, mut hit: F) -> Option<(f32, usize)>
+where
+    F: FnMut(usize) -> Option<f32>,
+{
+    (0..count)
+        .filter_map(|i| hit(i).map(|t| (t, i)))
+        .fold(None, |closest, hit| match closest {
+            Some((t, _)) if t <= hit.0 => closest,
+            _ => Some(hit),
+        })
+}
@@ -112,8 +112,7 @@ fn light_apply(
             distance * 0.2
         }
     }
-    .max(0.0)
-    .min(0.7);
+    .clamp(0.0, 0.7);
 
     // if intensity > 0.0 {
     //     return Color::new(1.0, 0.0, 0.0);
@@ -164,8 +163,7 @@ fn fog_apply(
             distance * 0.03
         }
     }
-    .max(0.0)
-    .min(1.0);
+    .clamp(0.0, 1.0);
 
     (fog_amount * fog_color) + ((1.0 - fog_amount) * curr_color)
 }
@@ -228,10 +226,10 @@ fn cone_passes_through(cone: &ConeParams, ray: &Ray) -> Option<VolumeIntersectio
     let c = (src.x * src.x) + (src.z * src.z) - (src.y * src.y);
 
     match find_roots_quadratic(a, b, c) {
-        Roots::One(_) => return None,
+        Roots::One(_) => None,
         Roots::Two([r1, r2]) => {
             let i_1 = transformed_ray.src + (r1 * transformed_ray.dir);
-            if i_1.y >= 0.0 && i_1.y <= 3.0 {
+            if (0.0..=3.0).contains(&i_1.y) {
                 let i_2 = transformed_ray.src + (r2 * transformed_ray.dir);
                 Some(VolumeIntersection::new(
                     r1,
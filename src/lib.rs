@@ -1,8 +1,14 @@
 pub mod geometry;
 pub mod scene;
 
+mod display;
+mod framebuffer;
 mod raytrace;
-pub use crate::raytrace::Raytracer;
+mod sampling;
+pub use crate::display::{DisplayTransform, ToneMapping};
+pub use crate::framebuffer::Framebuffer;
+pub use crate::raytrace::{Backend, Integrator, Raytracer, TracingOptions};
+pub use crate::sampling::{AdaptiveSampling, Filter, SamplePattern, Supersampling};
 
 use nalgebra::{Point3, Transform3, Vector3};
 
@@ -1,42 +1,92 @@
+use crate::display::DisplayTransform;
+use crate::framebuffer::Framebuffer;
 use crate::geometry::volume::VolumetricSolid;
 use crate::geometry::Ray;
-use crate::scene::{Color, Intersect, Light, SceneNode};
-use image::{save_buffer, Rgb, RGB};
+use crate::sampling::{AdaptiveSampling, Supersampling};
+use crate::scene::{Color, Intersect, Intersection, Light, SceneBvh, SceneNode};
 use nalgebra::{convert, Affine3, Isometry, Point3, Rotation3, Vector3, U3};
 use pbr::ProgressBar;
 use rand::{thread_rng, Rng};
 use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
 use scoped_threadpool::Pool;
-use std::slice;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 type Isometry3<N> = Isometry<N, U3, Rotation3<f32>>;
 
-const USE_RAYON: bool = true;
-
-// TODO: Tracing Options
-// pub struct TracingOptions {
-//     super_sampling: bool,
-//     shadow_rays: bool,
-//     texture_mapping: bool,
-//     phong_lighting: bool,
-//     num_threads: u16,
-// }
-
-// impl Default for TracingOptions {
-//     fn default() -> TracingOptions {
-//         TracingOptions {
-//             super_sampling: true,
-//             shadow_rays: true,
-//             texture_mapping: true,
-//             phong_lighting: true,
-//             num_threads: 1,
-//         }
-//     }
-// }
+// Which thread pool implementation pixels are rendered with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Rayon,
+    ThreadPool,
+}
+
+impl Backend {
+    pub fn from_name(name: &str) -> Option<Backend> {
+        match name {
+            "rayon" => Some(Backend::Rayon),
+            "threadpool" | "scoped_threadpool" => Some(Backend::ThreadPool),
+            _ => None,
+        }
+    }
+}
+
+// How the light arriving along a camera ray is calculated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integrator {
+    // Direct phong lighting with recursive reflection and refraction
+    Whitted,
+    // Unidirectional Monte Carlo path tracing, adds indirect lighting
+    PathTracing,
+}
+
+impl Integrator {
+    pub fn from_name(name: &str) -> Option<Integrator> {
+        match name {
+            "whitted" | "raytrace" => Some(Integrator::Whitted),
+            "path" | "pathtrace" | "path_tracing" => Some(Integrator::PathTracing),
+            _ => None,
+        }
+    }
+}
+
+// Bounces a path takes before russian roulette can terminate it
+const ROULETTE_DEPTH: u32 = 3;
+// Lowest probability of a path surviving russian roulette
+const MIN_SURVIVAL: f32 = 0.05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TracingOptions {
+    pub supersampling: Supersampling,
+    pub shadow_rays: bool,
+    pub texture_mapping: bool,
+    pub num_threads: usize,
+    // How many times a ray can bounce through reflective or transparent materials, or
+    // the longest path the path tracer will follow
+    pub max_depth: u32,
+    pub backend: Backend,
+    pub integrator: Integrator,
+    // How the image is converted for 8-bit output
+    pub display: DisplayTransform,
+}
+
+impl Default for TracingOptions {
+    fn default() -> TracingOptions {
+        TracingOptions {
+            supersampling: Supersampling::default(),
+            shadow_rays: true,
+            texture_mapping: true,
+            num_threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_depth: 5,
+            backend: Backend::Rayon,
+            integrator: Integrator::Whitted,
+            display: DisplayTransform::default(),
+        }
+    }
+}
 
 pub struct Raytracer {
     pub root_node: SceneNode,
@@ -51,6 +101,11 @@ pub struct Raytracer {
     pub ambient: Color,
     pub lights: Vec<Light>,
     pub volumes: Vec<VolumetricSolid>,
+
+    // Acceleration structure over root_node, built before rendering
+    pub bvh: Option<SceneBvh>,
+    // Options for the current render
+    pub options: TracingOptions,
 }
 
 impl Default for Raytracer {
@@ -65,34 +120,109 @@ impl Default for Raytracer {
             ambient: Color::new(0.0, 0.0, 0.0),
             lights: Vec::new(),
             volumes: Vec::new(),
+            bvh: None,
+            options: TracingOptions::default(),
         }
     }
 }
 
 const Z_NEAR: f32 = -1.0;
 
+// Generates primary rays through points on the image plane
+pub(crate) struct Camera {
+    view_matrix: Affine3<f32>,
+    eye: Point3<f32>,
+    side: f32,
+    width: f32,
+    height: f32,
+}
+
+impl Camera {
+    pub(crate) fn new(raytracer: &Raytracer, width: u32, height: u32) -> Camera {
+        Camera {
+            view_matrix: convert(Isometry3::look_at_rh(
+                &raytracer.eye,
+                &raytracer.view,
+                &raytracer.up,
+            )),
+            eye: raytracer.eye,
+            side: -2.0f32 * (raytracer.fov_y.to_radians() / 2.0f32).tan(),
+            width: width as f32,
+            height: height as f32,
+        }
+    }
+
+    // Ray through the point (fx, fy) in pixel coordinates
+    pub(crate) fn ray(&self, fx: f32, fy: f32) -> Ray {
+        let (fw, fh) = (self.width, self.height);
+        let pixel_vec = self.view_matrix
+            * Vector3::new(
+                Z_NEAR * ((fx / fw) - 0.5) * self.side * fw / fh,
+                Z_NEAR * -((fy / fh) - 0.5) * self.side,
+                Z_NEAR,
+            );
+        Ray::new(self.eye, pixel_vec)
+    }
+}
+
 impl Raytracer {
+    // Flatten the scene tree into a BVH, this needs to be rebuilt if the tree changes
+    pub fn build_bvh(&mut self) {
+        let start = Instant::now();
+        let bvh = SceneBvh::new(&self.root_node);
+        println!(
+            "Built scene BVH over {} objects with {} nodes in {:?}",
+            bvh.object_count(),
+            bvh.node_count(),
+            start.elapsed()
+        );
+        self.bvh = Some(bvh);
+    }
+
+    // Find the closest object hit by a ray, using the BVH if one has been built
+    pub fn intersects(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        match &self.bvh {
+            Some(bvh) => bvh.intersects(ray),
+            None => self.root_node.intersects(ray),
+        }
+    }
+
     // Ray trace and save a specific image
-    pub fn render(
-        &self,
-        file_name: &str,
-        width: u32,
-        height: u32, /*, options: TracingOptions*/
-    ) {
-        let view_matrix: Affine3<f32> =
-            convert(Isometry3::look_at_rh(&self.eye, &self.view, &self.up));
+    pub fn render(&mut self, file_name: &str, width: u32, height: u32, options: TracingOptions) {
+        self.options = options;
+        self.build_bvh();
+        self.render_image(file_name, width, height);
+    }
 
-        let side = -2.0f32 * (self.fov_y.to_radians() / 2.0f32).tan();
-        let fw = width as f32;
-        let fh = height as f32;
+    fn render_image(&self, file_name: &str, width: u32, height: u32) {
+        let camera = Camera::new(self, width, height);
+        let supersampling = &self.options.supersampling;
 
+        let pixels = match &supersampling.adaptive {
+            Some(adaptive) => self.render_adaptive(&camera, width, height, adaptive),
+            None => self.render_pass(width, height, file_name, |x, y| {
+                self.render_pixel(&camera, width, height, x, y, supersampling)
+            }),
+        };
+
+        let framebuffer = Framebuffer::new(width, height, pixels);
+        if let Err(e) = framebuffer.save(file_name, &self.options.display) {
+            println!("Failed to save {}: {}", file_name, e);
+        }
+    }
+
+    // Render every pixel in the image in parallel, showing progress as it goes
+    fn render_pass<F>(&self, width: u32, height: u32, label: &str, pixel_fn: F) -> Vec<Color>
+    where
+        F: Fn(u32, u32) -> Color + Sync,
+    {
         let pixel_count = width * height;
 
         let pixels_rendered = Arc::new(AtomicUsize::new(0));
 
         let t_pr = pixels_rendered.clone();
         let pc_usize = pixel_count as usize;
-        let completion_string = format!("Completed {}!", file_name);
+        let completion_string = format!("Completed {}!", label);
         let progress_thread = thread::spawn(move || {
             let mut pb = ProgressBar::new(pc_usize as u64);
             pb.show_counter = false;
@@ -108,93 +238,336 @@ impl Raytracer {
             pb.finish_print(&completion_string);
         });
 
-        if USE_RAYON {
-            let image_pixels: Vec<Rgb<u8>> = (0..pixel_count)
-                .into_par_iter()
-                .map(|i| {
-                    let x = i % width;
-                    let y = i / height;
-                    let fx = x as f32 + 0.5;
-                    let fy = y as f32 + 0.5;
-                    let pixel_vec = view_matrix
-                        * Vector3::new(
-                            Z_NEAR * ((fx / fw) - 0.5) * side * fw / fh,
-                            Z_NEAR * -((fy / fh) - 0.5) * side,
-                            Z_NEAR,
-                        );
-                    let ray = Ray::new(self.eye, pixel_vec);
-                    let color = self.trace_ray(width, height, &ray, x, y);
-                    pixels_rendered.fetch_add(1, Ordering::Relaxed);
-                    color.as_rgb()
-                })
-                .collect();
-
-            // ENTERING SCARY ZONE, DON'T ASK QUESTIONS
-            let transmuted_pixels = unsafe {
-                slice::from_raw_parts(image_pixels.as_ptr() as *mut u8, (pixel_count * 3) as usize)
-            };
-            save_buffer(file_name, transmuted_pixels, width, height, RGB(8)).unwrap();
+        let num_threads = self.options.num_threads.max(1);
+        let pixels = if self.options.backend == Backend::Rayon {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                (0..pixel_count)
+                    .into_par_iter()
+                    .map(|i| {
+                        let x = i % width;
+                        let y = i / width;
+                        let color = pixel_fn(x, y);
+                        pixels_rendered.fetch_add(1, Ordering::Relaxed);
+                        color
+                    })
+                    .collect()
+            })
         } else {
-            let mut buffer: Vec<u8> = vec![0; (width * height * 3) as usize];
-            let mut pool = Pool::new(8);
+            let mut buffer = vec![Color::new(0.0, 0.0, 0.0); pixel_count as usize];
+            let mut pool = Pool::new(num_threads as u32);
 
             pool.scoped(|scoped| {
-                // Create references to each element in the vector ...
-                let mut y = 0;
-                let buffer_ref = &mut buffer;
-                // TODO change back to line by line chunks
-                for chunk in buffer_ref.chunks_mut(3 as usize) {
+                // Each job renders a single row of the image
+                for (y, row) in (0u32..).zip(buffer.chunks_mut(width as usize)) {
                     let pixels_rendered = pixels_rendered.clone();
+                    let pixel_fn = &pixel_fn;
                     scoped.execute(move || {
-                        for x in 0..width {
-                            let fx = x as f32 + 0.5;
-                            let fy = y as f32 + 0.5;
-                            let pixel_vec = view_matrix
-                                * Vector3::new(
-                                    Z_NEAR * ((fx / fw) - 0.5) * side * fw / fh,
-                                    Z_NEAR * -((fy / fh) - 0.5) * side,
-                                    Z_NEAR,
-                                );
-                            let ray = Ray::new(self.eye, pixel_vec);
-                            let color = self.trace_ray(width, height, &ray, x, y);
+                        for (x, pixel) in (0u32..).zip(row.iter_mut()) {
+                            *pixel = pixel_fn(x, y);
                             pixels_rendered.fetch_add(1, Ordering::Relaxed);
-                            let rgb = color.as_rgb();
-                            chunk[0] = rgb.data[0];
-                            chunk[1] = rgb.data[1];
-                            chunk[2] = rgb.data[2];
                         }
                     });
-                    y += 1;
                 }
             });
-            save_buffer(file_name, &buffer, width, height, RGB(8)).unwrap();
-        }
+            buffer
+        };
         progress_thread.join().unwrap();
+        pixels
+    }
+
+    // Adaptive supersampling, trace a single sample through every pixel and then only
+    // subdivide the pixels that contrast with their neighbours
+    fn render_adaptive(
+        &self,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+        adaptive: &AdaptiveSampling,
+    ) -> Vec<Color> {
+        let first_pass = self.render_pass(width, height, "initial samples", |x, y| {
+            let ray = camera.ray(x as f32 + 0.5, y as f32 + 0.5);
+            self.trace_ray(width, height, &ray, x, y)
+        });
+
+        let sample_counts: Vec<AtomicUsize> =
+            (0..first_pass.len()).map(|_| AtomicUsize::new(1)).collect();
+        let pixels = self.render_pass(width, height, "adaptive samples", |x, y| {
+            let index = (y * width + x) as usize;
+            let center = first_pass[index];
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            let contrasts = neighbours
+                .iter()
+                .filter(|(nx, ny)| *nx < width && *ny < height)
+                .any(|(nx, ny)| {
+                    color_difference(&center, &first_pass[(ny * width + nx) as usize])
+                        > adaptive.threshold
+                });
+            if !contrasts {
+                return center;
+            }
+
+            let mut samples = 1;
+            let color = self.refine_region(
+                camera,
+                (width, height),
+                (x, y),
+                (x as f32 + 0.5, y as f32 + 0.5),
+                1.0,
+                center,
+                0,
+                adaptive,
+                &mut samples,
+            );
+            sample_counts[index].store(samples, Ordering::Relaxed);
+            color
+        });
+
+        let counts: Vec<usize> = sample_counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let total: usize = counts.iter().sum();
+        println!(
+            "Adaptive sampling traced {} samples ({:.2} per pixel)",
+            total,
+            total as f32 / counts.len() as f32
+        );
+        if let Some(heatmap) = &adaptive.heatmap {
+            save_heatmap(heatmap, &counts, width, height);
+        }
+
+        pixels
+    }
+
+    // Split a square region of the image into quadrants and sample each one, recursing
+    // into quadrants which still differ from the center of their parent
+    #[allow(clippy::too_many_arguments)]
+    fn refine_region(
+        &self,
+        camera: &Camera,
+        (width, height): (u32, u32),
+        (x, y): (u32, u32),
+        (cx, cy): (f32, f32),
+        size: f32,
+        center: Color,
+        depth: u32,
+        adaptive: &AdaptiveSampling,
+        samples: &mut usize,
+    ) -> Color {
+        if depth >= adaptive.max_depth {
+            return center;
+        }
+
+        let offset = size / 4.0;
+        let quadrants = [
+            (cx - offset, cy - offset),
+            (cx + offset, cy - offset),
+            (cx - offset, cy + offset),
+            (cx + offset, cy + offset),
+        ];
+
+        let (mut r, mut g, mut b) = (0.0f32, 0.0f32, 0.0f32);
+        for &(qx, qy) in quadrants.iter() {
+            let ray = camera.ray(qx, qy);
+            let mut color = self.trace_ray(width, height, &ray, x, y);
+            *samples += 1;
+            if color_difference(&color, &center) > adaptive.threshold {
+                color = self.refine_region(
+                    camera,
+                    (width, height),
+                    (x, y),
+                    (qx, qy),
+                    size / 2.0,
+                    color,
+                    depth + 1,
+                    adaptive,
+                    samples,
+                );
+            }
+            r += color.r;
+            g += color.g;
+            b += color.b;
+        }
+        Color::new(r / 4.0, g / 4.0, b / 4.0)
+    }
+
+    // Trace every sample in a pixel and combine them using the reconstruction filter
+    fn render_pixel(
+        &self,
+        camera: &Camera,
+        width: u32,
+        height: u32,
+        x: u32,
+        y: u32,
+        supersampling: &Supersampling,
+    ) -> Color {
+        let (mut r, mut g, mut b) = (0.0f32, 0.0f32, 0.0f32);
+        let mut total_weight = 0.0f32;
+        for sample in supersampling.pixel_samples() {
+            if sample.weight == 0.0 {
+                continue;
+            }
+            let ray = camera.ray(x as f32 + 0.5 + sample.dx, y as f32 + 0.5 + sample.dy);
+            let color = self.trace_ray(width, height, &ray, x, y);
+            // Accumulate outside of Color, since filters can have negative weights
+            r += sample.weight * color.r;
+            g += sample.weight * color.g;
+            b += sample.weight * color.b;
+            total_weight += sample.weight;
+        }
+
+        if total_weight <= 0.0 {
+            return Color::new(0.0, 0.0, 0.0);
+        }
+        Color::new(r / total_weight, g / total_weight, b / total_weight)
     }
 
     fn trace_ray(&self, width: u32, height: u32, ray: &Ray, x: u32, y: u32) -> Color {
-        let collision = self.root_node.intersects(ray);
-        match collision {
-            Some(c) => {
-                let mut color = c.node.material.get_color(ray, self, &c);
-                for volume in self.volumes.iter() {
-                    // TODO: don't do this
-                    color = volume.apply(ray, &collision, color)
-                }
-                color
+        let background = || get_background_color(x, y, width, height);
+        match self.options.integrator {
+            Integrator::Whitted => self.trace(ray, 0, background),
+            Integrator::PathTracing => self.trace_path(ray, background, get_sky_color),
+        }
+    }
+
+    // Trace a reflected or refracted ray, depth is how many bounces it took to get here
+    pub fn trace_secondary_ray(&self, ray: &Ray, depth: u32) -> Color {
+        self.trace(ray, depth, || get_sky_color(ray))
+    }
+
+    fn trace<F>(&self, ray: &Ray, depth: u32, background: F) -> Color
+    where
+        F: FnOnce() -> Color,
+    {
+        let collision = self.intersects(ray);
+        let color = match collision {
+            Some(c) => c.material.get_color(ray, self, &c, depth),
+            None => background(),
+        };
+        self.apply_volumes(ray, &collision, color)
+    }
+
+    // Follow a random path through the scene, adding the light sampled directly from the
+    // lights at every bounce. Paths that escape the scene pick up the color of the sky
+    // in the direction they leave.
+    fn trace_path<F>(&self, ray: &Ray, background: F, sky: fn(&Ray) -> Color) -> Color
+    where
+        F: FnOnce() -> Color,
+    {
+        let first_collision = self.intersects(ray);
+        let mut collision = match first_collision {
+            Some(c) => c,
+            None => return self.apply_volumes(ray, &first_collision, background()),
+        };
+
+        // Kept outside of Color so russian roulette can scale the throughput past 1
+        let mut radiance = Vector3::new(0.0f32, 0.0, 0.0);
+        let mut throughput = Vector3::new(1.0f32, 1.0, 1.0);
+        let mut current_ray = *ray;
+        let mut depth = 0;
+        let mut rng = thread_rng();
+        loop {
+            let direct = collision
+                .material
+                .direct_lighting(&current_ray, self, &collision);
+            radiance += throughput.component_mul(&direct.as_vector());
+            if depth >= self.options.max_depth {
+                break;
             }
-            None => {
-                let mut color = get_background_color(x, y, width, height);
-                for volume in self.volumes.iter() {
-                    // TODO: don't do this
-                    color = volume.apply(ray, &collision, color)
+
+            let (next_ray, weight) =
+                match collision.material.scatter(&current_ray, self, &collision) {
+                    Some(scattered) => scattered,
+                    None => break,
+                };
+            throughput = throughput.component_mul(&weight.as_vector());
+            depth += 1;
+
+            if depth >= ROULETTE_DEPTH {
+                let survival = throughput.x.max(throughput.y).max(throughput.z);
+                let survival = survival.clamp(MIN_SURVIVAL, 1.0);
+                if rng.gen::<f32>() >= survival {
+                    break;
                 }
-                color
+                throughput /= survival;
             }
+
+            current_ray = next_ray;
+            collision = match self.intersects(&current_ray) {
+                Some(c) => c,
+                None => {
+                    let sky = sky(&current_ray);
+                    radiance += throughput.component_mul(&sky.as_vector());
+                    break;
+                }
+            };
+        }
+
+        let color = Color::from_vector(&radiance);
+        self.apply_volumes(ray, &first_collision, color)
+    }
+
+    fn apply_volumes(&self, ray: &Ray, collision: &Option<Intersection>, color: Color) -> Color {
+        let mut color = color;
+        for volume in self.volumes.iter() {
+            // TODO: don't do this
+            color = volume.apply(ray, collision, color)
         }
+        color
     }
 }
 
+// Largest difference between two colors in any channel
+fn color_difference(a: &Color, b: &Color) -> f32 {
+    (a.r - b.r)
+        .abs()
+        .max((a.g - b.g).abs())
+        .max((a.b - b.b).abs())
+}
+
+// Save an image showing how many samples were taken for each pixel, going from black
+// (a single sample) through red and yellow to white (the most samples in the image)
+fn save_heatmap(file_name: &str, counts: &[usize], width: u32, height: u32) {
+    let max = counts.iter().cloned().max().unwrap_or(1).max(2);
+    let pixels: Vec<Color> = counts
+        .iter()
+        .map(|&count| {
+            let t = 3.0 * (count - 1) as f32 / (max - 1) as f32;
+            Color::new(
+                t.min(1.0),
+                (t - 1.0).clamp(0.0, 1.0),
+                (t - 2.0).clamp(0.0, 1.0),
+            )
+        })
+        .collect();
+    match Framebuffer::new(width, height, pixels).save(file_name, &DisplayTransform::raw()) {
+        Ok(()) => println!("Saved sample heatmap to {}", file_name),
+        Err(e) => println!("Failed to save heatmap {}: {}", file_name, e),
+    }
+}
+
+// Background seen by secondary rays, the same gradient as the rendered background
+// but based on the direction of the ray rather than the pixel
+fn get_sky_color(ray: &Ray) -> Color {
+    let dir = ray.dir.normalize();
+    // Map straight up to the top of the screen and straight down to the bottom
+    let height_rate = f32::max(0.0f32, (0.5 - (dir.y * 0.5)) - 0.2f32);
+    Color::new(
+        (67.0f32 / 255.) * height_rate,
+        (133.0f32 / 255.) * height_rate,
+        height_rate,
+    )
+}
+
 fn get_background_color(_x: u32, y: u32, _width: u32, height: u32) -> Color {
     // let fw = width as f32;
     let fh = height as f32;
@@ -230,3 +603,88 @@ fn get_background_color(_x: u32, y: u32, _width: u32, height: u32) -> Color {
         b_rate * height_rate,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+    use crate::scene::Material;
+
+    fn white_sky(_: &Ray) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
+
+    fn black_sky(_: &Ray) -> Color {
+        Color::new(0.0, 0.0, 0.0)
+    }
+
+    // A path tracer looking at a unit sphere at the origin
+    fn sphere_scene(material: Material, lights: Vec<Light>) -> Raytracer {
+        let mut sphere = SceneNode::new(1, "sphere".to_string());
+        sphere.primitive = Arc::new(Primitive::Sphere);
+        sphere.material = Arc::new(material);
+        let mut raytracer = Raytracer {
+            lights,
+            ..Raytracer::default()
+        };
+        raytracer.root_node.add_child(sphere);
+        raytracer.options.integrator = Integrator::PathTracing;
+        raytracer
+    }
+
+    // Average of many paths from a point in front of the sphere towards its center
+    fn average_path(raytracer: &Raytracer, sky: fn(&Ray) -> Color, samples: u32) -> Color {
+        let ray = Ray::new(Point3::new(0.0, 0.0, 5.0), -Vector3::z());
+        let total = (0..samples).fold(Vector3::zeros(), |total, _| {
+            let color = raytracer.trace_path(&ray, || Color::new(0.0, 0.0, 0.0), sky);
+            total + color.as_vector()
+        });
+        Color::from_vector(&(total / samples as f32))
+    }
+
+    fn assert_gray(color: Color, expected: f32, tolerance: f32) {
+        for &channel in [color.r, color.g, color.b].iter() {
+            assert!(
+                (channel - expected).abs() < tolerance,
+                "{:?} is not {}",
+                color,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn paths_under_a_white_sky_return_the_albedo() {
+        // Nothing on a lone sphere can bounce back onto it, so every path leaves after one
+        // bounce with either the diffuse color or, for the mirror part, all of the light
+        let gray = Color::new(0.5, 0.5, 0.5);
+        let raytracer = sphere_scene(Material::phong(gray, gray, 20.0, 0.0), vec![]);
+        assert_gray(average_path(&raytracer, white_sky, 100), 0.5, 1e-4);
+
+        let raytracer = sphere_scene(Material::phong(gray, gray, 20.0, 0.25), vec![]);
+        let expected = 0.25 + (0.75 * 0.5);
+        assert_gray(average_path(&raytracer, white_sky, 20000), expected, 0.01);
+    }
+
+    #[test]
+    fn phong_highlights_are_not_sampled_from_lights_when_path_tracing() {
+        // A light right behind the viewer, where the highlight would be brightest
+        let light = || {
+            Light::new(
+                Color::new(1.0, 1.0, 1.0),
+                Point3::new(0.0, 0.0, 5.0),
+                [1.0, 0.0, 0.0],
+                0.0,
+                1,
+            )
+        };
+        let gray = Color::new(0.5, 0.5, 0.5);
+        let shiny = sphere_scene(Material::phong(gray, gray, 20.0, 0.0), vec![light()]);
+        let matte = sphere_scene(
+            Material::phong(gray, Color::new(0.0, 0.0, 0.0), 20.0, 0.0),
+            vec![light()],
+        );
+        assert_gray(average_path(&shiny, black_sky, 10), 0.5, 1e-4);
+        assert_gray(average_path(&matte, black_sky, 10), 0.5, 1e-4);
+    }
+}
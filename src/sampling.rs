@@ -0,0 +1,392 @@
+use nalgebra::Vector3;
+use rand::{thread_rng, Rng};
+use std::f32;
+
+// How sample positions are distributed across a pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplePattern {
+    // Evenly spaced samples on a regular grid
+    Grid,
+    // One random sample inside each cell of a grid (stratified sampling)
+    Jittered,
+    // Low discrepancy Halton sequence in bases 2 and 3
+    Halton,
+    // Low discrepancy Sobol sequence (the first two dimensions)
+    Sobol,
+}
+
+impl SamplePattern {
+    pub fn from_name(name: &str) -> Option<SamplePattern> {
+        match name {
+            "grid" | "regular" => Some(SamplePattern::Grid),
+            "jittered" | "stratified" => Some(SamplePattern::Jittered),
+            "halton" => Some(SamplePattern::Halton),
+            "sobol" => Some(SamplePattern::Sobol),
+            _ => None,
+        }
+    }
+}
+
+// Reconstruction filter used to weight samples when they are combined into a pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+}
+
+const GAUSSIAN_ALPHA: f32 = 2.0;
+// Mitchell-Netravali parameters, B = C = 1/3 as recommended in the paper
+const MITCHELL_B: f32 = 1.0 / 3.0;
+const MITCHELL_C: f32 = 1.0 / 3.0;
+
+impl Filter {
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box),
+            "tent" | "triangle" => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            _ => None,
+        }
+    }
+
+    // How far from the pixel center (in pixels) the filter has any weight
+    pub fn radius(&self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    // Weight of a sample offset (dx, dy) pixels from the pixel center
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        let radius = self.radius();
+        // The box filter would still have weight at its edge otherwise
+        if x >= radius {
+            return 0.0;
+        }
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => radius - x,
+            Filter::Gaussian => {
+                (-GAUSSIAN_ALPHA * x * x).exp() - (-GAUSSIAN_ALPHA * radius * radius).exp()
+            }
+            Filter::Mitchell => {
+                let (b, c) = (MITCHELL_B, MITCHELL_C);
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                }
+            }
+        }
+    }
+}
+
+// A single sample within a pixel, offset from the pixel center
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PixelSample {
+    pub dx: f32,
+    pub dy: f32,
+    pub weight: f32,
+}
+
+// Settings for adaptive supersampling, where a pixel is only subdivided if its color
+// differs from its neighbours
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveSampling {
+    // Largest difference in any color channel before a pixel is subdivided
+    pub threshold: f32,
+    // How many times a pixel can be split into quadrants
+    pub max_depth: u32,
+    // Optional file to write a heatmap of the number of samples per pixel to
+    pub heatmap: Option<String>,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> AdaptiveSampling {
+        AdaptiveSampling {
+            threshold: 0.1,
+            max_depth: 3,
+            heatmap: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Supersampling {
+    // Number of samples per pixel, grid based patterns round this up to a square
+    pub samples: u32,
+    pub pattern: SamplePattern,
+    pub filter: Filter,
+    // When set, pixels are sampled adaptively instead of using a fixed sample pattern
+    pub adaptive: Option<AdaptiveSampling>,
+}
+
+impl Default for Supersampling {
+    fn default() -> Supersampling {
+        Supersampling {
+            samples: 1,
+            pattern: SamplePattern::Grid,
+            filter: Filter::Box,
+            adaptive: None,
+        }
+    }
+}
+
+impl Supersampling {
+    // Generate the samples for a single pixel. Samples are spread across the whole
+    // footprint of the filter, so wider filters blend in some of the neighbouring pixels.
+    pub fn pixel_samples(&self) -> Vec<PixelSample> {
+        if self.samples <= 1 {
+            return vec![PixelSample {
+                dx: 0.0,
+                dy: 0.0,
+                weight: 1.0,
+            }];
+        }
+
+        let radius = self.filter.radius();
+        self.unit_square_samples()
+            .into_iter()
+            .map(|(u, v)| {
+                let dx = (u - 0.5) * 2.0 * radius;
+                let dy = (v - 0.5) * 2.0 * radius;
+                PixelSample {
+                    dx,
+                    dy,
+                    weight: self.filter.weight(dx, dy),
+                }
+            })
+            .collect()
+    }
+
+    // Sample positions in [0, 1)^2
+    fn unit_square_samples(&self) -> Vec<(f32, f32)> {
+        let mut rng = thread_rng();
+        match self.pattern {
+            SamplePattern::Grid | SamplePattern::Jittered => {
+                let side = (self.samples as f32).sqrt().ceil() as u32;
+                let cell = 1.0 / side as f32;
+                let jitter = self.pattern == SamplePattern::Jittered;
+                let mut samples = Vec::with_capacity((side * side) as usize);
+                for j in 0..side {
+                    for i in 0..side {
+                        let (ox, oy) = if jitter {
+                            (rng.gen::<f32>(), rng.gen::<f32>())
+                        } else {
+                            (0.5, 0.5)
+                        };
+                        samples.push(((i as f32 + ox) * cell, (j as f32 + oy) * cell));
+                    }
+                }
+                samples
+            }
+            SamplePattern::Halton | SamplePattern::Sobol => {
+                // Every pixel uses the same sequence, so each is shifted by a random offset
+                // (Cranley-Patterson rotation) to avoid structured aliasing across the image
+                let shift: (f32, f32) = (rng.gen(), rng.gen());
+                (0..self.samples)
+                    .map(|i| {
+                        let (u, v) = if self.pattern == SamplePattern::Halton {
+                            (radical_inverse(i + 1, 2), radical_inverse(i + 1, 3))
+                        } else {
+                            sobol_2d(i)
+                        };
+                        ((u + shift.0).fract(), (v + shift.1).fract())
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+// The radical inverse of i in the given base, used to generate the Halton sequence
+fn radical_inverse(mut i: u32, base: u32) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut factor = inv_base;
+    let mut result = 0.0f32;
+    while i > 0 {
+        result += (i % base) as f32 * factor;
+        i /= base;
+        factor *= inv_base;
+    }
+    result
+}
+
+// The first two dimensions of the Sobol sequence. The first is the van der Corput
+// sequence, the second uses the generator matrix of Pascal's triangle mod 2.
+fn sobol_2d(i: u32) -> (f32, f32) {
+    let first = i.reverse_bits();
+    let mut second = 0u32;
+    let mut v = 1u32 << 31;
+    let mut index = i;
+    while index != 0 {
+        if index & 1 != 0 {
+            second ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    let scale = 1.0 / 4_294_967_296.0f32;
+    (
+        (first as f32 * scale).min(1.0 - f32::EPSILON),
+        (second as f32 * scale).min(1.0 - f32::EPSILON),
+    )
+}
+
+// Two vectors which together with the normal form an orthonormal basis
+fn orthonormal_basis(normal: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if normal.x.abs() > 0.9 {
+        Vector3::new(0.0, 1.0, 0.0)
+    } else {
+        Vector3::new(1.0, 0.0, 0.0)
+    };
+    let tangent = normal.cross(&helper).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
+}
+
+// A random direction in the hemisphere around a normal, distributed proportionally to the
+// cosine of the angle to the normal
+pub fn cosine_hemisphere(normal: &Vector3<f32>) -> Vector3<f32> {
+    let mut rng = thread_rng();
+    let (u, v): (f32, f32) = (rng.gen(), rng.gen());
+    let radius = u.sqrt();
+    let theta = 2.0 * f32::consts::PI * v;
+    let (x, y) = (radius * theta.cos(), radius * theta.sin());
+    let z = (1.0 - u).max(0.0).sqrt();
+
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (x * tangent) + (y * bitangent) + (z * normal)
+}
+
+// A random microfacet normal around a surface normal, distributed by the GGX normal
+// distribution with the given alpha (roughness squared)
+pub fn ggx_half_vector(normal: &Vector3<f32>, alpha: f32) -> Vector3<f32> {
+    let mut rng = thread_rng();
+    let (u, v): (f32, f32) = (rng.gen(), rng.gen());
+    let cos2_theta = (1.0 - u) / (1.0 + ((alpha * alpha) - 1.0) * u);
+    let cos_theta = cos2_theta.max(0.0).sqrt();
+    let sin_theta = (1.0 - cos2_theta).max(0.0).sqrt();
+    let phi = 2.0 * f32::consts::PI * v;
+
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    (sin_theta * phi.cos() * tangent) + (sin_theta * phi.sin() * bitangent) + (cos_theta * normal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 4] = [
+        Filter::Box,
+        Filter::Tent,
+        Filter::Gaussian,
+        Filter::Mitchell,
+    ];
+
+    #[test]
+    fn halton_sequence_matches_its_radical_inverses() {
+        let base_2: Vec<f32> = (1..8).map(|i| radical_inverse(i, 2)).collect();
+        assert_eq!(
+            base_2,
+            [
+                1.0 / 2.0,
+                1.0 / 4.0,
+                3.0 / 4.0,
+                1.0 / 8.0,
+                5.0 / 8.0,
+                3.0 / 8.0,
+                7.0 / 8.0
+            ]
+        );
+        let base_3: Vec<f32> = (1..9).map(|i| radical_inverse(i, 3) * 9.0).collect();
+        for (value, expected) in base_3
+            .iter()
+            .zip([3.0, 6.0, 1.0, 4.0, 7.0, 2.0, 5.0, 8.0].iter())
+        {
+            assert!((value - expected).abs() < 1e-5, "{:?}", base_3);
+        }
+    }
+
+    #[test]
+    fn sobol_sequence_matches_its_first_two_dimensions() {
+        let points: Vec<(f32, f32)> = (0..8).map(sobol_2d).collect();
+        let eighths = |values: [f32; 8]| values.iter().map(|v| v / 8.0).collect::<Vec<f32>>();
+        let first: Vec<f32> = points.iter().map(|p| p.0).collect();
+        let second: Vec<f32> = points.iter().map(|p| p.1).collect();
+        assert_eq!(first, eighths([0.0, 4.0, 2.0, 6.0, 1.0, 5.0, 3.0, 7.0]));
+        assert_eq!(second, eighths([0.0, 4.0, 6.0, 2.0, 5.0, 1.0, 3.0, 7.0]));
+    }
+
+    #[test]
+    fn filters_have_no_weight_from_their_radius_on() {
+        for filter in FILTERS.iter() {
+            let radius = filter.radius();
+            assert!(filter.weight(0.0, 0.0) > 0.0, "{:?}", filter);
+            for &x in [radius, radius + 0.01, radius * 2.0, 100.0].iter() {
+                assert_eq!(filter.weight(x, 0.0), 0.0, "{:?} at {}", filter, x);
+                assert_eq!(filter.weight(0.0, -x), 0.0, "{:?} at {}", filter, -x);
+            }
+            // Each filter comes down to zero continuously, except the box
+            if *filter != Filter::Box {
+                assert!(
+                    filter.weight(radius - 0.001, 0.0).abs() < 1e-3,
+                    "{:?}",
+                    filter
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn mitchell_weights_integrate_to_one_over_the_filter() {
+        let radius = Filter::Mitchell.radius();
+        let steps = 400;
+        let step = 2.0 * radius / steps as f32;
+        let mut total = 0.0f64;
+        for j in 0..steps {
+            for i in 0..steps {
+                let dx = -radius + (i as f32 + 0.5) * step;
+                let dy = -radius + (j as f32 + 0.5) * step;
+                total += f64::from(Filter::Mitchell.weight(dx, dy) * step * step);
+            }
+        }
+        assert!((total - 1.0).abs() < 1e-3, "{}", total);
+    }
+
+    #[test]
+    fn samples_cover_the_filter_footprint() {
+        for filter in FILTERS.iter() {
+            let supersampling = Supersampling {
+                samples: 16,
+                filter: *filter,
+                ..Supersampling::default()
+            };
+            let samples = supersampling.pixel_samples();
+            assert_eq!(samples.len(), 16);
+            for sample in samples.iter() {
+                assert!(sample.dx.abs() < filter.radius() && sample.dy.abs() < filter.radius());
+                assert_eq!(sample.weight, filter.weight(sample.dx, sample.dy));
+            }
+            assert!(samples.iter().map(|s| s.weight).sum::<f32>() > 0.0);
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use crate::geometry::{Aabb, Bvh, Primitive, Ray};
+use crate::scene::{Color, Csg, Intersection, Material, SceneNode};
+use nalgebra::{Affine3, Vector3};
+use std::f32;
+use std::sync::Arc;
+
+// A node from the scene tree with its transform flattened into world space
+#[derive(Debug, Clone)]
+struct SceneObject {
+    primitive: Arc<Primitive>,
+    material: Arc<Material>,
+    transform: Affine3<f32>,
+    inv_transform: Affine3<f32>,
+    // The solid of a csg node and the node's material, intersected as one object. The
+    // object's material is only set when an instance overrides the materials inside it.
+    csg: Option<(Arc<Csg>, Arc<Material>)>,
+}
+
+impl SceneObject {
+    fn primitive_intersects(&self, transformed_ray: &Ray) -> Option<Intersection<'_>> {
+        let mut t_value: f32 = 0.0;
+        let mut normal = Vector3::new(0.0f32, 0.0, 0.0);
+        let mut uv = [0.0, 0.0];
+        let mut face = None;
+        let mut color = None;
+        if !self.primitive.collides(
+            transformed_ray,
+            &mut t_value,
+            &mut normal,
+            &mut uv,
+            &mut face,
+            &mut color,
+        ) {
+            return None;
+        }
+
+        Some(Intersection {
+            face,
+            color: color.map(|c| Color::from_vector(&c)),
+            ..Intersection::new(
+                t_value,
+                transformed_ray.src + (t_value * transformed_ray.dir),
+                &self.material,
+                normal,
+                uv[0],
+                uv[1],
+            )
+        })
+    }
+
+    fn intersects(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let transformed_ray = self.inv_transform * *ray;
+
+        let intersection = match &self.csg {
+            Some((csg, csg_material)) => {
+                let hit = csg
+                    .crossings(csg_material, &transformed_ray)
+                    .into_iter()
+                    .next()?;
+                // Csg objects only take the material of an instance they are inside of
+                match *self.material {
+                    Material::None => hit,
+                    _ => Intersection {
+                        material: &self.material,
+                        ..hit
+                    },
+                }
+            }
+            None => self.primitive_intersects(&transformed_ray)?,
+        }
+        .apply_transform(&self.transform, &self.inv_transform);
+
+        // The transformed ray is renormalized, so t has to be recovered in world space
+        Some(Intersection {
+            t_value: (intersection.point - ray.src).dot(&ray.dir),
+            ..intersection
+        })
+    }
+}
+
+// Acceleration structure over every primitive in a scene tree. The tree is flattened
+// into world space objects, which are then organized into a bounding volume hierarchy
+// so rays can skip entire groups of objects at once.
+#[derive(Debug, Clone, Default)]
+pub struct SceneBvh {
+    objects: Vec<SceneObject>,
+    // Objects without finite bounds, these are tested against every ray
+    unbounded: Vec<usize>,
+    bvh: Bvh,
+    // Maps leaf items in the bvh to their index in objects
+    bounded: Vec<usize>,
+}
+
+// `material` replaces the material of everything below an instance that overrides it
+fn flatten(
+    node: &SceneNode,
+    parent_transform: &Affine3<f32>,
+    material: Option<&Arc<Material>>,
+    objects: &mut Vec<SceneObject>,
+) {
+    let transform = parent_transform * node.transform;
+
+    // The operands of a csg node make up one solid, so the whole node is one object
+    if let Some(csg) = &node.csg {
+        objects.push(SceneObject {
+            primitive: Arc::new(Primitive::None),
+            material: material
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Material::None)),
+            transform,
+            inv_transform: transform.inverse(),
+            csg: Some((Arc::clone(csg), node.material.clone())),
+        });
+        return;
+    }
+
+    if !matches!(*node.primitive, Primitive::None) {
+        objects.push(SceneObject {
+            primitive: node.primitive.clone(),
+            material: material.unwrap_or(&node.material).clone(),
+            transform,
+            inv_transform: transform.inverse(),
+            csg: None,
+        });
+    }
+
+    for child in node.children.iter() {
+        flatten(child, &transform, material, objects);
+    }
+
+    // Prototypes are shared, so only their transforms are copied for each instance
+    if let Some(prototype) = &node.instance {
+        let material = match *node.material {
+            Material::None => material,
+            _ => material.or(Some(&node.material)),
+        };
+        flatten(prototype, &transform, material, objects);
+    }
+}
+
+impl SceneBvh {
+    pub fn new(root: &SceneNode) -> SceneBvh {
+        let mut objects = vec![];
+        flatten(root, &Affine3::identity(), None, &mut objects);
+
+        let mut bounded = vec![];
+        let mut unbounded = vec![];
+        let mut bounds: Vec<Aabb> = vec![];
+        for (i, object) in objects.iter().enumerate() {
+            let object_bounds = match &object.csg {
+                Some((csg, _)) => csg.bounds(),
+                None => object.primitive.bounds(),
+            };
+            match object_bounds {
+                Some(b) => {
+                    bounded.push(i);
+                    bounds.push(b.transform(&object.transform));
+                }
+                None => unbounded.push(i),
+            }
+        }
+
+        SceneBvh {
+            objects,
+            unbounded,
+            bvh: Bvh::build(&bounds),
+            bounded,
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.bvh.node_count()
+    }
+
+    // Find the closest intersection of a ray with any object in the scene
+    pub fn intersects(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let mut closest: Option<Intersection> = None;
+
+        for &i in self.unbounded.iter() {
+            if let Some(hit) = self.objects[i].intersects(ray) {
+                if closest.is_none_or(|c| hit.t_value < c.t_value) {
+                    closest = Some(hit);
+                }
+            }
+        }
+
+        let t_max = closest.map_or(f32::MAX, |c| c.t_value);
+        self.bvh.traverse(ray, t_max, |item, t_closest| {
+            let hit = self.objects[self.bounded[item]].intersects(ray)?;
+            if hit.t_value < t_closest {
+                closest = Some(hit);
+                Some(hit.t_value)
+            } else {
+                None
+            }
+        });
+
+        closest
+    }
+}
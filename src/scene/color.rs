@@ -18,7 +18,6 @@ impl Add for Color {
             g: self.g + rhs.g,
             b: self.b + rhs.b,
         }
-        .clamp()
     }
 }
 
@@ -31,7 +30,6 @@ impl Mul for Color {
             g: self.g * rhs.g,
             b: self.b * rhs.b,
         }
-        .clamp()
     }
 }
 
@@ -44,11 +42,10 @@ impl Mul<Color> for f32 {
             g: self * rhs.g,
             b: self * rhs.b,
         }
-        .clamp()
     }
 }
 
-impl<'a, 'b> Mul<&'b Vector3<f32>> for &'a Color {
+impl<'b> Mul<&'b Vector3<f32>> for &Color {
     type Output = Color;
 
     fn mul(self, rhs: &'b Vector3<f32>) -> Color {
@@ -57,11 +54,10 @@ impl<'a, 'b> Mul<&'b Vector3<f32>> for &'a Color {
             g: self.g * rhs.y,
             b: self.b * rhs.z,
         }
-        .clamp()
     }
 }
 
-impl<'a> Mul<f32> for &'a Color {
+impl Mul<f32> for &Color {
     type Output = Color;
 
     fn mul(self, rhs: f32) -> Color {
@@ -70,7 +66,6 @@ impl<'a> Mul<f32> for &'a Color {
             g: self.g * rhs,
             b: self.b * rhs,
         }
-        .clamp()
     }
 }
 
@@ -83,12 +78,11 @@ impl Div<f32> for Color {
             g: self.g / rhs,
             b: self.b / rhs,
         }
-        .clamp()
     }
 }
 
 impl Color {
-    pub fn new(r: f32, g: f32, b: f32) -> Color {
+    pub const fn new(r: f32, g: f32, b: f32) -> Color {
         Color { r, g, b }
     }
 
@@ -100,18 +94,29 @@ impl Color {
         }
     }
 
+    // Conversions for doing vector math on colors
+    pub fn as_vector(&self) -> Vector3<f32> {
+        Vector3::new(self.r, self.g, self.b)
+    }
+
+    pub fn from_vector(v: &Vector3<f32>) -> Color {
+        Color::new(v.x, v.y, v.z)
+    }
+
+    // 8-bit display color, anything outside of [0, 1] is clamped
     pub fn as_rgb(&self) -> Rgb<u8> {
+        let clamped = self.clamp();
         Rgb([
-            (self.r * 255.0).round() as u8,
-            (self.g * 255.0).round() as u8,
-            (self.b * 255.0).round() as u8,
+            (clamped.r * 255.0).round() as u8,
+            (clamped.g * 255.0).round() as u8,
+            (clamped.b * 255.0).round() as u8,
         ])
     }
 
     fn clamp(mut self) -> Color {
-        self.r = self.r.max(0.0).min(1.0);
-        self.g = self.g.max(0.0).min(1.0);
-        self.b = self.b.max(0.0).min(1.0);
+        self.r = self.r.clamp(0.0, 1.0);
+        self.g = self.g.clamp(0.0, 1.0);
+        self.b = self.b.clamp(0.0, 1.0);
         self
     }
 }
@@ -0,0 +1,265 @@
+use crate::geometry::{Aabb, Ray};
+use crate::scene::{Color, Intersection, Material, SceneNode};
+use std::sync::Arc;
+
+// How the children of a constructive solid geometry node are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // The first child with every following child cut out of it
+    Difference,
+}
+
+impl CsgOperation {
+    pub fn name(self) -> &'static str {
+        match self {
+            CsgOperation::Union => "union",
+            CsgOperation::Intersection => "intersection",
+            CsgOperation::Difference => "difference",
+        }
+    }
+
+    // Whether a point inside or outside each of the two solids is inside the result
+    fn contains(self, a: bool, b: bool) -> bool {
+        match self {
+            CsgOperation::Union => a || b,
+            CsgOperation::Intersection => a && b,
+            CsgOperation::Difference => a && !b,
+        }
+    }
+}
+
+// The solids combined by a csg node. They are kept behind an Arc on the node, so the scene
+// BVH and instances share them instead of copying them.
+#[derive(Debug, Clone)]
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub operands: Vec<SceneNode>,
+}
+
+impl Csg {
+    pub fn new(operation: CsgOperation, operands: Vec<SceneNode>) -> Arc<Csg> {
+        Arc::new(Csg {
+            operation,
+            operands,
+        })
+    }
+
+    // Every crossing of the combined solid, closest first. The ray and the crossings are in
+    // the space of the csg node, and operands without a material of their own use `material`.
+    pub fn crossings<'a>(&'a self, material: &'a Material, ray: &Ray) -> Vec<Intersection<'a>> {
+        let mut solids = self.operands.iter().map(|operand| crossings(operand, ray));
+        let first = solids.next().unwrap_or_default();
+        let mut combined = solids.fold(first, |a, b| combine(self.operation, a, b));
+        for hit in combined.iter_mut() {
+            if let Material::None = hit.material {
+                hit.material = material;
+            }
+        }
+        combined
+    }
+
+    // Bounds of the combined solid in the space of the csg node
+    pub fn bounds(&self) -> Option<Aabb> {
+        let operands = match self.operation {
+            // Nothing outside of the first operand can be left after a difference
+            CsgOperation::Difference => &self.operands[..self.operands.len().min(1)],
+            _ => &self.operands[..],
+        };
+        union_bounds(operands.iter().filter_map(bounds))
+    }
+}
+
+fn union_bounds<I: Iterator<Item = Aabb>>(bounds: I) -> Option<Aabb> {
+    bounds.fold(None, |total: Option<Aabb>, b| match total {
+        Some(total) => Some(total.union(&b)),
+        None => Some(b),
+    })
+}
+
+// Merge the crossings of two solids into the crossings of the combined solid, keeping
+// only the ones where the ray moves between the inside and outside of the result
+fn combine<'a>(
+    operation: CsgOperation,
+    a: Vec<Intersection<'a>>,
+    b: Vec<Intersection<'a>>,
+) -> Vec<Intersection<'a>> {
+    // A ray crosses a closed surface an odd number of times when it starts inside
+    let mut inside_a = a.len() % 2 == 1;
+    let mut inside_b = b.len() % 2 == 1;
+
+    let mut result = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        let was_inside = operation.contains(inside_a, inside_b);
+        let from_a = j == b.len() || (i < a.len() && a[i].t_value <= b[j].t_value);
+        let mut hit = if from_a {
+            inside_a = !inside_a;
+            i += 1;
+            a[i - 1]
+        } else {
+            inside_b = !inside_b;
+            j += 1;
+            b[j - 1]
+        };
+
+        if operation.contains(inside_a, inside_b) != was_inside {
+            // Surfaces cut out of a solid face into the hole they leave
+            if !from_a && operation == CsgOperation::Difference {
+                hit.normal = -hit.normal;
+            }
+            result.push(hit);
+        }
+    }
+    result
+}
+
+// Every point where the ray crosses the surface of the solid described by a node, closest
+// first. The ray is in the space of the node's parent, and so are the crossings. Nodes that
+// aren't csg nodes are the union of their primitive, their children and their prototype.
+pub fn crossings<'a>(node: &'a SceneNode, ray: &Ray) -> Vec<Intersection<'a>> {
+    let transformed_ray = node.inv_transform * *ray;
+
+    let local = match &node.csg {
+        Some(csg) => csg.crossings(&node.material, &transformed_ray),
+        None => {
+            let own = node
+                .primitive
+                .crossings(&transformed_ray)
+                .into_iter()
+                .map(|crossing| Intersection {
+                    face: crossing.face,
+                    color: crossing.color.map(|c| Color::from_vector(&c)),
+                    ..Intersection::new(
+                        crossing.t_value,
+                        transformed_ray.src + (crossing.t_value * transformed_ray.dir),
+                        &node.material,
+                        crossing.normal,
+                        crossing.uv[0],
+                        crossing.uv[1],
+                    )
+                })
+                .collect();
+            let prototype = node.instance.as_ref().map(|prototype| {
+                crossings(prototype, &transformed_ray)
+                    .into_iter()
+                    .map(|hit| node.instance_material(hit))
+                    .collect()
+            });
+            node.children
+                .iter()
+                .map(|child| crossings(child, &transformed_ray))
+                .chain(prototype)
+                .fold(own, |a, b| combine(CsgOperation::Union, a, b))
+        }
+    };
+
+    local
+        .into_iter()
+        .map(|hit| {
+            let hit = hit.apply_transform(&node.transform, &node.inv_transform);
+            // The transformed ray is renormalized, so t has to be recovered in this space
+            Intersection {
+                t_value: (hit.point - ray.src).dot(&ray.dir),
+                ..hit
+            }
+        })
+        .collect()
+}
+
+// Bounds of the solid described by a node, in the space of its parent
+pub fn bounds(node: &SceneNode) -> Option<Aabb> {
+    let local = match &node.csg {
+        Some(csg) => csg.bounds(),
+        None => union_bounds(
+            node.children
+                .iter()
+                .chain(node.instance.as_deref())
+                .filter_map(bounds)
+                .chain(node.primitive.bounds()),
+        ),
+    };
+    local.map(|b| b.transform(&node.transform))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Primitive;
+    use nalgebra::{Point3, Vector3};
+
+    // A unit sphere with a cube of side 1 centered inside it
+    fn sphere_and_cube(operation: CsgOperation) -> SceneNode {
+        let mut sphere = SceneNode::new(1, "sphere".into());
+        sphere.primitive = Arc::new(Primitive::Sphere);
+        let mut cube = SceneNode::new(2, "cube".into());
+        cube.primitive = Arc::new(Primitive::Cube);
+        cube.translate(-0.5, -0.5, -0.5);
+
+        let mut node = SceneNode::new(0, "csg".into());
+        node.csg = Some(Csg::new(operation, vec![sphere, cube]));
+        node
+    }
+
+    // Where a ray along the x axis crosses the solid, with the x component of each normal
+    fn crossings_along_x(operation: CsgOperation, start: f32) -> Vec<(f32, f32)> {
+        let node = sphere_and_cube(operation);
+        let ray = Ray::new(Point3::new(start, 0.0, 0.0), Vector3::x());
+        crossings(&node, &ray)
+            .iter()
+            .map(|hit| (hit.point.x, hit.normal.normalize().x))
+            .collect()
+    }
+
+    fn assert_crossings(actual: Vec<(f32, f32)>, expected: &[(f32, f32)]) {
+        assert_eq!(actual.len(), expected.len(), "{:?}", actual);
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a.0 - e.0).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+            assert!((a.1 - e.1).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn union_keeps_the_outer_surface() {
+        let crossings = crossings_along_x(CsgOperation::Union, -5.0);
+        assert_crossings(crossings, &[(-1.0, -1.0), (1.0, 1.0)]);
+    }
+
+    #[test]
+    fn intersection_keeps_the_inner_surface() {
+        let crossings = crossings_along_x(CsgOperation::Intersection, -5.0);
+        assert_crossings(crossings, &[(-0.5, -1.0), (0.5, 1.0)]);
+    }
+
+    #[test]
+    fn difference_cuts_a_hole_facing_inwards() {
+        let crossings = crossings_along_x(CsgOperation::Difference, -5.0);
+        assert_crossings(
+            crossings,
+            &[(-1.0, -1.0), (-0.5, 1.0), (0.5, -1.0), (1.0, 1.0)],
+        );
+    }
+
+    #[test]
+    fn rays_starting_inside_only_see_the_surfaces_ahead() {
+        // From the center the ray is inside both solids, which each cross it once
+        assert_crossings(crossings_along_x(CsgOperation::Union, 0.0), &[(1.0, 1.0)]);
+        assert_crossings(
+            crossings_along_x(CsgOperation::Intersection, 0.0),
+            &[(0.5, 1.0)],
+        );
+        // Inside the hole of the difference, so the first crossing enters the solid
+        assert_crossings(
+            crossings_along_x(CsgOperation::Difference, 0.0),
+            &[(0.5, -1.0), (1.0, 1.0)],
+        );
+    }
+
+    #[test]
+    fn bounds_of_a_difference_are_the_first_operand() {
+        let bounds = bounds(&sphere_and_cube(CsgOperation::Difference)).unwrap();
+        assert_eq!(bounds.min, Point3::new(-1.0, -1.0, -1.0));
+        assert_eq!(bounds.max, Point3::new(1.0, 1.0, 1.0));
+    }
+}
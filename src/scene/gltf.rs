@@ -0,0 +1,466 @@
+use crate::geometry::{Mesh, Primitive, DEFAULT_CREASE_ANGLE};
+use crate::scene::texture::Texture;
+use crate::scene::{Color, Light, Material, PbrMaterial, SceneNode};
+use base64::Engine;
+use gltf::camera::Projection;
+use gltf::khr_lights_punctual::Kind;
+use gltf::mesh::Mode;
+use gltf::Gltf;
+use nalgebra::{Affine3, Matrix4, Point3, Vector3};
+use std::collections::HashMap;
+use std::error::Error;
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// Directional lights are placed this far away, in the direction the light comes from
+const DIRECTIONAL_DISTANCE: f32 = 10000.0;
+
+// A camera from a gltf file, in the terms the raytracer is given its view
+#[derive(Debug, Clone)]
+pub struct GltfCamera {
+    pub name: String,
+    pub eye: Point3<f32>,
+    // A point the camera looks towards
+    pub view: Point3<f32>,
+    pub up: Vector3<f32>,
+    // Vertical field of view in degrees
+    pub fov_y: f32,
+}
+
+// Everything loaded from a gltf file. Lights and cameras are placed in the coordinates of
+// the file, so they don't follow transforms applied to the root node afterwards.
+#[derive(Debug, Clone)]
+pub struct GltfScene {
+    pub root: SceneNode,
+    pub lights: Vec<Light>,
+    pub cameras: Vec<GltfCamera>,
+}
+
+// Shared state while walking the nodes of a file
+struct Loader<'a> {
+    document: &'a gltf::Document,
+    buffers: Vec<Vec<u8>>,
+    directory: &'a Path,
+    // Meshes and materials can be used by many nodes, so they are only built once
+    primitives: HashMap<(usize, usize), Arc<Primitive>>,
+    materials: HashMap<Option<usize>, Arc<Material>>,
+    textures: HashMap<(usize, bool), Texture>,
+    next_id: u32,
+    lights: Vec<Light>,
+    cameras: Vec<GltfCamera>,
+}
+
+// Contents of a buffer or image uri, either embedded as base64 or a file next to the gltf
+fn read_uri(uri: &str, directory: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    if uri.starts_with("data:") {
+        let data = uri
+            .split(";base64,")
+            .nth(1)
+            .ok_or_else(|| format!("unsupported data uri \'{}\'", uri))?;
+        return Ok(base64::engine::general_purpose::STANDARD.decode(data)?);
+    }
+    // Spaces are the only escape seen in practice from exporters
+    let file_name = uri.replace("%20", " ");
+    Ok(fs::read(directory.join(file_name))?)
+}
+
+fn to_affine(matrix: [[f32; 4]; 4]) -> Affine3<f32> {
+    let columns: Vec<f32> = matrix.iter().flat_map(|c| c.iter().cloned()).collect();
+    Affine3::from_matrix_unchecked(Matrix4::from_column_slice(&columns))
+}
+
+impl<'a> Loader<'a> {
+    fn new_node(&mut self, name: String) -> SceneNode {
+        let node = SceneNode::new(self.next_id, name);
+        self.next_id += 1;
+        node
+    }
+
+    fn texture(&mut self, image: gltf::Image, srgb: bool) -> Result<Texture, Box<dyn Error>> {
+        let key = (image.index(), srgb);
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(texture.clone());
+        }
+        let bytes = match image.source() {
+            gltf::image::Source::View { view, .. } => {
+                let buffer = &self.buffers[view.buffer().index()];
+                buffer[view.offset()..view.offset() + view.length()].to_vec()
+            }
+            gltf::image::Source::Uri { uri, .. } => read_uri(uri, self.directory)?,
+        };
+        let decoded = image::load_from_memory(&bytes)?.to_rgb();
+        // Texture coordinates go from 0 to 1 across the image
+        let texture = Texture::from_image(&decoded, 1.0, 1.0, srgb);
+        self.textures.insert(key, texture.clone());
+        Ok(texture)
+    }
+
+    // Metallic/roughness materials map straight onto pbr materials. Primitives without a
+    // material get the default from the specification, a white rough metal.
+    fn material(&mut self, material: gltf::Material) -> Result<Arc<Material>, Box<dyn Error>> {
+        if let Some(material) = self.materials.get(&material.index()) {
+            return Ok(Arc::clone(material));
+        }
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, _] = pbr.base_color_factor();
+        let mut lucis_material = PbrMaterial::new(
+            Color::new(r, g, b),
+            pbr.metallic_factor(),
+            pbr.roughness_factor(),
+        );
+        // Only the first set of texture coordinates is loaded
+        if let Some(info) = pbr.base_color_texture().filter(|i| i.tex_coord() == 0) {
+            lucis_material.base_color_map = Some(self.texture(info.texture().source(), true)?);
+        }
+        if let Some(info) = pbr
+            .metallic_roughness_texture()
+            .filter(|i| i.tex_coord() == 0)
+        {
+            // Metallic is stored in the blue channel and roughness in green, which is where
+            // pbr materials read them from
+            let texture = self.texture(info.texture().source(), false)?;
+            lucis_material.metallic_map = Some(texture.clone());
+            lucis_material.roughness_map = Some(texture);
+        }
+        let lucis_material = Arc::new(Material::pbr(lucis_material));
+        self.materials
+            .insert(material.index(), Arc::clone(&lucis_material));
+        Ok(lucis_material)
+    }
+
+    fn primitive(
+        &mut self,
+        mesh_index: usize,
+        primitive: &gltf::Primitive,
+    ) -> Result<Option<Arc<Primitive>>, Box<dyn Error>> {
+        let key = (mesh_index, primitive.index());
+        if let Some(primitive) = self.primitives.get(&key) {
+            return Ok(Some(Arc::clone(primitive)));
+        }
+
+        let buffers = &self.buffers;
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()][..]));
+        let vertices: Vec<Vector3<f32>> = match reader.read_positions() {
+            Some(positions) => positions.map(Vector3::from).collect(),
+            None => return Ok(None),
+        };
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..vertices.len()).collect(),
+        };
+        let faces: Vec<[usize; 3]> = match primitive.mode() {
+            Mode::Triangles => indices
+                .chunks_exact(3)
+                .map(|t| [t[0], t[1], t[2]])
+                .collect(),
+            // Every other triangle of a strip is flipped to keep the winding the same
+            Mode::TriangleStrip => (0..indices.len().saturating_sub(2))
+                .map(|i| {
+                    if i % 2 == 0 {
+                        [indices[i], indices[i + 1], indices[i + 2]]
+                    } else {
+                        [indices[i + 1], indices[i], indices[i + 2]]
+                    }
+                })
+                .collect(),
+            Mode::TriangleFan => (1..indices.len().saturating_sub(1))
+                .map(|i| [indices[0], indices[i], indices[i + 1]])
+                .collect(),
+            // Points and lines have no surface to render
+            _ => return Ok(None),
+        };
+        if let Some(face) = faces
+            .iter()
+            .find(|f| f.iter().any(|&i| i >= vertices.len()))
+        {
+            return Err(format!("face {:?} uses a vertex that doesn't exist", face).into());
+        }
+
+        // Every attribute shares the vertex indexes
+        let mut mesh = Mesh::new(vertices, faces);
+        if let Some(normals) = reader.read_normals() {
+            mesh.normals = normals.map(Vector3::from).collect();
+            mesh.face_normals = mesh.faces.iter().map(|f| Some(*f)).collect();
+        }
+        if let Some(uvs) = reader.read_tex_coords(0) {
+            mesh.uvs = uvs.into_f32().collect();
+            mesh.face_uvs = mesh.faces.iter().map(|f| Some(*f)).collect();
+        }
+        if let Some(colors) = reader.read_colors(0) {
+            mesh.colors = colors.into_rgb_f32().map(Vector3::from).collect();
+        }
+        mesh.generate_normals(DEFAULT_CREASE_ANGLE);
+
+        let primitive = Arc::new(Primitive::Mesh(mesh));
+        self.primitives.insert(key, Arc::clone(&primitive));
+        Ok(Some(primitive))
+    }
+
+    fn add_light(&mut self, light: gltf::khr_lights_punctual::Light, world: &Affine3<f32>) {
+        let [r, g, b] = light.color();
+        // Intensities are in candela (or lux for directional lights), and a white surface
+        // facing a light reflects intensity / pi of it
+        let color = (light.intensity() / PI) * Color::new(r, g, b);
+        let position = world * Point3::origin();
+        let lucis_light = match light.kind() {
+            Kind::Directional => {
+                let direction = (world * -Vector3::z()).normalize();
+                let position = position - (direction * DIRECTIONAL_DISTANCE);
+                Light::new(color, position, [1.0, 0.0, 0.0], 0.0, 1)
+            }
+            // Spot lights are treated as point lights, lucis lights shine in every direction
+            Kind::Point | Kind::Spot { .. } => Light::new(color, position, [0.0, 0.0, 1.0], 0.0, 1),
+        };
+        self.lights.push(lucis_light);
+    }
+
+    fn add_camera(&mut self, camera: gltf::Camera, world: &Affine3<f32>) {
+        let fov_y = match camera.projection() {
+            Projection::Perspective(perspective) => perspective.yfov().to_degrees(),
+            Projection::Orthographic(_) => {
+                println!("Skipping orthographic camera, only perspective cameras are supported");
+                return;
+            }
+        };
+        // Cameras look down their -z axis with y up. The raytracer turns rays by the
+        // look_at_rh matrix of its view without inverting it, so the camera's axes are
+        // handed over transposed for rays to come out along them.
+        let eye = world * Point3::origin();
+        let right = (world * Vector3::x()).normalize();
+        let up = (world * Vector3::y()).normalize();
+        let back = (world * Vector3::z()).normalize();
+        self.cameras.push(GltfCamera {
+            name: camera.name().unwrap_or("camera").to_string(),
+            eye,
+            view: eye - Vector3::new(right.z, up.z, back.z),
+            up: Vector3::new(right.y, up.y, back.y),
+            fov_y,
+        });
+    }
+
+    fn node(
+        &mut self,
+        node: gltf::Node,
+        parent_world: &Affine3<f32>,
+    ) -> Result<SceneNode, Box<dyn Error>> {
+        let name = node
+            .name()
+            .map_or_else(|| format!("node {}", node.index()), String::from);
+        let mut scene_node = self.new_node(name.clone());
+        scene_node.transform = to_affine(node.transform().matrix());
+        scene_node.inv_transform = scene_node.transform.inverse();
+        let world = parent_world * scene_node.transform;
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                let lucis_primitive = match self.primitive(mesh.index(), &primitive)? {
+                    Some(lucis_primitive) => lucis_primitive,
+                    None => continue,
+                };
+                let mut child = self.new_node(format!("{} primitive {}", name, primitive.index()));
+                child.primitive = lucis_primitive;
+                child.material = self.material(primitive.material())?;
+                scene_node.add_child(child);
+            }
+        }
+        if let Some(light) = node.light() {
+            self.add_light(light, &world);
+        }
+        if let Some(camera) = node.camera() {
+            self.add_camera(camera, &world);
+        }
+        for child in node.children() {
+            let child = self.node(child, &world)?;
+            scene_node.add_child(child);
+        }
+        Ok(scene_node)
+    }
+}
+
+// Load the default scene of a gltf or glb file. Node ids are handed out starting from
+// first_id, and the id after the last one used is returned.
+pub fn load(file_name: &str, first_id: u32) -> Result<(GltfScene, u32), Box<dyn Error>> {
+    let Gltf { document, blob } = Gltf::open(file_name)?;
+    let directory = Path::new(file_name)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+
+    let mut buffers = vec![];
+    for buffer in document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => blob
+                .clone()
+                .ok_or("buffer refers to a binary chunk the file doesn't have")?,
+            gltf::buffer::Source::Uri(uri) => read_uri(uri, directory)?,
+        };
+        if data.len() < buffer.length() {
+            return Err(format!("buffer {} is shorter than its length", buffer.index()).into());
+        }
+        buffers.push(data);
+    }
+
+    let mut loader = Loader {
+        document: &document,
+        buffers,
+        directory,
+        primitives: HashMap::new(),
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+        next_id: first_id,
+        lights: vec![],
+        cameras: vec![],
+    };
+    let mut root = loader.new_node(file_name.to_string());
+    let scene = loader
+        .document
+        .default_scene()
+        .or_else(|| loader.document.scenes().next())
+        .ok_or("file has no scenes")?;
+    for node in scene.nodes() {
+        let node = loader.node(node, &Affine3::identity())?;
+        root.add_child(node);
+    }
+
+    let scene = GltfScene {
+        root,
+        lights: loader.lights,
+        cameras: loader.cameras,
+    };
+    Ok((scene, loader.next_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Ray;
+    use crate::raytrace::Camera;
+    use crate::scene::Intersect;
+    use crate::Raytracer;
+    use nalgebra::{Unit, UnitQuaternion};
+
+    // A triangle, light and camera on a node inside a translated, rotated and scaled parent
+    fn write_scene() -> String {
+        let positions: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        let buffer = base64::engine::general_purpose::STANDARD.encode(positions);
+        let half = 0.5f32.sqrt();
+        let gltf = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [
+                    {{"translation": [0, 0, -5], "rotation": [0, {half}, 0, {half}],
+                      "scale": [2, 2, 2], "children": [1]}},
+                    {{"translation": [1, 0, 0], "mesh": 0, "camera": 0,
+                      "extensions": {{"KHR_lights_punctual": {{"light": 0}}}}}}
+                ],
+                "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}}}]}}],
+                "cameras": [{{"type": "perspective", "perspective": {{"yfov": 1.0, "znear": 0.1}}}}],
+                "extensionsUsed": ["KHR_lights_punctual"],
+                "extensions": {{"KHR_lights_punctual": {{"lights": [{{"type": "point"}}]}}}},
+                "buffers": [{{"uri": "data:application/octet-stream;base64,{buffer}", "byteLength": 36}}],
+                "bufferViews": [{{"buffer": 0, "byteLength": 36}}],
+                "accessors": [{{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                                "min": [0, 0, 0], "max": [1, 1, 0]}}]
+            }}"#,
+            half = half,
+            buffer = buffer
+        );
+        write_file("scene", &gltf)
+    }
+
+    // Tests run at the same time, so each writes a file of its own
+    fn write_file(name: &str, gltf: &str) -> String {
+        let file_name =
+            std::env::temp_dir().join(format!("lucis-test-{}-{}.gltf", name, std::process::id()));
+        fs::write(&file_name, gltf).unwrap();
+        file_name.to_string_lossy().into_owned()
+    }
+
+    fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+        assert!((a - b).norm() < 1e-4, "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn node_transforms_are_composed_parent_first() {
+        let file_name = write_scene();
+        let loaded = load(&file_name, 0);
+        fs::remove_file(&file_name).unwrap();
+        let (scene, next_id) = loaded.unwrap();
+
+        // The root, both nodes and the primitive's own node
+        assert_eq!(next_id, 4);
+        // The child's origin is moved out to x = 2 by the scale, turned onto -z by the
+        // rotation and then pushed back by the translation
+        let origin = Point3::new(0.0, 0.0, -7.0);
+        assert_near(scene.lights[0].position, origin);
+        assert_near(scene.cameras[0].eye, origin);
+        assert_eq!(scene.cameras[0].fov_y, 1.0f32.to_degrees());
+
+        // The triangle is turned to face along x, so a ray down x finds it half way through
+        let ray = Ray::new(Point3::new(-5.0, 0.5, -7.5), Vector3::x());
+        let hit = scene.root.intersects(&ray).unwrap();
+        assert_near(hit.point, Point3::new(0.0, 0.5, -7.5));
+        assert!(scene
+            .root
+            .intersects(&Ray::new(Point3::new(-5.0, 0.5, -6.5), Vector3::x()))
+            .is_none());
+    }
+
+    #[test]
+    fn primary_rays_leave_down_the_cameras_negative_z() {
+        // A camera turned about an axis off all the others, inside a scaled parent
+        let axis = Unit::new_normalize(Vector3::new(1.0, 2.0, 2.0));
+        let rotation = UnitQuaternion::from_axis_angle(&axis, 1.2);
+        let q = rotation.quaternion().coords;
+        let gltf = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [
+                    {{"translation": [0, 1, 0], "scale": [3, 3, 3], "children": [1]}},
+                    {{"translation": [1, 0, 2], "rotation": [{}, {}, {}, {}], "camera": 0}}
+                ],
+                "cameras": [{{"type": "perspective", "perspective": {{"yfov": 0.8, "znear": 0.1}}}}]
+            }}"#,
+            q.x, q.y, q.z, q.w
+        );
+        let file_name = write_file("camera", &gltf);
+        let loaded = load(&file_name, 0);
+        fs::remove_file(&file_name).unwrap();
+        let (scene, _) = loaded.unwrap();
+
+        let camera = &scene.cameras[0];
+        assert_near(camera.eye, Point3::new(3.0, 1.0, 6.0));
+        let raytracer = Raytracer {
+            eye: camera.eye,
+            view: camera.view,
+            up: camera.up,
+            fov_y: camera.fov_y,
+            ..Raytracer::default()
+        };
+        let (width, height) = (200, 100);
+        let image = Camera::new(&raytracer, width, height);
+
+        let forward = rotation * -Vector3::z();
+        let up = rotation * Vector3::y();
+        let right = rotation * Vector3::x();
+        let direction = |fx: f32, fy: f32| {
+            let ray = image.ray(fx, fy);
+            assert_near(ray.src, camera.eye);
+            Point3::from(ray.dir.normalize())
+        };
+        assert_near(direction(100.0, 50.0), Point3::from(forward));
+
+        // The top and right edges of the image are half the field of view away
+        let tan = (0.8f32 / 2.0).tan();
+        let top = (forward + (up * tan)).normalize();
+        let side = (forward + (right * tan * 2.0)).normalize();
+        assert_near(direction(100.0, 0.0), Point3::from(top));
+        assert_near(direction(200.0, 50.0), Point3::from(side));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::scene::SceneNode;
+use crate::scene::{Color, Material};
 use nalgebra::{Affine3, Point3, Vector3, U3};
 use std::cmp::{Ordering, PartialEq, PartialOrd};
 
@@ -7,10 +7,14 @@ pub struct Intersection<'a> {
     // The t value for the ray where this collision occured. Can be used to calculate the intersection point
     pub t_value: f32,
     pub point: Point3<f32>,
-    pub node: &'a SceneNode,
+    pub material: &'a Material,
     pub normal: Vector3<f32>,
     pub u_value: f32,
     pub v_value: f32,
+    // Index of the triangle that was hit, for meshes
+    pub face: Option<usize>,
+    // Vertex color of a mesh, which tints the diffuse color of the material
+    pub color: Option<Color>,
 }
 
 impl<'a> PartialEq for Intersection<'a> {
@@ -29,18 +33,20 @@ impl<'a> Intersection<'a> {
     pub fn new(
         t_value: f32,
         point: Point3<f32>,
-        node: &'a SceneNode,
+        material: &'a Material,
         normal: Vector3<f32>,
         u_value: f32,
         v_value: f32,
-    ) -> Intersection {
+    ) -> Intersection<'a> {
         Intersection {
             t_value,
             point,
-            node,
+            material,
             normal,
             u_value,
             v_value,
+            face: None,
+            color: None,
         }
     }
 
@@ -56,12 +62,9 @@ impl<'a> Intersection<'a> {
         let transformed_point = transform * self.point;
         let transformed_normal = (inv_mat3_transpose * self.normal).normalize();
         Intersection {
-            t_value: self.t_value,
             point: transformed_point,
-            node: self.node,
             normal: transformed_normal,
-            u_value: self.u_value,
-            v_value: self.v_value,
+            ..self
         }
     }
 }
@@ -1,11 +1,20 @@
 use crate::geometry::volume::{BoxParams, ConeParams, Volume, VolumeEffect, VolumetricSolid};
-use crate::geometry::{Mesh, Primitive};
-use crate::scene::{Color, Light, Material, SceneNode};
-use crate::Raytracer;
+use crate::geometry::{Aabb, Heightfield, Mesh, Primitive, Sdf, SdfGrid, UvProjection};
+use crate::scene::gltf;
+use crate::scene::mtl;
+use crate::scene::texture::Texture;
+use crate::scene::{Color, Csg, CsgOperation, Light, Material, PbrMaterial, SceneNode};
+use crate::{
+    AdaptiveSampling, Backend, Filter, Integrator, Raytracer, SamplePattern, Supersampling,
+    ToneMapping, TracingOptions,
+};
 use nalgebra::{Point3, Vector3};
-use rlua::{Function, Lua, Result, Table, UserData, UserDataMethods};
+use rlua::{Error, Function, Lua, Result, Table, UserData, UserDataMethods};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::Arc;
+use std::time::Instant;
 
 fn print_node(_: &Lua, node: SceneNode) -> Result<()> {
     println!("{:#?}", node);
@@ -19,12 +28,38 @@ fn create_node(lua: &Lua, name: String) -> Result<SceneNode> {
     Ok(SceneNode::new(node_count, name))
 }
 
+// A node that instances share instead of copying, made with rt.prototype
+#[derive(Debug, Clone)]
+struct Prototype(Arc<SceneNode>);
+
+fn create_prototype(_: &Lua, node: SceneNode) -> Result<Prototype> {
+    println!("Creating new prototype from \'{}\'", node.name);
+    Ok(Prototype(Arc::new(node)))
+}
+
+fn create_instance(
+    lua: &Lua,
+    (name, prototype, material): (String, Prototype, Option<Material>),
+) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!(
+        "Creating new instance \'{}\' of \'{}\'",
+        name, prototype.0.name
+    );
+    let mut node = SceneNode::new_instance(node_count, name, prototype.0);
+    if let Some(material) = material {
+        node.material = Arc::new(material);
+    }
+    Ok(node)
+}
+
 fn create_sphere(lua: &Lua, name: String) -> Result<SceneNode> {
     let node_count: u32 = lua.globals().get("node_count")?;
     lua.globals().set("node_count", node_count + 1).unwrap();
     println!("Creating new sphere \'{}\'", name);
     let mut node = SceneNode::new(node_count, name);
-    node.primitive = Primitive::Sphere;
+    node.primitive = Arc::new(Primitive::Sphere);
     Ok(node)
 }
 
@@ -33,7 +68,7 @@ fn create_cylinder(lua: &Lua, name: String) -> Result<SceneNode> {
     lua.globals().set("node_count", node_count + 1).unwrap();
     println!("Creating new cylinder \'{}\'", name);
     let mut node = SceneNode::new(node_count, name);
-    node.primitive = Primitive::Cylinder;
+    node.primitive = Arc::new(Primitive::Cylinder);
     Ok(node)
 }
 
@@ -42,7 +77,7 @@ fn create_cone(lua: &Lua, name: String) -> Result<SceneNode> {
     lua.globals().set("node_count", node_count + 1).unwrap();
     println!("Creating new cone \'{}\'", name);
     let mut node = SceneNode::new(node_count, name);
-    node.primitive = Primitive::Cone;
+    node.primitive = Arc::new(Primitive::Cone);
     Ok(node)
 }
 
@@ -51,21 +86,293 @@ fn create_cube(lua: &Lua, name: String) -> Result<SceneNode> {
     lua.globals().set("node_count", node_count + 1).unwrap();
     println!("Creating new cube \'{}\'", name);
     let mut node = SceneNode::new(node_count, name);
-    node.primitive = Primitive::Cube;
+    node.primitive = Arc::new(Primitive::Cube);
+    Ok(node)
+}
+
+// Combine two solids into a csg node, e.g. rt.difference(cube, sphere)
+fn create_csg(
+    lua: &Lua,
+    operation: CsgOperation,
+    (a, b): (SceneNode, SceneNode),
+) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    let name = format!("{}({}, {})", operation.name(), a.name, b.name);
+    println!("Creating new csg node \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.csg = Some(Csg::new(operation, vec![a, b]));
+    Ok(node)
+}
+
+fn create_plane(lua: &Lua, name: String) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new plane \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Plane);
+    Ok(node)
+}
+
+fn create_disc(lua: &Lua, name: String) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new disc \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Disc);
+    Ok(node)
+}
+
+fn create_rectangle(lua: &Lua, name: String) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new rectangle \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Rectangle);
+    Ok(node)
+}
+
+fn create_torus(lua: &Lua, (name, radius): (String, Option<f32>)) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new torus \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Torus {
+        radius: radius.unwrap_or(0.25),
+    });
+    Ok(node)
+}
+
+fn create_capsule(
+    lua: &Lua,
+    (name, radius, height): (String, Option<f32>, Option<f32>),
+) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new capsule \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Capsule {
+        radius: radius.unwrap_or(0.5),
+        height: height.unwrap_or(1.0),
+    });
+    Ok(node)
+}
+
+// Read a {x, y, z} table
+fn table_vector(t: &Table) -> Vector3<f32> {
+    Vector3::new(
+        t.raw_get(1).unwrap(),
+        t.raw_get(2).unwrap(),
+        t.raw_get(3).unwrap(),
+    )
+}
+
+fn create_sdf(lua: &Lua, (name, shape): (String, Sdf)) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new distance field \'{}\'", name);
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Sdf(shape));
     Ok(node)
 }
 
-fn create_mesh(lua: &Lua, (name, file_name): (String, String)) -> Result<SceneNode> {
+fn create_sdf_sphere(_: &Lua, radius: f32) -> Result<Sdf> {
+    Ok(Sdf::Sphere { radius })
+}
+
+fn create_sdf_box(_: &Lua, size: Table) -> Result<Sdf> {
+    Ok(Sdf::Box {
+        half_size: table_vector(&size) / 2.0,
+    })
+}
+
+fn create_sdf_torus(_: &Lua, (ring_radius, tube_radius): (f32, f32)) -> Result<Sdf> {
+    Ok(Sdf::Torus {
+        ring_radius,
+        tube_radius,
+    })
+}
+
+fn create_sdf_smooth_union(_: &Lua, (a, b, smoothness): (Sdf, Sdf, f32)) -> Result<Sdf> {
+    Ok(Sdf::SmoothUnion {
+        a: Box::new(a),
+        b: Box::new(b),
+        smoothness,
+    })
+}
+
+fn create_sdf_repeat(_: &Lua, (shape, period): (Sdf, Table)) -> Result<Sdf> {
+    Ok(Sdf::Repeat {
+        shape: Box::new(shape),
+        period: table_vector(&period),
+    })
+}
+
+fn create_sdf_translate(_: &Lua, (shape, offset): (Sdf, Table)) -> Result<Sdf> {
+    Ok(Sdf::Translate {
+        shape: Box::new(shape),
+        offset: table_vector(&offset),
+    })
+}
+
+// Lua can't be called while rendering, so the function is sampled onto a grid up front,
+// e.g. rt.sdf_function(f, { min = {-1, -1, -1}, max = {1, 1, 1}, resolution = 48 })
+fn create_sdf_function(_: &Lua, (function, options): (Function, Option<Table>)) -> Result<Sdf> {
+    let mut min = Vector3::new(-1.0, -1.0, -1.0);
+    let mut max = Vector3::new(1.0, 1.0, 1.0);
+    let mut resolution = 48;
+    if let Some(options) = options {
+        if let Some(t) = options.get::<_, Option<Table>>("min")? {
+            min = table_vector(&t);
+        }
+        if let Some(t) = options.get::<_, Option<Table>>("max")? {
+            max = table_vector(&t);
+        }
+        if let Some(r) = options.get::<_, Option<usize>>("resolution")? {
+            resolution = r;
+        }
+    }
+
+    println!(
+        "Sampling distance function at {} points",
+        resolution * resolution * resolution
+    );
+    let mut error = None;
+    let grid = SdfGrid::sample(
+        Aabb::new(Point3::from(min), Point3::from(max)),
+        resolution,
+        |p| match function.call::<_, f32>((p.x, p.y, p.z)) {
+            Ok(distance) => distance,
+            Err(e) => {
+                error.get_or_insert(e);
+                0.0
+            }
+        },
+    );
+    match error {
+        Some(e) => Err(e),
+        None => Ok(Sdf::Grid(grid)),
+    }
+}
+
+fn create_heightfield(
+    lua: &Lua,
+    (name, file_name, size): (String, String, Table),
+) -> Result<SceneNode> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    lua.globals().set("node_count", node_count + 1).unwrap();
+    println!("Creating new heightfield \'{}\' from {}", name, file_name);
+    let heightfield = Heightfield::load(&file_name, table_vector(&size)).map_err(|e| {
+        Error::RuntimeError(format!(
+            "Failed to load heightfield \'{}\': {}",
+            file_name, e
+        ))
+    })?;
+    println!(
+        "Loaded heightfield \'{}\' with {}x{} samples",
+        file_name, heightfield.width, heightfield.depth
+    );
+    let mut node = SceneNode::new(node_count, name);
+    node.primitive = Arc::new(Primitive::Heightfield(heightfield));
+    Ok(node)
+}
+
+fn create_mesh(
+    lua: &Lua,
+    (name, file_name, options): (String, String, Option<Table>),
+) -> Result<SceneNode> {
     let node_count: u32 = lua.globals().get("node_count")?;
     lua.globals().set("node_count", node_count + 1).unwrap();
     println!("Creating new mesh({}) \'{}\'", file_name, name);
     let mut node = SceneNode::new(node_count, name);
-    // TODO: Better error handling
-    node.primitive = Primitive::Mesh(Mesh::from_file(file_name.as_ref()).unwrap());
+    // Subdivision has to happen as the mesh is loaded, before its normals are generated
+    let (levels, crease) = match options {
+        Some(ref options) => (
+            options.get::<_, Option<usize>>("subdivide")?.unwrap_or(0),
+            options.get::<_, Option<f32>>("crease")?,
+        ),
+        None => (0, None),
+    };
+    let start = Instant::now();
+    let mut mesh = Mesh::from_file_subdivided(file_name.as_ref(), levels, crease).map_err(|e| {
+        Error::RuntimeError(format!("Failed to load mesh \'{}\': {}", file_name, e))
+    })?;
+    if let Some(options) = options {
+        if let Some(projection) = options.get::<_, Option<String>>("projection")? {
+            mesh.projection = UvProjection::from_name(&projection).ok_or_else(|| {
+                Error::RuntimeError(format!("Unknown uv projection \'{}\'", projection))
+            })?;
+        }
+    }
+    println!(
+        "Loaded mesh \'{}\' with {} faces ({} groups) and {} BVH nodes in {:?}",
+        file_name,
+        mesh.faces.len(),
+        mesh.groups.len(),
+        mesh.bvh.node_count(),
+        start.elapsed()
+    );
+
+    // Materials from the obj file's libraries, set_material replaces them all
+    if !mesh.material_libraries.is_empty() {
+        let mut library = HashMap::new();
+        for library_file in mesh.material_libraries.iter() {
+            let materials = mtl::load_library(library_file).map_err(|e| {
+                Error::RuntimeError(format!(
+                    "Failed to load material library '{}': {}",
+                    library_file, e
+                ))
+            })?;
+            library.extend(materials);
+        }
+        println!(
+            "Loaded {} materials for mesh '{}'",
+            library.len(),
+            file_name
+        );
+        node.material = Arc::new(mtl::face_materials(&mesh.material_groups, &library));
+    }
+    node.primitive = Arc::new(Primitive::Mesh(mesh));
     Ok(node)
 }
 
-fn create_material(_: &Lua, (d, s, p): (Table, Table, f32)) -> Result<Material> {
+// Load a gltf or glb file, returning its scene as a node along with tables of its lights
+// and cameras. Cameras are tables of eye, view, up and fov, the arguments rt.render takes.
+fn create_gltf<'lua>(
+    lua: &'lua Lua,
+    file_name: String,
+) -> Result<(SceneNode, Table<'lua>, Table<'lua>)> {
+    let node_count: u32 = lua.globals().get("node_count")?;
+    println!("Loading gltf scene \'{}\'", file_name);
+    let (scene, next_id) = gltf::load(&file_name, node_count).map_err(|e| {
+        Error::RuntimeError(format!("Failed to load gltf \'{}\': {}", file_name, e))
+    })?;
+    lua.globals().set("node_count", next_id).unwrap();
+    println!(
+        "Loaded gltf scene \'{}\' with {} lights and {} cameras",
+        file_name,
+        scene.lights.len(),
+        scene.cameras.len()
+    );
+
+    let lights = lua.create_sequence_from(scene.lights)?;
+    let cameras = lua.create_table()?;
+    for (i, camera) in scene.cameras.iter().enumerate() {
+        let table = lua.create_table()?;
+        table.set("name", camera.name.clone())?;
+        table.set("eye", vec![camera.eye.x, camera.eye.y, camera.eye.z])?;
+        table.set("view", vec![camera.view.x, camera.view.y, camera.view.z])?;
+        table.set("up", vec![camera.up.x, camera.up.y, camera.up.z])?;
+        table.set("fov", camera.fov_y)?;
+        cameras.set(i + 1, table)?;
+    }
+    Ok((scene.root, lights, cameras))
+}
+
+fn create_material(
+    _: &Lua,
+    (d, s, p, reflectivity): (Table, Table, f32, Option<f32>),
+) -> Result<Material> {
     let dr: f32 = d.raw_get(1).unwrap();
     let dg: f32 = d.raw_get(2).unwrap();
     let db: f32 = d.raw_get(3).unwrap();
@@ -77,12 +384,13 @@ fn create_material(_: &Lua, (d, s, p): (Table, Table, f32)) -> Result<Material>
         Color::new(dr, dg, db),
         Color::new(sr, sg, sb),
         p,
+        reflectivity.unwrap_or(0.0),
     ))
 }
 
 fn create_textured_material(
     _: &Lua,
-    (file_name, u_max, v_max, s, p): (String, f32, f32, Table, f32),
+    (file_name, u_max, v_max, s, p, reflectivity): (String, f32, f32, Table, f32, Option<f32>),
 ) -> Result<Material> {
     let sr: f32 = s.raw_get(1).unwrap();
     let sg: f32 = s.raw_get(2).unwrap();
@@ -94,9 +402,55 @@ fn create_textured_material(
         v_max,
         Color::new(sr, sg, sb),
         p,
+        reflectivity.unwrap_or(0.0),
     ))
 }
 
+fn create_glass_material(_: &Lua, (ior, tint): (f32, Option<Table>)) -> Result<Material> {
+    let tint = match tint {
+        Some(t) => Color::new(
+            t.raw_get(1).unwrap(),
+            t.raw_get(2).unwrap(),
+            t.raw_get(3).unwrap(),
+        ),
+        None => Color::new(1.0, 1.0, 1.0),
+    };
+    Ok(Material::dielectric(ior, tint))
+}
+
+fn create_pbr_material(
+    _: &Lua,
+    (c, metallic, roughness, maps): (Table, f32, f32, Option<Table>),
+) -> Result<Material> {
+    let base_color = Color::new(
+        c.raw_get(1).unwrap(),
+        c.raw_get(2).unwrap(),
+        c.raw_get(3).unwrap(),
+    );
+    let mut material = PbrMaterial::new(base_color, metallic, roughness);
+
+    if let Some(maps) = maps {
+        let u_max = maps.get::<_, Option<f32>>("u_max")?.unwrap_or(1.0);
+        let v_max = maps.get::<_, Option<f32>>("v_max")?.unwrap_or(1.0);
+        // Only the base color map holds colors, the others are linear data
+        let load = |key: &str, srgb: bool| -> Result<Option<Texture>> {
+            let file_name = maps.get::<_, Option<String>>(key)?;
+            Ok(file_name.map(|f| {
+                if srgb {
+                    Texture::load_texture(&f, u_max, v_max)
+                } else {
+                    Texture::load_linear_texture(&f, u_max, v_max)
+                }
+            }))
+        };
+        material.base_color_map = load("base_color", true)?;
+        material.metallic_map = load("metallic", false)?;
+        material.roughness_map = load("roughness", false)?;
+    }
+
+    Ok(Material::pbr(material))
+}
+
 fn create_light(_: &Lua, (p, c, a): (Table, Table, Table)) -> Result<Light> {
     let px: f32 = p.raw_get(1).unwrap();
     let py: f32 = p.raw_get(2).unwrap();
@@ -179,21 +533,98 @@ fn create_volume_cone(_: &Lua, (p, scale_y, r): (Table, f32, Table)) -> Result<V
     Ok(VolumetricSolid::new(volume, effect))
 }
 
-fn render(
-    _: &Lua,
-    (node, file_name, width, height, eye, view, up, fov, ambient_light, lights, volumes): (
-        SceneNode,
-        String,
-        u32,
-        u32,
-        Table,
-        Table,
-        Table,
-        f32,
-        Table,
-        Table,
-        Table,
-    ),
+// Read the supersampling settings out of the render options table, e.g.
+// { samples = 16, pattern = 'halton', filter = 'mitchell' }
+fn parse_supersampling(options: &Table) -> Result<Supersampling> {
+    let mut supersampling = Supersampling::default();
+    if let Some(samples) = options.get::<_, Option<u32>>("samples")? {
+        supersampling.samples = samples;
+    }
+    if let Some(pattern) = options.get::<_, Option<String>>("pattern")? {
+        supersampling.pattern = SamplePattern::from_name(&pattern).ok_or_else(|| {
+            Error::RuntimeError(format!("Unknown sample pattern \'{}\'", pattern))
+        })?;
+    }
+    if let Some(filter) = options.get::<_, Option<String>>("filter")? {
+        supersampling.filter = Filter::from_name(&filter)
+            .ok_or_else(|| Error::RuntimeError(format!("Unknown filter \'{}\'", filter)))?;
+    }
+    // { adaptive = { threshold = 0.1, depth = 3, heatmap = 'samples.png' } }
+    if let Some(adaptive_options) = options.get::<_, Option<Table>>("adaptive")? {
+        let mut adaptive = AdaptiveSampling::default();
+        if let Some(threshold) = adaptive_options.get::<_, Option<f32>>("threshold")? {
+            adaptive.threshold = threshold;
+        }
+        if let Some(depth) = adaptive_options.get::<_, Option<u32>>("depth")? {
+            adaptive.max_depth = depth;
+        }
+        adaptive.heatmap = adaptive_options.get("heatmap")?;
+        supersampling.adaptive = Some(adaptive);
+    }
+    Ok(supersampling)
+}
+
+// Read the optional table passed to rt.render, e.g.
+// { samples = 4, shadows = false, textures = true, threads = 8, max_depth = 5, backend = 'rayon' }
+fn parse_tracing_options(options: &Table) -> Result<TracingOptions> {
+    let mut tracing_options = TracingOptions {
+        supersampling: parse_supersampling(options)?,
+        ..Default::default()
+    };
+    if let Some(shadows) = options.get::<_, Option<bool>>("shadows")? {
+        tracing_options.shadow_rays = shadows;
+    }
+    if let Some(textures) = options.get::<_, Option<bool>>("textures")? {
+        tracing_options.texture_mapping = textures;
+    }
+    if let Some(threads) = options.get::<_, Option<usize>>("threads")? {
+        tracing_options.num_threads = threads;
+    }
+    if let Some(max_depth) = options.get::<_, Option<u32>>("max_depth")? {
+        tracing_options.max_depth = max_depth;
+    }
+    if let Some(backend) = options.get::<_, Option<String>>("backend")? {
+        tracing_options.backend = Backend::from_name(&backend)
+            .ok_or_else(|| Error::RuntimeError(format!("Unknown backend \'{}\'", backend)))?;
+    }
+    if let Some(exposure) = options.get::<_, Option<f32>>("exposure")? {
+        tracing_options.display.exposure = exposure;
+    }
+    if let Some(tonemap) = options.get::<_, Option<String>>("tonemap")? {
+        tracing_options.display.tone_mapping =
+            ToneMapping::from_name(&tonemap).ok_or_else(|| {
+                Error::RuntimeError(format!("Unknown tone mapping operator \'{}\'", tonemap))
+            })?;
+    }
+    if let Some(srgb) = options.get::<_, Option<bool>>("srgb")? {
+        tracing_options.display.srgb = srgb;
+    }
+    if let Some(integrator) = options.get::<_, Option<String>>("integrator")? {
+        tracing_options.integrator = Integrator::from_name(&integrator)
+            .ok_or_else(|| Error::RuntimeError(format!("Unknown integrator \'{}\'", integrator)))?;
+    }
+    Ok(tracing_options)
+}
+
+// Arguments to rt.render, the volumes and options tables are optional
+type RenderArgs<'lua> = (
+    SceneNode,
+    String,
+    u32,
+    u32,
+    Table<'lua>,
+    Table<'lua>,
+    Table<'lua>,
+    f32,
+    Table<'lua>,
+    Table<'lua>,
+    Option<Table<'lua>>,
+    Option<Table<'lua>>,
+);
+
+fn render<'lua>(
+    _: &'lua Lua,
+    (node, file_name, width, height, eye, view, up, fov, ambient_light, lights, volumes, options): RenderArgs<'lua>,
 ) -> Result<()> {
     let mut lights_vec: Vec<Light> = Vec::new();
     for i in 1..=lights.raw_len() {
@@ -201,9 +632,16 @@ fn render(
     }
 
     let mut volumes_vec: Vec<VolumetricSolid> = Vec::new();
-    for i in 1..=volumes.raw_len() {
-        volumes_vec.push(volumes.raw_get(i).unwrap());
+    if let Some(volumes) = volumes {
+        for i in 1..=volumes.raw_len() {
+            volumes_vec.push(volumes.raw_get(i).unwrap());
+        }
     }
+
+    let tracing_options = match options {
+        Some(options) => parse_tracing_options(&options)?,
+        None => TracingOptions::default(),
+    };
     //    if ADD_VOLUMES {
     //        volumes.push(VolumetricSolid::new(
     //            Volume::Box(BoxParams {
@@ -223,7 +661,7 @@ fn render(
     //            VolumeEffect::Light(Color::new(0.5, 0.4, 0.2)),
     //        ));
     //    }
-    let raytracer = Raytracer {
+    let mut raytracer = Raytracer {
         root_node: node,
         eye: Point3::new(
             eye.raw_get(1).unwrap(),
@@ -248,9 +686,10 @@ fn render(
         ),
         lights: lights_vec,
         volumes: volumes_vec,
+        ..Default::default()
     };
     println!("Rendering {}", file_name);
-    raytracer.render(file_name.as_ref(), width, height);
+    raytracer.render(file_name.as_ref(), width, height, tracing_options);
     Ok(())
 }
 
@@ -267,6 +706,10 @@ impl UserData for VolumeEffect {}
 
 impl UserData for Material {}
 
+impl UserData for Prototype {}
+
+impl UserData for Sdf {}
+
 impl UserData for Light {
     fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method_mut("set_soft", |_, light, (radius, samples): (f32, u32)| {
@@ -283,7 +726,7 @@ impl UserData for SceneNode {
             Ok(())
         });
         methods.add_method_mut("set_material", |_, node, material| {
-            node.material = material;
+            node.material = Arc::new(material);
             Ok(())
         });
         methods.add_method_mut("scale", |_, node, (x, y, z): (f32, f32, f32)| {
@@ -315,8 +758,69 @@ pub fn run_lua_script(file_name: &str) {
         ("cone", lua.create_function(create_cone).unwrap()),
         // Create a cube node
         ("cube", lua.create_function(create_cube).unwrap()),
+        // Create an infinite plane node
+        ("plane", lua.create_function(create_plane).unwrap()),
+        // Create a disc node
+        ("disc", lua.create_function(create_disc).unwrap()),
+        // Create a rectangle node
+        ("rectangle", lua.create_function(create_rectangle).unwrap()),
+        // Create a torus node
+        ("torus", lua.create_function(create_torus).unwrap()),
+        // Create a capsule node
+        ("capsule", lua.create_function(create_capsule).unwrap()),
+        // Create a distance field node, from the rt.sdf_* shapes below
+        ("sdf", lua.create_function(create_sdf).unwrap()),
+        (
+            "sdf_sphere",
+            lua.create_function(create_sdf_sphere).unwrap(),
+        ),
+        ("sdf_box", lua.create_function(create_sdf_box).unwrap()),
+        ("sdf_torus", lua.create_function(create_sdf_torus).unwrap()),
+        (
+            "sdf_smooth_union",
+            lua.create_function(create_sdf_smooth_union).unwrap(),
+        ),
+        (
+            "sdf_repeat",
+            lua.create_function(create_sdf_repeat).unwrap(),
+        ),
+        (
+            "sdf_translate",
+            lua.create_function(create_sdf_translate).unwrap(),
+        ),
+        (
+            "sdf_function",
+            lua.create_function(create_sdf_function).unwrap(),
+        ),
+        // Create a heightfield node from a grayscale image
+        (
+            "heightfield",
+            lua.create_function(create_heightfield).unwrap(),
+        ),
         // Create a mesh node
         ("mesh", lua.create_function(create_mesh).unwrap()),
+        // Share a node between instances
+        ("prototype", lua.create_function(create_prototype).unwrap()),
+        // Create an instance of a prototype
+        ("instance", lua.create_function(create_instance).unwrap()),
+        // Combine two nodes into a csg union, intersection or difference
+        (
+            "union",
+            lua.create_function(|lua, args| create_csg(lua, CsgOperation::Union, args))
+                .unwrap(),
+        ),
+        (
+            "intersection",
+            lua.create_function(|lua, args| create_csg(lua, CsgOperation::Intersection, args))
+                .unwrap(),
+        ),
+        (
+            "difference",
+            lua.create_function(|lua, args| create_csg(lua, CsgOperation::Difference, args))
+                .unwrap(),
+        ),
+        // Load a gltf scene
+        ("gltf", lua.create_function(create_gltf).unwrap()),
         // Create a new material
         ("material", lua.create_function(create_material).unwrap()),
         // Create a new textured material
@@ -324,6 +828,16 @@ pub fn run_lua_script(file_name: &str) {
             "textured_material",
             lua.create_function(create_textured_material).unwrap(),
         ),
+        // Create a new physically based material
+        (
+            "pbr_material",
+            lua.create_function(create_pbr_material).unwrap(),
+        ),
+        // Create a new transparent material
+        (
+            "glass_material",
+            lua.create_function(create_glass_material).unwrap(),
+        ),
         (
             "effect_fog",
             lua.create_function(create_effect_fog).unwrap(),
@@ -0,0 +1,599 @@
+use crate::geometry::Ray;
+use crate::sampling::cosine_hemisphere;
+use crate::scene::pbr::{PbrMaterial, PbrSurface};
+use crate::scene::texture::Texture;
+use crate::scene::{Color, Intersection, Light};
+use crate::Raytracer;
+use nalgebra::{clamp, Point3, Vector3};
+use rand::{thread_rng, Rng};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// Distance secondary rays are moved off of a surface so they don't hit it again
+const REFLECTION_EPS: f32 = 0.001;
+
+// Used for meshes with vertex colors that weren't given a material, so the colors are
+// still shown as a plain diffuse surface
+static VERTEX_COLOR_MATERIAL: Material = Material::PhongMaterial {
+    kd: Color::new(1.0, 1.0, 1.0),
+    ks: Color::new(0.0, 0.0, 0.0),
+    shininess: 0.0,
+    reflectivity: 0.0,
+};
+
+#[derive(Debug, Clone)]
+pub enum Material {
+    PhongMaterial {
+        kd: Color,
+        ks: Color,
+        shininess: f32,
+        // How much of the color comes from mirror reflection, from 0 (none) to 1 (a mirror)
+        reflectivity: f32,
+    },
+    PhongTexture {
+        ks: Color,
+        shininess: f32,
+        texture: Texture,
+        reflectivity: f32,
+    },
+    // A transparent material such as glass or water
+    Dielectric {
+        // Index of refraction, 1.5 for glass and 1.33 for water
+        ior: f32,
+        // Color of light that has passed through the material, applied as it goes in
+        tint: Color,
+    },
+    // Physically based metallic/roughness material
+    Pbr(PbrMaterial),
+    // Separate materials for ranges of faces in a mesh, such as the materials from an obj
+    // material library. Faces outside every range use the default.
+    FaceMaterials {
+        ranges: Vec<FaceMaterial>,
+        default: Box<Material>,
+    },
+    // Another material with its normals bent by the slope of a height map
+    Bump {
+        material: Box<Material>,
+        map: Texture,
+        strength: f32,
+    },
+    None,
+}
+
+// A material used by the faces from start up to (but not including) end
+#[derive(Debug, Clone)]
+pub struct FaceMaterial {
+    pub start: usize,
+    pub end: usize,
+    pub material: Arc<Material>,
+}
+
+// Tilt a normal by the slope of a height map. The texture coordinates of meshes don't come
+// with tangents, so the u direction is approximated by the x axis (or the z axis when
+// looking straight down it) projected onto the surface, which matches the planar
+// coordinates meshes fall back to. Texture coordinates run down the image, so v increases
+// against the bitangent.
+fn bump_normal(map: &Texture, strength: f32, intersect: &Intersection) -> Vector3<f32> {
+    let n = intersect.normal.normalize();
+    let axis = if n.x.abs() < 0.9 {
+        Vector3::x()
+    } else {
+        Vector3::z()
+    };
+    let tangent = (axis - (n.dot(&axis) * n)).normalize();
+    let bitangent = n.cross(&tangent);
+    let (du, dv) = map.gradient(intersect.u_value, intersect.v_value);
+    (n - (strength * ((du * tangent) - (dv * bitangent)))).normalize()
+}
+
+// Normal at an intersection, facing towards the side the ray came from
+fn facing_normal(ray: &Ray, intersect: &Intersection) -> Vector3<f32> {
+    let n = intersect.normal.normalize();
+    if n.dot(&ray.dir) > 0.0 {
+        -n
+    } else {
+        n
+    }
+}
+
+// Whether nothing blocks the straight line from a point to a light sample
+fn light_visible(raytracer: &Raytracer, origin: Point3<f32>, sample: Point3<f32>) -> bool {
+    let shadow_ray = Ray::new_from_points(origin, sample);
+    match raytracer.intersects(&shadow_ray) {
+        // Objects behind the light don't cast shadows
+        Some(hit) => hit.t_value >= (sample - origin).norm(),
+        None => true,
+    }
+}
+
+// Diffuse and specular light reaching a point from a single light, ignoring shadows
+fn phong_light(
+    kd: &Color,
+    ks: &Color,
+    shininess: f32,
+    light: &Light,
+    point: &Point3<f32>,
+    n: &Vector3<f32>,
+    v: &Vector3<f32>,
+) -> Color {
+    let mut l = light.position - point;
+    let l_norm = l.norm();
+    l = l.normalize();
+
+    let ldotn = clamp(l.dot(n), 0.0f32, 1.0f32);
+    let r = ((2.0f32 * ldotn * n) - l).normalize();
+    let rdotv = clamp(r.dot(v), 0.0f32, 1.0f32);
+    let attenuation =
+        light.falloff[0] + (light.falloff[1] * l_norm) + (light.falloff[2] * l_norm * l_norm);
+    let light_sum = (kd * ldotn * light.color) + (ks * rdotv.powf(shininess) * light.color);
+    light_sum / attenuation
+}
+
+// Light reaching a point from every light, scaled by how much of each light is visible.
+// The direct lighting tracer casts a shadow ray to every sample of a light, while the path
+// tracer picks a single random sample per light for next event estimation.
+fn sum_lights<F>(
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+    all_samples: bool,
+    light_fn: F,
+) -> Color
+where
+    F: Fn(&Light, &Vector3<f32>, &Vector3<f32>) -> Color,
+{
+    // Reflected rays don't start at the eye, so view from the ray source
+    let v = -ray.dir.normalize();
+    // Surfaces are lit from whichever side they are viewed from
+    let n = facing_normal(ray, intersect);
+    // Start shadow rays just off of the surface so they can't hit it
+    let shadow_origin = intersect.point + (REFLECTION_EPS * n);
+    let mut rng = thread_rng();
+
+    let mut final_color = Color::new(0.0, 0.0, 0.0);
+    for light in raytracer.lights.iter() {
+        let shadow_multiplier = if !raytracer.options.shadow_rays {
+            1.0
+        } else if all_samples {
+            let shadow_rays_hit = light
+                .light_samples
+                .iter()
+                .filter(|p| light_visible(raytracer, shadow_origin, **p))
+                .count();
+            shadow_rays_hit as f32 / light.num_samples as f32
+        } else {
+            let sample = light.light_samples[rng.gen_range(0, light.light_samples.len())];
+            if light_visible(raytracer, shadow_origin, sample) {
+                1.0
+            } else {
+                0.0
+            }
+        };
+        if shadow_multiplier <= 0.0 {
+            continue;
+        }
+
+        final_color = final_color + (shadow_multiplier * light_fn(light, &n, &v));
+    }
+    final_color
+}
+
+fn calculate_phong_lighting(
+    kd: &Color,
+    ks: &Color,
+    shininess: f32,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+) -> Color {
+    let point = intersect.point;
+    let lights = sum_lights(ray, raytracer, intersect, true, |light, n, v| {
+        phong_light(kd, ks, shininess, light, &point, n, v)
+    });
+    (*kd * raytracer.ambient) + lights
+}
+
+// Light reaching a point on a microfacet surface from a single light, ignoring shadows.
+// Lights use the same units as phong lighting, so a white diffuse surface facing a light
+// is lit by exactly the light color.
+fn pbr_light(
+    surface: &PbrSurface,
+    light: &Light,
+    point: &Point3<f32>,
+    n: &Vector3<f32>,
+    v: &Vector3<f32>,
+) -> Color {
+    let l = light.position - point;
+    let l_norm = l.norm();
+    let attenuation =
+        light.falloff[0] + (light.falloff[1] * l_norm) + (light.falloff[2] * l_norm * l_norm);
+    let reflected = surface
+        .eval(n, v, &(l / l_norm))
+        .component_mul(&light.color.as_vector());
+    Color::from_vector(&(reflected * (PI / attenuation)))
+}
+
+// Material parameters at an intersection, with the base color tinted by vertex colors
+fn pbr_surface(
+    material: &PbrMaterial,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+) -> PbrSurface {
+    let mut surface = material.surface(
+        intersect.u_value,
+        intersect.v_value,
+        raytracer.options.texture_mapping,
+    );
+    if let Some(color) = intersect.color {
+        surface.base_color = surface.base_color.component_mul(&color.as_vector());
+    }
+    surface
+}
+
+fn calculate_pbr_lighting(
+    material: &PbrMaterial,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+    depth: u32,
+) -> Color {
+    let surface = pbr_surface(material, raytracer, intersect);
+    let point = intersect.point;
+    let lights = sum_lights(ray, raytracer, intersect, true, |light, n, v| {
+        pbr_light(&surface, light, &point, n, v)
+    });
+    let local_color = (&raytracer.ambient * &surface.base_color) + lights;
+
+    // Smooth surfaces reflect the scene around them, which the lights alone can't show
+    let smoothness = surface.smoothness();
+    if smoothness <= 0.0 || depth >= raytracer.options.max_depth {
+        return local_color;
+    }
+    let n = facing_normal(ray, intersect);
+    let dir = ray.dir.normalize();
+    let reflected_dir = dir - (2.0 * dir.dot(&n) * n);
+    let reflected_ray = Ray::new(point + (REFLECTION_EPS * n), reflected_dir);
+    let reflected_color = raytracer.trace_secondary_ray(&reflected_ray, depth + 1);
+    let weight = surface.fresnel(-dir.dot(&n)) * smoothness;
+    local_color + (&reflected_color * &weight)
+}
+
+// Continue a path from a microfacet surface
+fn scatter_pbr(
+    material: &PbrMaterial,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+) -> Option<(Ray, Color)> {
+    let surface = pbr_surface(material, raytracer, intersect);
+    let n = facing_normal(ray, intersect);
+    let (dir, weight) = surface.sample(&n, &-ray.dir.normalize())?;
+    let origin = intersect.point + (REFLECTION_EPS * n);
+    Some((Ray::new(origin, dir), Color::from_vector(&weight)))
+}
+
+// Continue a path from a phong surface, either in the mirror direction or in a cosine
+// weighted direction around the normal
+fn scatter_phong(
+    kd: &Color,
+    reflectivity: f32,
+    ray: &Ray,
+    intersect: &Intersection,
+) -> Option<(Ray, Color)> {
+    let n = facing_normal(ray, intersect);
+    let origin = intersect.point + (REFLECTION_EPS * n);
+    if thread_rng().gen::<f32>() < reflectivity {
+        let dir = ray.dir.normalize();
+        let reflected_dir = dir - (2.0 * dir.dot(&n) * n);
+        Some((Ray::new(origin, reflected_dir), Color::new(1.0, 1.0, 1.0)))
+    } else {
+        // The cosine term and pdf cancel out, leaving only the diffuse color
+        Some((Ray::new(origin, cosine_hemisphere(&n)), *kd))
+    }
+}
+
+// Blend the local color with the color seen in the mirror direction
+fn apply_reflection(
+    local_color: Color,
+    reflectivity: f32,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+    depth: u32,
+) -> Color {
+    if reflectivity <= 0.0 || depth >= raytracer.options.max_depth {
+        return local_color;
+    }
+
+    let dir = ray.dir.normalize();
+    let n = facing_normal(ray, intersect);
+    let reflected_dir = dir - (2.0 * dir.dot(&n) * n);
+    let reflected_ray = Ray::new(intersect.point + (REFLECTION_EPS * n), reflected_dir);
+    let reflected_color = raytracer.trace_secondary_ray(&reflected_ray, depth + 1);
+
+    ((1.0 - reflectivity) * local_color) + (reflectivity * reflected_color)
+}
+
+// Schlick's approximation of the fresnel reflectance
+fn schlick(cos_theta: f32, n1: f32, n2: f32) -> f32 {
+    let r0 = ((n1 - n2) / (n1 + n2)).powi(2);
+    r0 + ((1.0 - r0) * (1.0 - cos_theta).powi(5))
+}
+
+// Rays leaving a transparent surface along with the fresnel reflectance. There is no
+// refracted ray under total internal reflection. Rays hitting the back of the surface are
+// treated as leaving the material.
+fn dielectric_rays(ior: f32, ray: &Ray, intersect: &Intersection) -> (Ray, Option<Ray>, f32) {
+    let dir = ray.dir.normalize();
+    let mut n = intersect.normal.normalize();
+    let mut cos_i = -dir.dot(&n);
+    let (n1, n2) = if cos_i < 0.0 {
+        // Leaving the material, flip the normal to face the incoming ray
+        n = -n;
+        cos_i = -cos_i;
+        (ior, 1.0)
+    } else {
+        (1.0, ior)
+    };
+
+    let reflected_dir = dir + (2.0 * cos_i * n);
+    let reflected_ray = Ray::new(intersect.point + (REFLECTION_EPS * n), reflected_dir);
+
+    let eta = n1 / n2;
+    let sin2_t = eta * eta * (1.0 - (cos_i * cos_i));
+    if sin2_t > 1.0 {
+        // Total internal reflection
+        return (reflected_ray, None, 1.0);
+    }
+
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let reflectance = schlick(if n1 > n2 { cos_t } else { cos_i }, n1, n2);
+    let refracted_dir = (eta * dir) + (((eta * cos_i) - cos_t) * n);
+    let refracted_ray = Ray::new(intersect.point - (REFLECTION_EPS * n), refracted_dir);
+    (reflected_ray, Some(refracted_ray), reflectance)
+}
+
+// Weight of light refracted through a transparent surface. Light is tinted once as it
+// passes into the material and not again on the way out, so a solid object isn't tinted
+// twice.
+fn transmitted_tint(tint: &Color, ray: &Ray, intersect: &Intersection) -> Color {
+    if ray.dir.dot(&intersect.normal) < 0.0 {
+        *tint
+    } else {
+        Color::new(1.0, 1.0, 1.0)
+    }
+}
+
+// Color of a transparent surface, combining the reflected and refracted rays using the
+// fresnel reflectance
+fn calculate_dielectric(
+    ior: f32,
+    tint: &Color,
+    ray: &Ray,
+    raytracer: &Raytracer,
+    intersect: &Intersection,
+    depth: u32,
+) -> Color {
+    if depth >= raytracer.options.max_depth {
+        return Color::new(0.0, 0.0, 0.0);
+    }
+
+    let (reflected_ray, refracted_ray, reflectance) = dielectric_rays(ior, ray, intersect);
+    let reflected_color = raytracer.trace_secondary_ray(&reflected_ray, depth + 1);
+    match refracted_ray {
+        Some(refracted_ray) => {
+            let refracted_color = raytracer.trace_secondary_ray(&refracted_ray, depth + 1);
+            let tint = transmitted_tint(tint, ray, intersect);
+            (reflectance * reflected_color) + ((1.0 - reflectance) * (tint * refracted_color))
+        }
+        None => reflected_color,
+    }
+}
+
+impl Material {
+    pub fn phong(kd: Color, ks: Color, shininess: f32, reflectivity: f32) -> Material {
+        Material::PhongMaterial {
+            kd,
+            ks,
+            shininess,
+            reflectivity,
+        }
+    }
+
+    pub fn phong_texture(
+        file_name: &str,
+        u_max: f32,
+        v_max: f32,
+        ks: Color,
+        shininess: f32,
+        reflectivity: f32,
+    ) -> Material {
+        Material::PhongTexture {
+            texture: Texture::load_texture(file_name, u_max, v_max),
+            ks,
+            shininess,
+            reflectivity,
+        }
+    }
+
+    pub fn dielectric(ior: f32, tint: Color) -> Material {
+        Material::Dielectric { ior, tint }
+    }
+
+    pub fn pbr(material: PbrMaterial) -> Material {
+        Material::Pbr(material)
+    }
+
+    // Materials that wrap other materials, and vertex colors without a material, resolved
+    // to the material that shades the intersection and the intersection it shades
+    fn resolve<'b>(
+        &self,
+        raytracer: &Raytracer,
+        intersect: &Intersection<'b>,
+    ) -> Option<(&Material, Intersection<'b>)> {
+        match self {
+            Material::FaceMaterials { ranges, default } => {
+                let material = intersect
+                    .face
+                    .and_then(|face| {
+                        // Ranges are in face order, so the range ending after the face is
+                        // the only one which can hold it
+                        let i = ranges.partition_point(|range| range.end <= face);
+                        ranges.get(i).filter(|range| range.start <= face)
+                    })
+                    .map_or(&**default, |range| &*range.material);
+                Some((material, *intersect))
+            }
+            Material::Bump {
+                material,
+                map,
+                strength,
+            } => {
+                if !raytracer.options.texture_mapping {
+                    return Some((material, *intersect));
+                }
+                let bumped = Intersection {
+                    normal: bump_normal(map, *strength, intersect),
+                    ..*intersect
+                };
+                Some((material, bumped))
+            }
+            Material::None if intersect.color.is_some() => {
+                Some((&VERTEX_COLOR_MATERIAL, *intersect))
+            }
+            _ => None,
+        }
+    }
+
+    // Diffuse color of the material at an intersection, if it has one. Vertex colors tint
+    // the color of the material.
+    fn diffuse_color(&self, raytracer: &Raytracer, intersect: &Intersection) -> Option<Color> {
+        let color = match self {
+            Material::PhongMaterial { kd, .. } => *kd,
+            Material::PhongTexture { texture, .. } => {
+                if raytracer.options.texture_mapping {
+                    texture.get_color(intersect.u_value, intersect.v_value)
+                } else {
+                    texture.average_color()
+                }
+            }
+            _ => return None,
+        };
+        match intersect.color {
+            Some(tint) => Some(color * tint),
+            None => Some(color),
+        }
+    }
+
+    // Light reaching the camera directly from the lights after hitting this material, used
+    // by the path tracer for next event estimation
+    pub fn direct_lighting(
+        &self,
+        ray: &Ray,
+        raytracer: &Raytracer,
+        intersect: &Intersection,
+    ) -> Color {
+        if let Some((material, intersect)) = self.resolve(raytracer, intersect) {
+            return material.direct_lighting(ray, raytracer, &intersect);
+        }
+        match self {
+            Material::PhongMaterial { reflectivity, .. }
+            | Material::PhongTexture { reflectivity, .. } => {
+                // Paths only scatter off the diffuse lobe or in the mirror direction, so the
+                // specular highlight is left out here as well. Lighting it here but never
+                // bouncing off it would make ks only reflect the lights and not the scene.
+                let kd = self.diffuse_color(raytracer, intersect).unwrap();
+                let no_specular = Color::new(0.0, 0.0, 0.0);
+                let point = intersect.point;
+                let color = sum_lights(ray, raytracer, intersect, false, |light, n, v| {
+                    phong_light(&kd, &no_specular, 1.0, light, &point, n, v)
+                });
+                (1.0 - reflectivity) * color
+            }
+            Material::Pbr(material) => {
+                let surface = pbr_surface(material, raytracer, intersect);
+                let point = intersect.point;
+                sum_lights(ray, raytracer, intersect, false, |light, n, v| {
+                    pbr_light(&surface, light, &point, n, v)
+                })
+            }
+            // Point lights can't be seen through refraction
+            Material::Dielectric { .. } | Material::None => Color::new(0.0, 0.0, 0.0),
+            Material::FaceMaterials { .. } | Material::Bump { .. } => unreachable!(),
+        }
+    }
+
+    // Pick a direction for a path to continue in after hitting this material, along with
+    // the weight the light coming back along it is multiplied by
+    pub fn scatter(
+        &self,
+        ray: &Ray,
+        raytracer: &Raytracer,
+        intersect: &Intersection,
+    ) -> Option<(Ray, Color)> {
+        if let Some((material, intersect)) = self.resolve(raytracer, intersect) {
+            return material.scatter(ray, raytracer, &intersect);
+        }
+        match self {
+            Material::PhongMaterial { reflectivity, .. }
+            | Material::PhongTexture { reflectivity, .. } => {
+                let kd = self.diffuse_color(raytracer, intersect).unwrap();
+                scatter_phong(&kd, *reflectivity, ray, intersect)
+            }
+            Material::Dielectric { ior, tint } => {
+                let (reflected_ray, refracted_ray, reflectance) =
+                    dielectric_rays(*ior, ray, intersect);
+                match refracted_ray {
+                    Some(refracted_ray) if thread_rng().gen::<f32>() >= reflectance => {
+                        Some((refracted_ray, transmitted_tint(tint, ray, intersect)))
+                    }
+                    _ => Some((reflected_ray, Color::new(1.0, 1.0, 1.0))),
+                }
+            }
+            Material::Pbr(material) => scatter_pbr(material, ray, raytracer, intersect),
+            Material::None => None,
+            Material::FaceMaterials { .. } | Material::Bump { .. } => unreachable!(),
+        }
+    }
+
+    // Color of the material at an intersection, depth is how many times the ray has
+    // already been reflected or refracted
+    pub fn get_color(
+        &self,
+        ray: &Ray,
+        raytracer: &Raytracer,
+        intersect: &Intersection,
+        depth: u32,
+    ) -> Color {
+        if let Some((material, intersect)) = self.resolve(raytracer, intersect) {
+            return material.get_color(ray, raytracer, &intersect, depth);
+        }
+        match self {
+            Material::PhongMaterial {
+                ks,
+                shininess,
+                reflectivity,
+                ..
+            }
+            | Material::PhongTexture {
+                ks,
+                shininess,
+                reflectivity,
+                ..
+            } => {
+                let kd = self.diffuse_color(raytracer, intersect).unwrap();
+                let color =
+                    calculate_phong_lighting(&kd, ks, *shininess, ray, raytracer, intersect);
+                apply_reflection(color, *reflectivity, ray, raytracer, intersect, depth)
+            }
+            Material::Dielectric { ior, tint } => {
+                calculate_dielectric(*ior, tint, ray, raytracer, intersect, depth)
+            }
+            Material::Pbr(material) => {
+                calculate_pbr_lighting(material, ray, raytracer, intersect, depth)
+            }
+            Material::None => Color::new(0.0, 0.0, 0.0),
+            Material::FaceMaterials { .. } | Material::Bump { .. } => unreachable!(),
+        }
+    }
+}
@@ -4,15 +4,25 @@
 // - texturing
 // - scene trees
 
+mod bvh;
 mod color;
+mod csg;
+mod gltf;
 mod intersection;
 mod light;
 mod lua;
+mod material;
+mod mtl;
 mod node;
+mod pbr;
 pub mod texture;
 
+pub use self::bvh::SceneBvh;
 pub use self::color::Color;
+pub use self::csg::{Csg, CsgOperation};
 pub use self::intersection::Intersection;
 pub use self::light::Light;
 pub use self::lua::run_lua_script;
-pub use self::node::{Intersect, Material, SceneNode};
+pub use self::material::Material;
+pub use self::node::{Intersect, SceneNode};
+pub use self::pbr::PbrMaterial;
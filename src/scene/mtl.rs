@@ -0,0 +1,182 @@
+use crate::geometry::{MeshError, MeshGroup};
+use crate::scene::material::FaceMaterial;
+use crate::scene::texture::Texture;
+use crate::scene::{Color, Material};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+// Used for faces without a material, or with a material the libraries don't define
+const DEFAULT_DIFFUSE: f32 = 0.8;
+
+// The parts of a material definition lucis understands, everything else is ignored
+#[derive(Debug, Clone)]
+struct MtlDefinition {
+    kd: Color,
+    ks: Color,
+    ns: f32,
+    diffuse_map: Option<String>,
+    // Height map file and the -bm multiplier
+    bump_map: Option<(String, f32)>,
+}
+
+impl Default for MtlDefinition {
+    fn default() -> MtlDefinition {
+        MtlDefinition {
+            kd: Color::new(DEFAULT_DIFFUSE, DEFAULT_DIFFUSE, DEFAULT_DIFFUSE),
+            ks: Color::new(0.0, 0.0, 0.0),
+            ns: 0.0,
+            diffuse_map: None,
+            bump_map: None,
+        }
+    }
+}
+
+fn parse_error(line: usize, message: String) -> MeshError {
+    MeshError::Parse { line, message }
+}
+
+fn parse_color(parts: &[&str], line: usize, kind: &str) -> Result<Color, MeshError> {
+    let values = parts
+        .iter()
+        .map(|part| {
+            part.parse::<f32>()
+                .map_err(|_| parse_error(line, format!("invalid {} value \'{}\'", kind, part)))
+        })
+        .collect::<Result<Vec<f32>, MeshError>>()?;
+    match values.len() {
+        // A single value is used for all three channels
+        1 => Ok(Color::new(values[0], values[0], values[0])),
+        n if n >= 3 => Ok(Color::new(values[0], values[1], values[2])),
+        n => Err(parse_error(
+            line,
+            format!("{} needs 3 values but has {}", kind, n),
+        )),
+    }
+}
+
+// Texture map statements can have options before the file name, like `-bm 0.5` or
+// `-s 2 2 1`. The file name is always last, and the only option used is the bump multiplier.
+fn parse_map(parts: &[&str], line: usize, kind: &str) -> Result<(String, f32), MeshError> {
+    let file_name = match parts.last() {
+        Some(name) => name.to_string(),
+        None => return Err(parse_error(line, format!("{} needs a file name", kind))),
+    };
+    let mut multiplier = 1.0;
+    if let Some(i) = parts.iter().position(|part| *part == "-bm") {
+        let value = parts.get(i + 1).unwrap_or(&"");
+        multiplier = value
+            .parse()
+            .map_err(|_| parse_error(line, format!("invalid -bm value \'{}\'", value)))?;
+    }
+    Ok((file_name, multiplier))
+}
+
+fn parse<R: BufRead>(reader: R) -> Result<Vec<(String, MtlDefinition)>, MeshError> {
+    let mut definitions: Vec<(String, MtlDefinition)> = vec![];
+    for (i, l) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = l?;
+        let content = line.split('#').next().unwrap_or("");
+        let parts: Vec<&str> = content.split_whitespace().collect();
+        let (keyword, args) = match parts.split_first() {
+            Some((keyword, args)) => (*keyword, args),
+            None => continue,
+        };
+
+        if keyword == "newmtl" {
+            definitions.push((args.join(" "), MtlDefinition::default()));
+            continue;
+        }
+        let definition = match definitions.last_mut() {
+            Some((_, definition)) => definition,
+            // Statements before the first newmtl have nothing to apply to
+            None => continue,
+        };
+        match keyword {
+            "Kd" => definition.kd = parse_color(args, line_number, "Kd")?,
+            "Ks" => definition.ks = parse_color(args, line_number, "Ks")?,
+            "Ns" => {
+                let value = args.first().unwrap_or(&"");
+                definition.ns = value
+                    .parse()
+                    .map_err(|_| parse_error(line_number, format!("invalid Ns \'{}\'", value)))?;
+            }
+            "map_Kd" => definition.diffuse_map = Some(parse_map(args, line_number, keyword)?.0),
+            "map_Bump" | "map_bump" | "bump" => {
+                definition.bump_map = Some(parse_map(args, line_number, keyword)?)
+            }
+            // Ignore all other statements
+            _ => {}
+        }
+    }
+    Ok(definitions)
+}
+
+fn load_map(directory: &Path, file_name: &str, srgb: bool) -> Result<Texture, MeshError> {
+    let path = directory.join(file_name);
+    let image = image::open(&path).map_err(|e| {
+        MeshError::Io(std::io::Error::other(format!(
+            "failed to load texture \'{}\': {}",
+            path.display(),
+            e
+        )))
+    })?;
+    // Obj texture coordinates go from 0 to 1 across the image
+    Ok(Texture::from_image(&image.to_rgb(), 1.0, 1.0, srgb))
+}
+
+fn build_material(directory: &Path, definition: &MtlDefinition) -> Result<Material, MeshError> {
+    let material = match &definition.diffuse_map {
+        Some(file_name) => Material::PhongTexture {
+            ks: definition.ks,
+            shininess: definition.ns,
+            texture: load_map(directory, file_name, true)?,
+            reflectivity: 0.0,
+        },
+        None => Material::phong(definition.kd, definition.ks, definition.ns, 0.0),
+    };
+    match &definition.bump_map {
+        Some((file_name, strength)) => Ok(Material::Bump {
+            material: Box::new(material),
+            map: load_map(directory, file_name, false)?,
+            strength: *strength,
+        }),
+        None => Ok(material),
+    }
+}
+
+// Load every material in a wavefront mtl file. Texture files are found relative to the
+// directory of the mtl file.
+pub fn load_library(file_name: &str) -> Result<HashMap<String, Arc<Material>>, MeshError> {
+    let reader = BufReader::new(File::open(file_name)?);
+    let directory = Path::new(file_name)
+        .parent()
+        .unwrap_or_else(|| Path::new(""));
+    let mut materials = HashMap::new();
+    for (name, definition) in parse(reader)? {
+        materials.insert(name, Arc::new(build_material(directory, &definition)?));
+    }
+    Ok(materials)
+}
+
+// A material giving each range of faces the library material it names
+pub fn face_materials(groups: &[MeshGroup], library: &HashMap<String, Arc<Material>>) -> Material {
+    let default = MtlDefinition::default();
+    let ranges = groups
+        .iter()
+        .filter_map(|group| {
+            library.get(&group.name).map(|material| FaceMaterial {
+                start: group.start,
+                end: group.end,
+                material: Arc::clone(material),
+            })
+        })
+        .collect();
+    Material::FaceMaterials {
+        ranges,
+        default: Box::new(Material::phong(default.kd, default.ks, default.ns, 0.0)),
+    }
+}
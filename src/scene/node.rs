@@ -1,105 +1,8 @@
 use crate::geometry::{Primitive, Ray};
-use crate::scene::texture::Texture;
-use crate::scene::{Color, Intersection};
-use crate::Raytracer;
-use nalgebra::{clamp, distance_squared, Affine3, Matrix4, Vector3};
-
-#[derive(Debug, Clone)]
-pub enum Material {
-    PhongMaterial {
-        kd: Color,
-        ks: Color,
-        shininess: f32,
-    },
-    PhongTexture {
-        ks: Color,
-        shininess: f32,
-        texture: Texture,
-    },
-    None,
-}
-
-fn calculate_phong_lighting(
-    kd: &Color,
-    ks: &Color,
-    shininess: f32,
-    _ray: &Ray,
-    raytracer: &Raytracer,
-    intersect: &Intersection,
-) -> Color {
-    let intersect_point = intersect.point;
-    let n = intersect.normal.normalize();
-    let v = (raytracer.eye - intersect_point).normalize();
-
-    let mut final_color = *kd * raytracer.ambient;
-
-    for light in raytracer.lights.iter() {
-        let total_shadow_rays = light.num_samples;
-        let mut shadow_rays_hit = 0;
-        for p in light.light_samples.iter() {
-            let shadow_ray = Ray::new_from_points(intersect_point, *p);
-            if raytracer.root_node.intersects(&shadow_ray).is_none() {
-                shadow_rays_hit += 1;
-            }
-        }
-        if shadow_rays_hit == 0 {
-            continue;
-        }
-
-        let shadow_multiplier = shadow_rays_hit as f32 / total_shadow_rays as f32;
-
-        let mut l = light.position - intersect_point;
-        let l_norm = l.norm();
-        l = l.normalize();
-
-        let ldotn = clamp(l.dot(&n), 0.0f32, 1.0f32);
-        let r = ((2.0f32 * ldotn * n) - l).normalize();
-        let rdotv = clamp(r.dot(&v), 0.0f32, 1.0f32);
-        let attenuation =
-            light.falloff[0] + (light.falloff[1] * l_norm) + (light.falloff[2] * l_norm * l_norm);
-        let light_sum = (kd * ldotn * light.color) + (ks * rdotv.powf(shininess) * light.color);
-        final_color = final_color + (shadow_multiplier * (light_sum / attenuation));
-    }
-
-    final_color
-}
-
-impl Material {
-    pub fn phong(kd: Color, ks: Color, shininess: f32) -> Material {
-        Material::PhongMaterial { kd, ks, shininess }
-    }
-
-    pub fn phong_texture(
-        file_name: &str,
-        u_max: f32,
-        v_max: f32,
-        ks: Color,
-        shininess: f32,
-    ) -> Material {
-        Material::PhongTexture {
-            texture: Texture::load_texture(file_name, u_max, v_max),
-            ks,
-            shininess,
-        }
-    }
-
-    pub fn get_color(&self, ray: &Ray, raytracer: &Raytracer, intersect: &Intersection) -> Color {
-        match self {
-            Material::PhongMaterial { kd, ks, shininess } => {
-                calculate_phong_lighting(kd, ks, *shininess, ray, raytracer, intersect)
-            }
-            Material::PhongTexture {
-                ks,
-                shininess,
-                texture,
-            } => {
-                let kd = texture.get_color(intersect.u_value, intersect.v_value);
-                calculate_phong_lighting(&kd, ks, *shininess, ray, raytracer, intersect)
-            }
-            Material::None => Color::new(0.0, 0.0, 0.0),
-        }
-    }
-}
+use crate::scene::csg::{self, Csg};
+use crate::scene::{Color, Intersection, Material};
+use nalgebra::{distance_squared, Affine3, Matrix4, Vector3};
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub struct SceneNode {
@@ -109,9 +12,16 @@ pub struct SceneNode {
     pub inv_transform: Affine3<f32>,
     pub name: String,
 
-    // Material and Primitive
-    pub material: Material,
-    pub primitive: Primitive,
+    // Material and Primitive, shared with the scene BVH while rendering
+    pub material: Arc<Material>,
+    pub primitive: Arc<Primitive>,
+
+    // Set for csg nodes, which combine the solids of their operands instead of grouping
+    // their children
+    pub csg: Option<Arc<Csg>>,
+    // Set for instance nodes, which place a shared subtree under their own transform. Unless
+    // it is Material::None, the material of an instance replaces every material inside it.
+    pub instance: Option<Arc<SceneNode>>,
 }
 
 impl SceneNode {
@@ -122,42 +32,80 @@ impl SceneNode {
             transform: Affine3::identity(),
             inv_transform: Affine3::identity(),
             name,
-            material: Material::None,
-            primitive: Primitive::None,
+            material: Arc::new(Material::None),
+            primitive: Arc::new(Primitive::None),
+            csg: None,
+            instance: None,
+        }
+    }
+
+    pub fn new_instance(id: u32, name: String, prototype: Arc<SceneNode>) -> SceneNode {
+        SceneNode {
+            instance: Some(prototype),
+            ..SceneNode::new(id, name)
+        }
+    }
+
+    // Replace the material of a hit inside the prototype of an instance
+    pub fn instance_material<'a>(&'a self, hit: Intersection<'a>) -> Intersection<'a> {
+        match *self.material {
+            Material::None => hit,
+            _ => Intersection {
+                material: &self.material,
+                ..hit
+            },
         }
     }
 }
 
 impl Intersect for SceneNode {
-    fn intersects(&self, ray: &Ray) -> Option<Intersection> {
+    fn intersects(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        if self.csg.is_some() {
+            return csg::crossings(self, ray).into_iter().next();
+        }
+
         let transformed_ray = self.inv_transform * *ray;
 
         let mut t_value: f32 = 0.0;
         let mut normal = Vector3::new(0.0f32, 0.0, 0.0);
         let mut uv = [0.0, 0.0];
-        let self_collides =
-            if self
-                .primitive
-                .collides(&transformed_ray, &mut t_value, &mut normal, &mut uv)
-            {
-                Some(Intersection::new(
+        let mut face = None;
+        let mut color = None;
+        let self_collides = if self.primitive.collides(
+            &transformed_ray,
+            &mut t_value,
+            &mut normal,
+            &mut uv,
+            &mut face,
+            &mut color,
+        ) {
+            Some(Intersection {
+                face,
+                color: color.map(|c| Color::from_vector(&c)),
+                ..Intersection::new(
                     t_value,
                     transformed_ray.src + (t_value * transformed_ray.dir.normalize()),
-                    &self,
+                    &self.material,
                     normal,
                     uv[0],
                     uv[1],
-                ))
-            } else {
-                None
-            };
+                )
+            })
+        } else {
+            None
+        };
+
+        let prototype_collides = self
+            .instance
+            .as_ref()
+            .and_then(|prototype| prototype.intersects(&transformed_ray))
+            .map(|hit| self.instance_material(hit));
 
         let min = self
             .children
             .iter()
-            .map(|child| child.intersects(&transformed_ray))
-            .filter(|child| child.is_some())
-            .map(|child| child.unwrap())
+            .filter_map(|child| child.intersects(&transformed_ray))
+            .chain(prototype_collides)
             .fold(None, |min, child| match min {
                 None => Some(child),
                 Some(cmin) => Some(
@@ -190,8 +138,12 @@ impl Intersect for SceneNode {
 }
 
 impl SceneNode {
+    // Children added to a csg node become more operands of it
     pub fn add_child(&mut self, child: SceneNode) {
-        self.children.push(child);
+        match &mut self.csg {
+            Some(csg) => Arc::make_mut(csg).operands.push(child),
+            None => self.children.push(child),
+        }
     }
     pub fn scale(&mut self, x: f32, y: f32, z: f32) {
         println!("Applying scaling to {} of ({}, {}, {})", self.name, x, y, z);
@@ -228,5 +180,5 @@ impl SceneNode {
 }
 
 pub trait Intersect {
-    fn intersects(&self, ray: &Ray) -> Option<Intersection>;
+    fn intersects(&self, ray: &Ray) -> Option<Intersection<'_>>;
 }
@@ -0,0 +1,180 @@
+use crate::sampling::{cosine_hemisphere, ggx_half_vector};
+use crate::scene::texture::Texture;
+use crate::scene::Color;
+use nalgebra::Vector3;
+use rand::{thread_rng, Rng};
+use std::f32::consts::PI;
+
+// Perfectly smooth surfaces turn the distribution into a spike that point lights never hit
+const MIN_ROUGHNESS: f32 = 0.05;
+// Reflectance of non-metals looking straight at the surface
+const DIELECTRIC_F0: f32 = 0.04;
+
+// A metallic/roughness material, lit with a Cook-Torrance microfacet BRDF using the GGX
+// distribution, Smith shadowing and Schlick's fresnel approximation
+#[derive(Debug, Clone)]
+pub struct PbrMaterial {
+    pub base_color: Color,
+    // 0 for dielectrics (plastic, wood) and 1 for metals
+    pub metallic: f32,
+    // 0 for a mirror finish up to 1 for completely rough
+    pub roughness: f32,
+    // Optional maps, each multiplies the matching factor above. Metallic is read from the
+    // blue channel and roughness from the green channel, which works for both grayscale
+    // maps and packed metallic/roughness maps.
+    pub base_color_map: Option<Texture>,
+    pub metallic_map: Option<Texture>,
+    pub roughness_map: Option<Texture>,
+}
+
+// The material parameters at a single point on a surface
+#[derive(Debug, Clone, Copy)]
+pub struct PbrSurface {
+    pub base_color: Vector3<f32>,
+    pub metallic: f32,
+    // Roughness squared, the width of the GGX distribution
+    pub alpha: f32,
+}
+
+impl PbrMaterial {
+    pub fn new(base_color: Color, metallic: f32, roughness: f32) -> PbrMaterial {
+        PbrMaterial {
+            base_color,
+            metallic,
+            roughness,
+            base_color_map: None,
+            metallic_map: None,
+            roughness_map: None,
+        }
+    }
+
+    // Look up the maps at a texture coordinate, when texture mapping is turned off the
+    // average color of each map is used instead
+    pub fn surface(&self, u: f32, v: f32, texture_mapping: bool) -> PbrSurface {
+        let sample = |map: &Option<Texture>| {
+            map.as_ref().map(|texture| {
+                if texture_mapping {
+                    texture.get_color(u, v)
+                } else {
+                    texture.average_color()
+                }
+            })
+        };
+
+        let mut base_color = self.base_color.as_vector();
+        if let Some(color) = sample(&self.base_color_map) {
+            base_color = base_color.component_mul(&color.as_vector());
+        }
+        let metallic = self.metallic * sample(&self.metallic_map).map_or(1.0, |c| c.b);
+        let roughness = self.roughness * sample(&self.roughness_map).map_or(1.0, |c| c.g);
+        let roughness = roughness.clamp(MIN_ROUGHNESS, 1.0);
+
+        PbrSurface {
+            base_color,
+            metallic: metallic.clamp(0.0, 1.0),
+            alpha: roughness * roughness,
+        }
+    }
+}
+
+impl PbrSurface {
+    // Schlick's approximation of the fresnel reflectance, where metals tint their
+    // reflections with the base color
+    pub fn fresnel(&self, cos_theta: f32) -> Vector3<f32> {
+        let dielectric = Vector3::new(DIELECTRIC_F0, DIELECTRIC_F0, DIELECTRIC_F0);
+        let f0 = dielectric.lerp(&self.base_color, self.metallic);
+        let weight = (1.0 - cos_theta.clamp(0.0, 1.0)).powi(5);
+        f0 + ((Vector3::new(1.0, 1.0, 1.0) - f0) * weight)
+    }
+
+    // How smooth the surface is, used to scale mirror reflections in the direct lighting
+    // tracer which can't blur them
+    pub fn smoothness(&self) -> f32 {
+        (1.0 - self.alpha.sqrt()).powi(2)
+    }
+
+    // GGX normal distribution function
+    fn distribution(&self, n_dot_h: f32) -> f32 {
+        let a2 = self.alpha * self.alpha;
+        let d = (n_dot_h * n_dot_h * (a2 - 1.0)) + 1.0;
+        a2 / (PI * d * d)
+    }
+
+    // Smith shadowing-masking for a single direction
+    fn smith_g1(&self, n_dot_x: f32) -> f32 {
+        let a2 = self.alpha * self.alpha;
+        2.0 * n_dot_x / (n_dot_x + (a2 + ((1.0 - a2) * n_dot_x * n_dot_x)).sqrt())
+    }
+
+    // The BRDF multiplied by the cosine of the light direction, for light arriving from l
+    // and leaving towards v. All vectors are normalized and point away from the surface.
+    pub fn eval(&self, n: &Vector3<f32>, v: &Vector3<f32>, l: &Vector3<f32>) -> Vector3<f32> {
+        let n_dot_l = n.dot(l);
+        let n_dot_v = n.dot(v);
+        if n_dot_l <= 0.0 || n_dot_v <= 0.0 {
+            return Vector3::new(0.0, 0.0, 0.0);
+        }
+
+        let h = (v + l).normalize();
+        let fresnel = self.fresnel(v.dot(&h));
+        let specular =
+            self.distribution(n.dot(&h).max(0.0)) * self.smith_g1(n_dot_l) * self.smith_g1(n_dot_v)
+                / (4.0 * n_dot_l * n_dot_v);
+
+        // Light that isn't reflected at the surface is scattered diffusely, except by metals
+        let diffuse = (Vector3::new(1.0, 1.0, 1.0) - fresnel).component_mul(&self.base_color)
+            * ((1.0 - self.metallic) / PI);
+        (diffuse + (fresnel * specular)) * n_dot_l
+    }
+
+    // Chance of sampling the specular lobe rather than the diffuse one
+    fn specular_probability(&self, n_dot_v: f32) -> f32 {
+        let specular = average(&self.fresnel(n_dot_v));
+        let diffuse = (1.0 - specular) * (1.0 - self.metallic) * average(&self.base_color);
+        if specular + diffuse <= 0.0 {
+            return 1.0;
+        }
+        (specular / (specular + diffuse)).clamp(0.1, 1.0)
+    }
+
+    // Pick a direction to continue a path in, either by sampling the GGX distribution or a
+    // cosine weighted diffuse bounce. Returns the direction and the BRDF weighted by the
+    // cosine term and divided by the combined probability of picking it.
+    pub fn sample(
+        &self,
+        n: &Vector3<f32>,
+        v: &Vector3<f32>,
+    ) -> Option<(Vector3<f32>, Vector3<f32>)> {
+        let n_dot_v = n.dot(v);
+        if n_dot_v <= 0.0 {
+            return None;
+        }
+
+        let specular_probability = self.specular_probability(n_dot_v);
+        let l = if thread_rng().gen::<f32>() < specular_probability {
+            let h = ggx_half_vector(n, self.alpha);
+            (2.0 * v.dot(&h) * h) - v
+        } else {
+            cosine_hemisphere(n)
+        };
+        let n_dot_l = n.dot(&l);
+        if n_dot_l <= 0.0 {
+            return None;
+        }
+
+        let h = (v + l).normalize();
+        let specular_pdf = self.distribution(n.dot(&h).max(0.0)) * n.dot(&h).max(0.0)
+            / (4.0 * v.dot(&h).max(f32::EPSILON));
+        let diffuse_pdf = n_dot_l / PI;
+        let pdf =
+            (specular_probability * specular_pdf) + ((1.0 - specular_probability) * diffuse_pdf);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((l, self.eval(n, v, &l) / pdf))
+    }
+}
+
+fn average(color: &Vector3<f32>) -> f32 {
+    (color.x + color.y + color.z) / 3.0
+}
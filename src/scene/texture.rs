@@ -1,36 +1,141 @@
+use crate::display::srgb_to_linear;
 use crate::scene::Color;
 use image::{open, RgbImage};
 
 #[derive(Debug, Clone)]
 pub struct Texture {
-    image: RgbImage,
+    width: u32,
+    height: u32,
+    // Linear colors of every pixel, in rows from the top left
+    pixels: Vec<Color>,
     u_max: f32,
     v_max: f32,
+    // Used in place of the texture when texture mapping is turned off
+    average: Color,
 }
 
 impl Texture {
+    // Load an image of colors, which are stored with the sRGB curve applied
     pub fn load_texture(file_name: &str, u_max: f32, v_max: f32) -> Texture {
         // TODO: Error handling
         let image = open(file_name).unwrap().to_rgb();
+        Texture::from_image(&image, u_max, v_max, true)
+    }
+
+    // Load an image of non-color data (such as roughness), which is already linear
+    pub fn load_linear_texture(file_name: &str, u_max: f32, v_max: f32) -> Texture {
+        let image = open(file_name).unwrap().to_rgb();
+        Texture::from_image(&image, u_max, v_max, false)
+    }
+
+    pub fn from_image(image: &RgbImage, u_max: f32, v_max: f32, srgb: bool) -> Texture {
+        // Decode every possible value once instead of for every pixel
+        let mut table = [0.0f32; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            let x = i as f32 / 255.0;
+            *value = if srgb { srgb_to_linear(x) } else { x };
+        }
+
+        let pixels: Vec<Color> = image
+            .pixels()
+            .map(|p| {
+                Color::new(
+                    table[p[0] as usize],
+                    table[p[1] as usize],
+                    table[p[2] as usize],
+                )
+            })
+            .collect();
+        let average = average_color(&pixels);
         Texture {
-            image,
+            width: image.width(),
+            height: image.height(),
+            pixels,
             u_max,
             v_max,
+            average,
         }
     }
 
+    pub fn average_color(&self) -> Color {
+        self.average
+    }
+
+    // Slope of the brightness of the texture along u and v, as the change in brightness
+    // from one pixel to the next. Used to read height maps.
+    pub fn gradient(&self, u: f32, v: f32) -> (f32, f32) {
+        let du = self.u_max / self.width as f32;
+        let dv = self.v_max / self.height as f32;
+        let height = |u: f32, v: f32| {
+            let color = self.get_color(u, v);
+            (color.r + color.g + color.b) / 3.0
+        };
+        (
+            (height(u + du, v) - height(u - du, v)) / 2.0,
+            (height(u, v + dv) - height(u, v - dv)) / 2.0,
+        )
+    }
+
     pub fn get_color(&self, u: f32, v: f32) -> Color {
-        let u_mapped = (u / self.u_max).fract().abs();
-        let v_mapped = (v / self.v_max).fract().abs();
+        let u_mapped = wrap(u / self.u_max);
+        let v_mapped = wrap(v / self.v_max);
         assert!(u_mapped <= 1.0);
         assert!(v_mapped <= 1.0);
         // println!("umapped: {}, vmapped: {}", u_mapped, v_mapped);
 
-        let (width, height) = self.image.dimensions();
-        let pixel_x = (u_mapped * (width - 1) as f32).round() as u32;
-        let pixel_y = (v_mapped * (height - 1) as f32).round() as u32;
+        let pixel_x = (u_mapped * (self.width - 1) as f32).round() as u32;
+        let pixel_y = (v_mapped * (self.height - 1) as f32).round() as u32;
         // println!("x: {}, y: {}", pixel_x, pixel_y);
 
-        Color::from_rgb(self.image.get_pixel(pixel_x, pixel_y))
+        self.pixels[(pixel_y * self.width + pixel_x) as usize]
+    }
+}
+
+// Repeat the texture outside 0 to 1. The far edge of a tile stays on the last pixel instead
+// of wrapping to the first, so a coordinate of exactly 1 (like v = 0 in an obj file,
+// flipped to run down the image) reads the bottom row rather than the top one.
+fn wrap(value: f32) -> f32 {
+    let wrapped = value.fract().abs();
+    if wrapped == 0.0 && value > 0.0 {
+        1.0
+    } else {
+        wrapped
+    }
+}
+
+fn average_color(pixels: &[Color]) -> Color {
+    let (mut r, mut g, mut b) = (0.0f64, 0.0f64, 0.0f64);
+    for color in pixels.iter() {
+        r += f64::from(color.r);
+        g += f64::from(color.g);
+        b += f64::from(color.b);
+    }
+    let count = pixels.len().max(1) as f64;
+    Color::new((r / count) as f32, (g / count) as f32, (b / count) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn the_edges_of_a_texture_read_the_edge_pixels() {
+        // Red on the top row and blue on the bottom one
+        let image = RgbImage::from_fn(2, 2, |_, y| {
+            Rgb(if y == 0 { [255, 0, 0] } else { [0, 0, 255] })
+        });
+        let texture = Texture::from_image(&image, 1.0, 1.0, false);
+        let (red, blue) = (Color::new(1.0, 0.0, 0.0), Color::new(0.0, 0.0, 1.0));
+
+        assert_eq!(texture.get_color(0.5, 0.0), red);
+        assert_eq!(texture.get_color(0.5, 1.0), blue);
+        assert_eq!(texture.get_color(1.0, 0.9), blue);
+        // v = 0 and 1 in an obj file, once flipped
+        assert_eq!(texture.get_color(0.0, 1.0 - 0.0), blue);
+        assert_eq!(texture.get_color(0.0, 1.0 - 1.0), red);
+        // Repeats start again from the top
+        assert_eq!(texture.get_color(0.5, 1.1), red);
+        assert_eq!(texture.get_color(0.5, 2.0), blue);
     }
 }